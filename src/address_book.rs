@@ -0,0 +1,271 @@
+//! The peers a node has heard of, kept on disk so that a restarted node can rejoin the network
+//! without its bootstrap peer.
+//!
+//! Every peer is remembered by its node id, together with the address it listens on, when it was
+//! last seen, how often connecting to it worked and failed and how the node first heard of it. The
+//! book is a text file with one peer per line,
+//!
+//! ```text
+//! <node id> <address> <last seen, in seconds since 1970> <successes> <failures> <source>
+//! ```
+//!
+//! which is read when the node starts and written every `ADDRESS_BOOK_SAVE_TIME` while it runs and
+//! once more when it stops. Lines that do not parse are skipped, so a damaged book only costs the
+//! peers on those lines.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use crate::identity::NodeId;
+use crate::protocol::PeerInfo;
+
+#[cfg(test)]
+mod tests;
+
+/// How often a node writes its address book to disk while it runs.
+pub(crate) const ADDRESS_BOOK_SAVE_TIME: Duration = Duration::from_secs(30);
+
+/// The most peers kept in the book. Past it the least promising one is forgotten, so that peers
+/// passing on made up addresses can not make the book grow without bounds.
+const ADDRESS_BOOK_LEN_MAX: usize = 1000;
+
+/// How the node first heard of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AddressSource {
+    /// The peer is a bootstrap peer the node was configured with.
+    Seed,
+    /// The peer connected to the node.
+    Inbound,
+    /// Another peer told the node about it.
+    Peer,
+}
+
+impl fmt::Display for AddressSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressSource::Seed => "seed",
+            AddressSource::Inbound => "inbound",
+            AddressSource::Peer => "peer",
+        })
+    }
+}
+
+impl FromStr for AddressSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "seed" => Ok(AddressSource::Seed),
+            "inbound" => Ok(AddressSource::Inbound),
+            "peer" => Ok(AddressSource::Peer),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the book knows about one peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AddressEntry {
+    pub(crate) addr: SocketAddr,
+    /// When the node was last connected to the peer, or heard of it if it never was.
+    pub(crate) last_seen: SystemTime,
+    /// How many handshakes with the peer completed.
+    pub(crate) successes: u32,
+    /// How many connections to the peer failed before the handshake completed.
+    pub(crate) failures: u32,
+    pub(crate) source: AddressSource,
+}
+
+impl AddressEntry {
+    /// How likely the peer is to be reachable, higher is better. Peers that were connected to
+    /// more often than they failed come first, and among them the ones seen most recently.
+    fn rank(&self) -> (bool, SystemTime) {
+        (self.successes > self.failures, self.last_seen)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AddressBook {
+    /// The file the book is kept in, `None` for a book that is only kept in memory.
+    path: Option<PathBuf>,
+    own_node_id: NodeId,
+    entries: HashMap<NodeId, AddressEntry>,
+    /// Whether the entries changed since the book was last written.
+    dirty: bool,
+}
+
+impl AddressBook {
+    /// A book that is not kept on disk.
+    pub(crate) fn in_memory(own_node_id: NodeId) -> Self {
+        AddressBook {
+            path: None,
+            own_node_id,
+            entries: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Read the book kept at `path`, or start an empty one there if the file does not exist yet.
+    pub(crate) fn load(path: PathBuf, own_node_id: NodeId) -> std::io::Result<Self> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let mut book = AddressBook {
+            path: Some(path),
+            own_node_id,
+            entries: HashMap::new(),
+            dirty: false,
+        };
+        for (node_id, entry) in contents.lines().filter_map(parse_line) {
+            book.insert(node_id, entry);
+        }
+        book.dirty = false;
+        Ok(book)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The peers in the book, the ones most likely to be reachable first.
+    pub(crate) fn candidates(&self) -> Vec<PeerInfo> {
+        let mut entries: Vec<(&NodeId, &AddressEntry)> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| Reverse(entry.rank()));
+        entries
+            .into_iter()
+            .map(|(node_id, entry)| PeerInfo {
+                node_id: *node_id,
+                addr: entry.addr,
+            })
+            .collect()
+    }
+
+    /// Note that another peer told the node about `peer`. Peers already in the book keep what the
+    /// book knows about them, the address they claim for themselves counts for more.
+    pub(crate) fn learned(&mut self, peer: PeerInfo, now: SystemTime) {
+        if self.entries.contains_key(&peer.node_id) {
+            return;
+        }
+        self.insert(
+            peer.node_id,
+            AddressEntry {
+                addr: peer.addr,
+                last_seen: now,
+                successes: 0,
+                failures: 0,
+                source: AddressSource::Peer,
+            },
+        );
+    }
+
+    /// Note that the handshake with `peer` completed. `source` is only recorded if the book did
+    /// not know the peer yet.
+    pub(crate) fn connected(&mut self, peer: PeerInfo, source: AddressSource, now: SystemTime) {
+        match self.entries.get_mut(&peer.node_id) {
+            Some(entry) => {
+                entry.addr = peer.addr;
+                entry.last_seen = now;
+                entry.successes = entry.successes.saturating_add(1);
+                self.dirty = true;
+            }
+            None => self.insert(
+                peer.node_id,
+                AddressEntry {
+                    addr: peer.addr,
+                    last_seen: now,
+                    successes: 1,
+                    failures: 0,
+                    source,
+                },
+            ),
+        }
+    }
+
+    /// Note that the connection to `node_id` ended, so it was last seen `now`.
+    pub(crate) fn disconnected(&mut self, node_id: &NodeId, now: SystemTime) {
+        if let Some(entry) = self.entries.get_mut(node_id) {
+            entry.last_seen = now;
+            self.dirty = true;
+        }
+    }
+
+    /// Note that connecting to `node_id` failed.
+    pub(crate) fn failed(&mut self, node_id: &NodeId) {
+        if let Some(entry) = self.entries.get_mut(node_id) {
+            entry.failures = entry.failures.saturating_add(1);
+            self.dirty = true;
+        }
+    }
+
+    fn insert(&mut self, node_id: NodeId, entry: AddressEntry) {
+        if node_id == self.own_node_id {
+            return;
+        }
+        if self.entries.len() >= ADDRESS_BOOK_LEN_MAX && !self.entries.contains_key(&node_id) {
+            let worst = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.rank())
+                .map(|(node_id, _)| *node_id);
+            if let Some(worst) = worst {
+                self.entries.remove(&worst);
+            }
+        }
+        self.entries.insert(node_id, entry);
+        self.dirty = true;
+    }
+
+    /// Write the book to its file if it changed since the last time. The file is replaced as a
+    /// whole, so that a node stopping halfway through does not leave half a book behind.
+    pub(crate) fn save(&mut self) -> std::io::Result<()> {
+        let path = match (&self.path, self.dirty) {
+            (Some(path), true) => path,
+            _ => return Ok(()),
+        };
+        let mut contents = Vec::new();
+        for (node_id, entry) in &self.entries {
+            let last_seen = entry
+                .last_seen
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            writeln!(
+                contents,
+                "{} {} {} {} {} {}",
+                node_id, entry.addr, last_seen, entry.successes, entry.failures, entry.source
+            )?;
+        }
+        let mut temporary_path = path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        std::fs::write(&temporary_path, contents)?;
+        std::fs::rename(&temporary_path, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Read one line of a book file, `None` if it is not a valid entry.
+fn parse_line(line: &str) -> Option<(NodeId, AddressEntry)> {
+    let mut fields = line.split_whitespace();
+    let node_id = fields.next()?.parse().ok()?;
+    let entry = AddressEntry {
+        addr: fields.next()?.parse().ok()?,
+        last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?),
+        successes: fields.next()?.parse().ok()?,
+        failures: fields.next()?.parse().ok()?,
+        source: fields.next()?.parse().ok()?,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((node_id, entry))
+}
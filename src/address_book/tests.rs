@@ -0,0 +1,88 @@
+use super::*;
+use crate::identity::Identity;
+
+fn peer(port: u16) -> PeerInfo {
+    PeerInfo {
+        node_id: Identity::generate().node_id(),
+        addr: SocketAddr::from(([127, 0, 0, 1], port)),
+    }
+}
+
+#[test]
+fn save_and_load_test() {
+    let path = std::env::temp_dir().join(format!(
+        "p2p_gossip_address_book_test_{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let own_node_id = Identity::generate().node_id();
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    let mut book = AddressBook::load(path.clone(), own_node_id).unwrap();
+    assert_eq!(book.len(), 0);
+    let (seed, inbound, learned) = (peer(1), peer(2), peer(3));
+    book.connected(seed, AddressSource::Seed, now);
+    book.connected(inbound, AddressSource::Inbound, now);
+    book.failed(&inbound.node_id);
+    book.learned(learned, now);
+    // the node itself is never in its book
+    book.learned(PeerInfo { node_id: own_node_id, addr: seed.addr }, now);
+    book.save().unwrap();
+
+    let loaded = AddressBook::load(path.clone(), own_node_id).unwrap();
+    assert_eq!(loaded.entries, book.entries);
+    assert_eq!(
+        loaded.entries[&inbound.node_id],
+        AddressEntry {
+            addr: inbound.addr,
+            last_seen: now,
+            successes: 1,
+            failures: 1,
+            source: AddressSource::Inbound,
+        }
+    );
+    assert!(!loaded.entries.contains_key(&own_node_id));
+
+    // lines that do not parse are skipped, the others still count
+    let mut contents = std::fs::read_to_string(&path).unwrap();
+    contents.push_str("not a peer\n");
+    contents.push_str(&format!("{} 127.0.0.1:4 12 0 0 elsewhere\n", peer(4).node_id));
+    std::fs::write(&path, contents).unwrap();
+    let loaded = AddressBook::load(path.clone(), own_node_id).unwrap();
+    assert_eq!(loaded.entries, book.entries);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn candidates_test() {
+    let mut book = AddressBook::in_memory(Identity::generate().node_id());
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let (unreliable, old, recent, learned) = (peer(1), peer(2), peer(3), peer(4));
+    book.connected(unreliable, AddressSource::Peer, start + Duration::from_secs(30));
+    book.failed(&unreliable.node_id);
+    book.connected(old, AddressSource::Peer, start);
+    book.connected(recent, AddressSource::Peer, start + Duration::from_secs(10));
+    book.learned(learned, start + Duration::from_secs(20));
+    // what a peer claims for another does not override what the node found out itself
+    book.learned(PeerInfo { node_id: recent.node_id, addr: learned.addr }, start);
+
+    assert_eq!(book.candidates(), [recent, old, unreliable, learned]);
+
+    book.disconnected(&old.node_id, start + Duration::from_secs(40));
+    assert_eq!(book.candidates(), [old, recent, unreliable, learned]);
+}
+
+#[test]
+fn bounded_len_test() {
+    let mut book = AddressBook::in_memory(Identity::generate().node_id());
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let connected = peer(1);
+    book.connected(connected, AddressSource::Seed, start);
+    for i in 0..ADDRESS_BOOK_LEN_MAX as u64 {
+        book.learned(peer(2), start + Duration::from_secs(i));
+    }
+    assert_eq!(book.len(), ADDRESS_BOOK_LEN_MAX);
+    // the peer the node connected to outranks all the ones it only heard of
+    assert!(book.entries.contains_key(&connected.node_id));
+}
@@ -0,0 +1,98 @@
+//! Pull-based anti-entropy, which recovers the gossip a node missed while it was cut off from the
+//! network.
+//!
+//! Gossip is pushed to peers only once, so a node whose connections were down while it went by
+//! never hears of it. To make up for that every node holds on to the gossip it heard for a
+//! retention time, and now and then sends a random peer a `Digest` of the gossip it holds. The
+//! peer answers with the gossip it holds that is missing from the digest, which the node then
+//! takes in like any other gossip.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{Digest, Gossip, MessageId};
+
+#[cfg(test)]
+mod tests;
+
+/// The false positive rate of the digests a node sends. Gossip a digest falsely claims to hold is
+/// not sent back, but the next digest is hashed differently and most likely gets it.
+const DIGEST_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// The most gossips held. Past it the oldest are dropped early, so that the node can not be made
+/// to hold more than this under heavy traffic and its digests stay small.
+const HELD_GOSSIP_LEN_MAX: usize = 10_000;
+
+/// The most gossips sent back in answer to one digest. A node that missed more than this catches
+/// up over several rounds instead of stalling on a single answer.
+const ANSWER_LEN_MAX: usize = 256;
+
+/// The gossip a node has heard within the retention time.
+#[derive(Debug)]
+pub(crate) struct AntiEntropy {
+    retention: Duration,
+    /// The gossip as it was passed on, or `None` for gossip that was heard but may not be relayed
+    /// any further. That is in the digests but never sent back.
+    held: HashMap<MessageId, (Instant, Option<Gossip>)>,
+    /// The ids in the order they were held in, which is also the order they expire in.
+    expiry_queue: VecDeque<(Instant, MessageId)>,
+}
+
+impl AntiEntropy {
+    pub(crate) fn new(retention: Duration) -> Self {
+        AntiEntropy {
+            retention,
+            held: HashMap::new(),
+            expiry_queue: VecDeque::new(),
+        }
+    }
+
+    /// Hold on to the gossip `id`, heard at `now`. `gossip` is what was passed on to the peers, it
+    /// is `None` if the gossip was not passed on because its time to live ran out.
+    pub(crate) fn hold(&mut self, id: MessageId, gossip: Option<&Gossip>, now: Instant) {
+        if self.held.len() >= HELD_GOSSIP_LEN_MAX && !self.held.contains_key(&id) {
+            if let Some((_, oldest)) = self.expiry_queue.pop_front() {
+                self.held.remove(&oldest);
+            }
+        }
+        if self.held.insert(id, (now, gossip.cloned())).is_none() {
+            self.expiry_queue.push_back((now, id));
+        }
+    }
+
+    /// A digest of the held gossip with a random seed.
+    pub(crate) fn digest(&self) -> Digest {
+        let ids: Vec<MessageId> = self.held.keys().copied().collect();
+        Digest::new(
+            &ids,
+            DIGEST_FALSE_POSITIVE_RATE,
+            self.retention.as_millis().min(u32::MAX as u128) as u32,
+            rand::random(),
+        )
+    }
+
+    /// The held gossip that is missing from `digest` and young enough to matter to the node that
+    /// sent it, at most `ANSWER_LEN_MAX` of them, oldest first.
+    pub(crate) fn missing(&self, digest: &Digest, now: Instant) -> Vec<Gossip> {
+        let max_age = Duration::from_millis(digest.max_age_ms as u64);
+        self.expiry_queue
+            .iter()
+            .filter(|(held_instant, id)| {
+                now.duration_since(*held_instant) <= max_age && !digest.contains(id)
+            })
+            .filter_map(|(_, id)| self.held[id].1.clone())
+            .take(ANSWER_LEN_MAX)
+            .collect()
+    }
+
+    /// Drop the gossip that has been held for longer than the retention time.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        while let Some(&(held_instant, id)) = self.expiry_queue.front() {
+            if now.duration_since(held_instant) <= self.retention {
+                break;
+            }
+            self.expiry_queue.pop_front();
+            self.held.remove(&id);
+        }
+    }
+}
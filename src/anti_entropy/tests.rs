@@ -0,0 +1,58 @@
+use super::*;
+use crate::identity::Identity;
+use crate::protocol::DIGEST_FILTER_LEN_MAX;
+
+#[test]
+fn missing_gossip_test() {
+    let start = Instant::now();
+    let identity = Identity::generate();
+    let gossips: Vec<Gossip> = (0..4)
+        .map(|seqno| Gossip::sign(&identity, seqno, 3, vec![seqno as u8]))
+        .collect();
+    let mut anti_entropy = AntiEntropy::new(Duration::from_secs(10));
+    for gossip in &gossips[..3] {
+        anti_entropy.hold(gossip.id(), Some(gossip), start);
+    }
+    // gossip that may not be relayed any further is in the digest but never sent back
+    anti_entropy.hold(gossips[3].id(), None, start);
+    let digest = anti_entropy.digest();
+    assert!(gossips.iter().all(|gossip| digest.contains(&gossip.id())));
+
+    let mut other = AntiEntropy::new(Duration::from_secs(10));
+    other.hold(gossips[1].id(), Some(&gossips[1]), start);
+    assert_eq!(
+        anti_entropy.missing(&other.digest(), start),
+        [gossips[0].clone(), gossips[2].clone()]
+    );
+    assert_eq!(other.missing(&digest, start), []);
+    assert_eq!(anti_entropy.missing(&digest, start), []);
+
+    // gossip older than what the digest covers is not sent back
+    let mut short_digest = other.digest();
+    short_digest.max_age_ms = 1000;
+    assert_eq!(anti_entropy.missing(&short_digest, start + Duration::from_secs(2)), []);
+}
+
+#[test]
+fn expire_test() {
+    let start = Instant::now();
+    let identity = Identity::generate();
+    let gossip = Gossip::sign(&identity, 0, 3, b"held".to_vec());
+    let mut anti_entropy = AntiEntropy::new(Duration::from_secs(10));
+    anti_entropy.hold(gossip.id(), Some(&gossip), start);
+    anti_entropy.expire(start + Duration::from_secs(10));
+    assert!(anti_entropy.held.contains_key(&gossip.id()));
+    anti_entropy.expire(start + Duration::from_secs(11));
+    assert!(anti_entropy.held.is_empty() && anti_entropy.expiry_queue.is_empty());
+
+    // past its maximum length the oldest gossip makes room
+    for seqno in 0..HELD_GOSSIP_LEN_MAX as u64 + 1 {
+        let mut gossip = gossip.clone();
+        gossip.seqno = seqno;
+        anti_entropy.hold(gossip.id(), Some(&gossip), start);
+    }
+    assert_eq!(anti_entropy.held.len(), HELD_GOSSIP_LEN_MAX);
+    assert!(!anti_entropy.held.contains_key(&gossip.id()));
+    // and a full digest still fits in one message
+    assert!(anti_entropy.digest().filter.len() <= DIGEST_FILTER_LEN_MAX as usize);
+}
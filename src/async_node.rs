@@ -0,0 +1,462 @@
+//! A node that runs as a task of a tokio runtime instead of on a thread of its own. It runs the
+//! same `do_peer` as a `GossipNode`, on a `TokioDriver`, so it speaks the same protocol and both
+//! kinds of node can be peers of each other. Unlike a `GossipNode` it only runs on TCP, see
+//! `GossipNodeBuilder::spawn_async`.
+//!
+//! Every connection gets a task reading it and a task writing it. The reading task hands what it
+//! read to the node over a channel and tells the node which connection is ready, the writing task
+//! writes what the node sends over another channel. Both channels are bounded, so that a slow
+//! peer backs up into the node's send queue, see `SendQueuePolicy`, and a fast one is slowed down
+//! by TCP. Connections are opened by tasks of their own, so that the node never waits for one.
+//! The node's timers are `tokio::time` sleeps.
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use mio::Token;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::{AbortHandle, JoinError, JoinHandle, JoinSet};
+
+use crate::driver::{Driver, OpenedStream, Stream, CONNECT_TIMEOUT};
+use crate::node::{self, NodeCommand, Reply, Subscriber};
+use crate::reactor::Wakeup;
+use crate::{
+    node_stopped, GossipNodeBuilder, MessageId, NodeEvent, NodeHandle, NodeId, NodeStats,
+    NodeWaker, TransportKind,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// The token the listener is reported ready with. The connections get the tokens after it.
+const LISTENER_TOKEN: Token = Token(0);
+
+/// How much a reading task reads from its connection at a time.
+const READ_CHUNK_LEN: usize = 16 * 1024;
+
+/// How many chunks a reading task reads ahead of the node. Past them it stops reading, so that a
+/// peer sending faster than the node keeps up is slowed down by TCP instead of filling the
+/// node's memory.
+const READ_AHEAD_CHUNKS: usize = 4;
+
+/// The most a writing task is handed at a time.
+const WRITE_CHUNK_LEN: usize = 16 * 1024;
+
+/// How many chunks a writing task takes from the node before it has written them. Past them the
+/// rest waits in the node's send queue.
+const WRITE_AHEAD_CHUNKS: usize = 4;
+
+/// How long the listener rests after failing to accept a connection, for example because the
+/// process is out of file descriptors.
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
+
+/// Runs a node as a task of a tokio runtime.
+#[derive(Debug)]
+pub(crate) struct TokioDriver {
+    local_addr: SocketAddr,
+    accepted: mpsc::UnboundedReceiver<(TcpStream, SocketAddr)>,
+    accept_task: AbortHandle,
+    /// The tokens of the connections that have something new to read, or room to write again.
+    ready: mpsc::UnboundedReceiver<Token>,
+    ready_sender: mpsc::UnboundedSender<Token>,
+    /// Notified by the node's handle when it wants the node's attention.
+    wake: Arc<Notify>,
+    /// The tasks opening connections, which hand them over `dialed` when they are done.
+    dial_tasks: JoinSet<()>,
+    dialed: mpsc::UnboundedReceiver<(Token, SocketAddr, std::io::Result<TcpStream>)>,
+    dialed_sender: mpsc::UnboundedSender<(Token, SocketAddr, std::io::Result<TcpStream>)>,
+    next_token: usize,
+}
+
+impl TokioDriver {
+    /// Listen on `bind_addr`, with a task accepting the connections that come in.
+    pub(crate) async fn bind(bind_addr: SocketAddr, wake: Arc<Notify>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (ready_sender, ready) = mpsc::unbounded_channel();
+        let (accepted_sender, accepted) = mpsc::unbounded_channel();
+        let (dialed_sender, dialed) = mpsc::unbounded_channel();
+        let listener_ready = ready_sender.clone();
+        let accept_task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok(accepted) => {
+                        if accepted_sender.send(accepted).is_err() {
+                            break;
+                        } // the node has stopped
+                        let _ = listener_ready.send(LISTENER_TOKEN);
+                    }
+                    Err(error) => {
+                        eprintln!("There was a accept error : {}", error);
+                        tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                    }
+                }
+            }
+        });
+        Ok(TokioDriver {
+            local_addr,
+            accepted,
+            accept_task: accept_task.abort_handle(),
+            ready,
+            ready_sender,
+            wake,
+            dial_tasks: JoinSet::new(),
+            dialed,
+            dialed_sender,
+            next_token: LISTENER_TOKEN.0 + 1,
+        })
+    }
+
+    /// A token no connection has been given yet.
+    fn next_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    /// Start the tasks reading and writing `stream`, which is known by `token`.
+    fn open(
+        &mut self,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        token: Token,
+    ) -> std::io::Result<OpenedStream> {
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let (received_sender, received) = mpsc::channel(READ_AHEAD_CHUNKS);
+        let (sending, sending_receiver) = mpsc::channel(WRITE_AHEAD_CHUNKS);
+        let sending_blocked = Arc::new(AtomicBool::new(false));
+        let reader = tokio::spawn(read_task(reader, received_sender, self.ready_sender.clone(), token));
+        tokio::spawn(write_task(
+            writer,
+            sending_receiver,
+            sending_blocked.clone(),
+            self.ready_sender.clone(),
+            token,
+        ));
+        Ok(OpenedStream {
+            stream: Box::new(ChannelStream {
+                received,
+                chunk: Vec::new(),
+                chunk_read: 0,
+                sending,
+                sending_blocked,
+                reader: reader.abort_handle(),
+            }),
+            token,
+            remote_addr,
+        })
+    }
+}
+
+impl Driver for TokioDriver {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn accept(&mut self) -> Vec<OpenedStream> {
+        let mut accepted = Vec::new();
+        while let Ok((stream, remote_addr)) = self.accepted.try_recv() {
+            let token = self.next_token();
+            match self.open(stream, remote_addr, token) {
+                Ok(opened) => accepted.push(opened),
+                Err(error) => eprintln!(
+                    "{}: Failed to take in the connection from {}: {}",
+                    self.local_addr, remote_addr, error
+                ),
+            }
+        }
+        accepted
+    }
+
+    /// The connection is opened by a task of its own, which reports its token ready when it is
+    /// done.
+    fn dial(&mut self, addr: &SocketAddr) -> std::io::Result<Token> {
+        let token = self.next_token();
+        let addr = *addr;
+        let dialed = self.dialed_sender.clone();
+        let ready = self.ready_sender.clone();
+        self.dial_tasks.spawn(async move {
+            let result = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+            if dialed.send((token, addr, result)).is_ok() {
+                let _ = ready.send(token);
+            }
+        });
+        Ok(token)
+    }
+
+    fn dialed(&mut self) -> Vec<(Token, std::io::Result<OpenedStream>)> {
+        while self.dial_tasks.try_join_next().is_some() {}
+        let mut dialed = Vec::new();
+        while let Ok((token, addr, result)) = self.dialed.try_recv() {
+            let opened = result.and_then(|stream| self.open(stream, addr, token));
+            dialed.push((token, opened));
+        }
+        dialed
+    }
+
+    async fn wait(&mut self, wakeup: &Wakeup) -> std::io::Result<HashSet<Token>> {
+        let mut ready = HashSet::new();
+        if let Ok(token) = self.ready.try_recv() {
+            ready.insert(token);
+        } else {
+            let sleep = async {
+                match wakeup.0 {
+                    Some(instant) => tokio::time::sleep_until(instant.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                token = self.ready.recv() => ready.extend(token),
+                _ = self.wake.notified() => {}
+                _ = sleep => {}
+            }
+        }
+        while let Ok(token) = self.ready.try_recv() {
+            ready.insert(token);
+        }
+        // what the listener has is picked up by `accept` anyway
+        ready.remove(&LISTENER_TOKEN);
+        Ok(ready)
+    }
+}
+
+impl Drop for TokioDriver {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// Read `reader` and hand what was read over `received`, telling the node over `ready` every time.
+/// An error is handed over as well, the end of the connection closes `received`.
+async fn read_task(
+    mut reader: OwnedReadHalf,
+    received: mpsc::Sender<std::io::Result<Vec<u8>>>,
+    ready: mpsc::UnboundedSender<Token>,
+    token: Token,
+) {
+    let mut chunk = vec![0; READ_CHUNK_LEN];
+    loop {
+        let result = match reader.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(len) => Ok(chunk[..len].to_vec()),
+            Err(error) => Err(error),
+        };
+        let failed = result.is_err();
+        if received.send(result).await.is_err() {
+            return;
+        } // the node has dropped the connection
+        let _ = ready.send(token);
+        if failed {
+            return;
+        }
+    }
+    drop(received);
+    let _ = ready.send(token);
+}
+
+/// Write everything that comes in over `sending` to `writer`, until the node drops the connection
+/// or writing fails. The connection is shut down once all of it is written. If the node found
+/// `sending` full, it is told over `ready` once there is room again.
+async fn write_task(
+    mut writer: OwnedWriteHalf,
+    mut sending: mpsc::Receiver<Vec<u8>>,
+    sending_blocked: Arc<AtomicBool>,
+    ready: mpsc::UnboundedSender<Token>,
+    token: Token,
+) {
+    while let Some(bytes) = sending.recv().await {
+        if writer.write_all(&bytes).await.is_err() {
+            return;
+        }
+        if sending_blocked.swap(false, Ordering::AcqRel) {
+            let _ = ready.send(token);
+        }
+    }
+}
+
+/// The node's end of a connection run by a reading and a writing task, see `TokioDriver::open`.
+#[derive(Debug)]
+struct ChannelStream {
+    received: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    /// The chunk being read, of which `chunk_read` bytes have been read so far.
+    chunk: Vec<u8>,
+    chunk_read: usize,
+    sending: mpsc::Sender<Vec<u8>>,
+    /// Set when `sending` was found full, for the writing task to say when it has room again.
+    sending_blocked: Arc<AtomicBool>,
+    reader: AbortHandle,
+}
+
+impl Stream for ChannelStream {}
+
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.chunk_read == self.chunk.len() {
+            match self.received.try_recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.chunk_read = 0;
+                }
+                Ok(Err(error)) => return Err(error),
+                Err(TryRecvError::Empty) => return Err(std::io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.chunk_read);
+        buf[..len].copy_from_slice(&self.chunk[self.chunk_read..self.chunk_read + len]);
+        self.chunk_read += len;
+        Ok(len)
+    }
+}
+
+impl Write for ChannelStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(WRITE_CHUNK_LEN);
+        let mut chunk = buf[..len].to_vec();
+        for attempt in 0..2 {
+            match self.sending.try_send(chunk) {
+                Ok(()) => return Ok(len),
+                Err(TrySendError::Full(unsent)) => chunk = unsent,
+                Err(TrySendError::Closed(_)) => return Err(std::io::ErrorKind::BrokenPipe.into()),
+            }
+            if attempt == 0 {
+                // the writing task may have made room since, and if it has not it sees the flag
+                // once it has written the next chunk
+                self.sending_blocked.store(true, Ordering::Release);
+            }
+        }
+        Err(std::io::ErrorKind::WouldBlock.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ChannelStream {
+    fn drop(&mut self) {
+        // the writing task stops by itself once it has written what it was sent
+        self.reader.abort();
+    }
+}
+
+impl GossipNodeBuilder {
+    /// Start the node as a task of the tokio runtime this is called from. This fails for the same
+    /// reasons `spawn` does, and with `Unsupported` if the transport is not `TransportKind::Tcp`.
+    /// The driver of an `AsyncGossipNode` only speaks TCP so far, a node on Unix domain sockets or
+    /// a `MemoryNetwork` has to be started with `spawn`, on a thread of its own.
+    pub async fn spawn_async(mut self) -> std::io::Result<AsyncGossipNode> {
+        if !matches!(self.transport, TransportKind::Tcp) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "an async node only runs on TCP",
+            ));
+        }
+        let address_book = self.prepare()?;
+        let wake = Arc::new(Notify::new());
+        let driver = TokioDriver::bind(self.config.bind_addr, wake.clone()).await?;
+        let started = node::start_peer(&self.config, address_book, driver);
+        let (handle, commands) =
+            NodeHandle::new(&self.config, started.driver.local_addr(), NodeWaker::Task(wake));
+        let config = self.config;
+        let shutdown = handle.shutdown.clone();
+        let subscribers = handle.subscribers.clone();
+        let next_seqno = handle.next_seqno.clone();
+        let task = tokio::spawn(async move {
+            node::do_peer(
+                started,
+                &config,
+                &shutdown,
+                commands,
+                &subscribers,
+                &next_seqno,
+            )
+            .await;
+        });
+
+        Ok(AsyncGossipNode {
+            handle,
+            task: Some(task),
+        })
+    }
+}
+
+/// Run the node configured by `builder` on the tokio runtime this is called from, until its
+/// lifetime runs out. Fails if the node can not be started, see `GossipNodeBuilder::spawn`, or if
+/// it panics. Dropping the future shuts the node down.
+pub async fn run(builder: GossipNodeBuilder) -> std::io::Result<()> {
+    let node = builder.spawn_async().await?;
+    node.join().await.map_err(std::io::Error::other)
+}
+
+/// A handle to a node running as a task of a tokio runtime. Dropping the handle shuts the node
+/// down.
+#[derive(Debug)]
+pub struct AsyncGossipNode {
+    handle: NodeHandle,
+    task: Option<JoinHandle<()>>,
+}
+
+impl AsyncGossipNode {
+    /// The address the node is listening on and advertising to other peers.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.local_addr
+    }
+
+    /// The node id the node is known by in the network.
+    pub fn node_id(&self) -> NodeId {
+        self.handle.identity.node_id()
+    }
+
+    /// Broadcast `gossip` to the network, see `GossipNode::publish`.
+    pub fn publish(&self, gossip: impl Into<Vec<u8>>) -> std::io::Result<MessageId> {
+        self.handle.publish(gossip.into())
+    }
+
+    /// A snapshot of the node's state, taken on its next loop iteration. Fails if the node is no
+    /// longer running.
+    pub async fn stats(&self) -> std::io::Result<NodeStats> {
+        let (sender, receiver) = oneshot::channel();
+        self.handle.send_command(NodeCommand::Stats(Reply::Async(sender)))?;
+        receiver.await.map_err(|_| node_stopped())
+    }
+
+    /// Subscribe to the node's events, see `GossipNode::subscribe`. The receiver is closed when
+    /// the node stops.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<NodeEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.handle.subscribe(Subscriber::Async(sender));
+        receiver
+    }
+
+    /// Ask the node to stop, see `GossipNode::shutdown`.
+    pub fn shutdown(&self) {
+        self.handle.shutdown();
+    }
+
+    /// Wait for the node to stop, either because of `shutdown` or because its lifetime ran out.
+    /// Returns an error if the node's task panicked.
+    pub async fn join(mut self) -> Result<(), JoinError> {
+        match self.task.take() {
+            Some(task) => task.await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AsyncGossipNode {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
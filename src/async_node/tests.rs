@@ -0,0 +1,101 @@
+use super::*;
+
+use std::time::Instant;
+
+/// Read everything `stream` has right now.
+fn read_available(stream: &mut OpenedStream) -> std::io::Result<Vec<u8>> {
+    let mut received = Vec::new();
+    let mut buf = [0; 4];
+    loop {
+        match stream.stream.read(&mut buf) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => received.extend_from_slice(&buf[..len]),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return Ok(received),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// The driver sleeps until its timer runs out, unless a connection has something to read or it is
+/// woken first. What is read and written goes through the connection's tasks.
+#[tokio::test]
+async fn wait_test() {
+    let wake = Arc::new(Notify::new());
+    let mut driver = TokioDriver::bind("127.0.0.1:0".parse().unwrap(), wake.clone())
+        .await
+        .unwrap();
+
+    let mut wakeup = Wakeup::default();
+    let start = Instant::now();
+    wakeup.at(start + Duration::from_millis(100));
+    assert!(driver.wait(&wakeup).await.unwrap().is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    let mut client = TcpStream::connect(driver.local_addr()).await.unwrap();
+    driver.wait(&Wakeup::default()).await.unwrap();
+    let mut accepted = driver.accept();
+    assert_eq!(accepted.len(), 1);
+    let mut opened = accepted.remove(0);
+    assert_eq!(opened.remote_addr, client.local_addr().unwrap());
+    assert_eq!(
+        opened.stream.read(&mut [0; 4]).unwrap_err().kind(),
+        std::io::ErrorKind::WouldBlock
+    );
+
+    client.write_all(b"ready").await.unwrap();
+    let start = Instant::now();
+    let ready = driver.wait(&Wakeup::default()).await.unwrap();
+    assert_eq!(ready, HashSet::from([opened.token]));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(read_available(&mut opened).unwrap(), b"ready");
+
+    opened.stream.write_all(b"written").unwrap();
+    let mut buf = [0; 7];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"written");
+
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        wake.notify_one();
+    });
+    let start = Instant::now();
+    assert!(driver.wait(&Wakeup::default()).await.unwrap().is_empty());
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    drop(client);
+    driver.wait(&Wakeup::default()).await.unwrap();
+    assert_eq!(
+        read_available(&mut opened).unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[tokio::test]
+async fn dial_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut driver = TokioDriver::bind("127.0.0.1:0".parse().unwrap(), Arc::new(Notify::new()))
+        .await
+        .unwrap();
+    let token = driver.dial(&listener.local_addr().unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().await.unwrap();
+    let mut dialed = Vec::new();
+    while dialed.is_empty() {
+        driver.wait(&Wakeup::default()).await.unwrap();
+        dialed = driver.dialed();
+    }
+    assert_eq!(dialed.len(), 1);
+    let (dialed_token, opened) = dialed.remove(0);
+    let mut opened = opened.unwrap();
+    assert_eq!(dialed_token, token);
+    assert_eq!(opened.token, token);
+    assert_eq!(opened.remote_addr, listener.local_addr().unwrap());
+
+    opened.stream.write_all(b"hello").unwrap();
+    let mut buf = [0; 5];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // the connection is shut down once the node drops it
+    drop(opened);
+    assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+}
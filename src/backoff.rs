@@ -0,0 +1,49 @@
+//! Exponential backoff with jitter, for retrying connections to peers that are down without
+//! hammering them.
+//!
+//! Every failed attempt doubles the delay before the next one, up to a maximum. Only half of the
+//! delay is fixed, the other half is random, so that nodes that lost a peer at the same time do
+//! not all come back to it at the same time.
+
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    /// How many attempts failed since the last success.
+    failures: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            failures: 0,
+        }
+    }
+
+    /// How many attempts failed since the last success.
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Note that an attempt failed and return how long to wait before the next one.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    /// Note that an attempt worked, so the next failure starts over at the initial delay.
+    pub(crate) fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
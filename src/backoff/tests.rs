@@ -0,0 +1,22 @@
+use super::*;
+
+#[test]
+fn backoff_test() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+    let expected_bases = [100, 200, 400, 800, 1000, 1000];
+    for base in expected_bases {
+        let base = Duration::from_millis(base);
+        let delay = backoff.next_delay();
+        assert!(delay >= base / 2 && delay <= base, "{:?} is not within {:?}", delay, base);
+    }
+    assert_eq!(backoff.failures(), expected_bases.len() as u32);
+
+    backoff.reset();
+    assert_eq!(backoff.failures(), 0);
+    assert!(backoff.next_delay() <= Duration::from_millis(100));
+
+    // the delay saturates instead of overflowing after many failures
+    for _ in 0..100 {
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,381 @@
+//! The connection to a peer, read and written without ever blocking the node.
+//!
+//! What is read from the socket is collected in a receive buffer, decrypted if the connection is
+//! encrypted, and cut into whole `protocol` messages by a `FrameDecoder` as soon as they are
+//! complete. The node picks the messages up one at a time with `next_message`. What is sent is
+//! encoded and written right away as far as the socket takes it. The rest waits in a send queue
+//! until the socket is ready for more, see `Driver::wait`.
+//!
+//! The send queue holds whole packets in plaintext, they are only encrypted when they are
+//! written. This lets the queue drop packets without upsetting the Noise nonces when it grows
+//! past its high-water mark, see `SendQueuePolicy`. A peer that reads slowly then costs the node a
+//! bounded amount of memory and never holds up its other peers.
+//!
+//! A connection the node accepted starts with the peer's magic, which tells whether it is
+//! encrypted, and its `Hello`. The connection works both out by itself and hands the `Hello` over
+//! with `take_hello`.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use mio::Token;
+
+use crate::driver::Stream;
+use crate::framing::{Frame, FrameDecoder};
+use crate::noise::NoiseSession;
+use crate::protocol::{self, DecodeLimits, Hello, Message, NOISE_CONNECTION_MAGIC};
+use crate::SendQueuePolicy;
+
+#[cfg(test)]
+mod tests;
+
+/// How much is read from the socket at a time.
+const READ_CHUNK_LEN: usize = 16 * 1024;
+
+/// The most received messages kept waiting for the node to pick them up.
+const INCOMING_LEN_MAX: usize = 64;
+
+/// How much of the send queue is encrypted and handed to the socket at a time.
+const WRITE_CHUNK_LEN: usize = 64 * 1024;
+
+/// How much a connection queues for its peer, and what it does past that.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SendQueueLimits {
+    /// The most bytes queued before the queue counts as full.
+    pub(crate) high_water_mark: usize,
+    pub(crate) policy: SendQueuePolicy,
+}
+
+pub(crate) struct Connection {
+    stream: Box<dyn Stream>,
+    token: Token,
+    /// `None` for a plaintext connection, and for an accepted one until its magic has arrived.
+    noise: Option<Box<NoiseSession>>,
+    /// Whether the peer's magic is still to come. Only accepted connections start with it.
+    awaiting_magic: bool,
+    /// What was read from the socket and not decrypted yet. Plaintext goes to `decoder` right
+    /// away.
+    received: Vec<u8>,
+    decoder: FrameDecoder,
+    hello: Option<Hello>,
+    incoming: VecDeque<Message>,
+    /// Whether the socket may have more to read than was read so far.
+    readable: bool,
+    /// Why the connection can not go on, handed out once the messages before it are.
+    error: Option<std::io::Error>,
+    /// The packets that were sent and not written to the socket yet, and how many bytes they
+    /// take.
+    queue: VecDeque<Vec<u8>>,
+    queue_len: usize,
+    /// What has been taken from the queue, and encrypted if need be, and not written to the
+    /// socket yet. It is past dropping.
+    outgoing: Vec<u8>,
+    /// Whether the socket may take more than was written so far. Packets are left in the queue
+    /// while it does not, where they can still be dropped.
+    writable: bool,
+    send_limits: SendQueueLimits,
+    /// Since when the queue has been above its high-water mark, with backpressure.
+    congested_since: Option<Instant>,
+}
+
+impl Connection {
+    fn new(
+        stream: Box<dyn Stream>,
+        token: Token,
+        limits: DecodeLimits,
+        send_limits: SendQueueLimits,
+        noise: Option<NoiseSession>,
+        accepted: bool,
+    ) -> Self {
+        Connection {
+            stream,
+            token,
+            noise: noise.map(Box::new),
+            awaiting_magic: accepted,
+            received: Vec::new(),
+            decoder: if accepted {
+                FrameDecoder::awaiting_hello(limits)
+            } else {
+                FrameDecoder::new(limits)
+            },
+            hello: None,
+            incoming: VecDeque::new(),
+            readable: false,
+            error: None,
+            queue: VecDeque::new(),
+            queue_len: 0,
+            outgoing: Vec::new(),
+            writable: true,
+            send_limits,
+            congested_since: None,
+        }
+    }
+
+    /// Take over a connection the node has opened, whose readiness is reported with `token`. If
+    /// it is to be `encrypted` the Noise magic is sent right away, so that the `Hello` can follow
+    /// it.
+    pub(crate) fn connected(
+        stream: Box<dyn Stream>,
+        token: Token,
+        limits: DecodeLimits,
+        send_limits: SendQueueLimits,
+        encrypted: bool,
+    ) -> std::io::Result<Self> {
+        if !encrypted {
+            return Ok(Connection::new(stream, token, limits, send_limits, None, false));
+        }
+        let noise = NoiseSession::initiator()?;
+        let mut connection =
+            Connection::new(stream, token, limits, send_limits, Some(noise), false);
+        connection
+            .outgoing
+            .extend_from_slice(NOISE_CONNECTION_MAGIC.as_bytes());
+        Ok(connection)
+    }
+
+    /// Take over a connection the node has accepted, whose readiness is reported with `token`.
+    pub(crate) fn accepted(
+        stream: Box<dyn Stream>,
+        token: Token,
+        limits: DecodeLimits,
+        send_limits: SendQueueLimits,
+    ) -> Self {
+        Connection::new(stream, token, limits, send_limits, None, true)
+    }
+
+    /// The token the driver reports the connection's readiness with.
+    pub(crate) fn token(&self) -> Token {
+        self.token
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.noise.is_some()
+    }
+
+    /// What the handshake signatures made at this point of the connection have to cover, see
+    /// `protocol::confirm_signed_data`.
+    pub(crate) fn channel_binding(&self) -> Vec<u8> {
+        match &self.noise {
+            Some(noise) => noise.handshake_hash(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Send a `Hello`. During a Noise handshake it has to be the first thing sent.
+    pub(crate) fn send_hello(&mut self, hello: &Hello) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        protocol::write_hello(&mut buf, hello)?;
+        self.send_bytes(&buf)
+    }
+
+    pub(crate) fn send(&mut self, message: &Message) -> std::io::Result<()> {
+        self.send_bytes(&protocol::encode_to_vec(message))
+    }
+
+    /// Send `packet`, which holds whole encoded messages. During a Noise handshake it has to be
+    /// exactly one.
+    ///
+    /// Fails if the packet can not be written, or if it fills the queue up past its high-water
+    /// mark and the policy is to drop the peer.
+    pub(crate) fn send_bytes(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        match &mut self.noise {
+            // the handshake signatures cover the handshake hash, which has to be up to date as
+            // soon as a handshake message is sent
+            Some(noise) if noise.is_handshaking() => noise.encrypt(packet, &mut self.outgoing)?,
+            _ => {
+                self.queue.push_back(packet.to_vec());
+                self.queue_len += packet.len();
+            }
+        }
+        self.flush()?;
+        if self.queued_len() <= self.send_limits.high_water_mark {
+            return Ok(());
+        }
+        match self.send_limits.policy {
+            SendQueuePolicy::DropOldest => {
+                // the packet just sent is kept even if it is bigger than the whole queue may be
+                while self.queued_len() > self.send_limits.high_water_mark && self.queue.len() > 1
+                {
+                    let packet = self.queue.pop_front().expect("the queue is not empty");
+                    self.queue_len -= packet.len();
+                }
+                Ok(())
+            }
+            SendQueuePolicy::DropPeer => Err(std::io::Error::other(
+                "the peer does not keep up with what it is sent",
+            )),
+            SendQueuePolicy::Backpressure => {
+                self.congested_since.get_or_insert_with(Instant::now);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write as much of the send queue as the socket takes without blocking.
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.write_queue();
+        if self.queued_len() <= self.send_limits.high_water_mark {
+            self.congested_since = None;
+        }
+        result
+    }
+
+    fn write_queue(&mut self) -> std::io::Result<()> {
+        while self.writable {
+            while self.outgoing.len() < WRITE_CHUNK_LEN {
+                let packet = match self.queue.pop_front() {
+                    Some(packet) => packet,
+                    None => break,
+                };
+                self.queue_len -= packet.len();
+                match &mut self.noise {
+                    Some(noise) => noise.encrypt(&packet, &mut self.outgoing)?,
+                    None => self.outgoing.extend_from_slice(&packet),
+                }
+            }
+            if self.outgoing.is_empty() {
+                return Ok(());
+            }
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.outgoing.drain(..len);
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.writable = false;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// How many bytes were sent and not written to the socket yet.
+    pub(crate) fn queued_len(&self) -> usize {
+        self.queue_len + self.outgoing.len()
+    }
+
+    /// Since when the send queue has been above its high-water mark, if it is and the policy is
+    /// backpressure.
+    pub(crate) fn congested_since(&self) -> Option<Instant> {
+        self.congested_since
+    }
+
+    /// Handle the readiness the driver reported: write what is waiting in the send queue and
+    /// read what the socket has. Whatever goes wrong is handed out by `next_message`.
+    pub(crate) fn ready(&mut self) {
+        self.writable = true;
+        if let Err(error) = self.flush() {
+            self.error.get_or_insert(error);
+            return;
+        }
+        self.readable = true;
+        self.receive();
+    }
+
+    /// Read from the socket until it would block, decoding the messages as they complete. Past
+    /// `INCOMING_LEN_MAX` messages that were not picked up the rest is left in the socket, so that
+    /// a peer sending faster than the node keeps up is slowed down by TCP instead of filling the
+    /// node's memory.
+    fn receive(&mut self) {
+        let mut chunk = vec![0; READ_CHUNK_LEN];
+        while self.readable && self.error.is_none() && self.incoming.len() < INCOMING_LEN_MAX {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.readable = false;
+                    self.error = Some(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(len) => {
+                    self.received.extend_from_slice(&chunk[..len]);
+                    if let Err(error) = self.decode_received() {
+                        self.error = Some(error);
+                    }
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.readable = false;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(error) => {
+                    self.readable = false;
+                    self.error = Some(error);
+                }
+            }
+        }
+    }
+
+    fn decode_received(&mut self) -> std::io::Result<()> {
+        if self.awaiting_magic {
+            if self.received.len() < NOISE_CONNECTION_MAGIC.len() {
+                return Ok(());
+            }
+            self.awaiting_magic = false;
+            if self.received.starts_with(NOISE_CONNECTION_MAGIC.as_bytes()) {
+                self.received.drain(..NOISE_CONNECTION_MAGIC.len());
+                self.noise = Some(Box::new(NoiseSession::responder()?));
+            } // otherwise it is the plaintext magic, which is part of the `Hello`
+        }
+        match &mut self.noise {
+            Some(noise) => {
+                let mut plaintext = Vec::new();
+                let used = noise.decrypt(&self.received, &mut plaintext)?;
+                self.received.drain(..used);
+                self.decoder.extend(&plaintext);
+            }
+            None => {
+                self.decoder.extend(&self.received);
+                self.received.clear();
+            }
+        }
+
+        while let Some(frame) = self.decoder.next_frame()? {
+            match frame {
+                Frame::Hello(hello) => self.hello = Some(hello),
+                Frame::Message(message) => self.incoming.push_back(message),
+            }
+        }
+        Ok(())
+    }
+
+    /// The `Hello` of an accepted connection, once it has arrived.
+    pub(crate) fn take_hello(&mut self) -> Option<Hello> {
+        self.hello.take()
+    }
+
+    /// The next message received, or why the connection can not go on. `None` if there is
+    /// nothing new.
+    pub(crate) fn next_message(&mut self) -> Option<std::io::Result<Message>> {
+        if self.incoming.is_empty() {
+            self.receive();
+        }
+        match self.incoming.pop_front() {
+            Some(message) => Some(Ok(message)),
+            None => self.error.take().map(Err),
+        }
+    }
+
+    /// Whether `next_message` has something to hand out.
+    pub(crate) fn has_incoming(&self) -> bool {
+        !self.incoming.is_empty() || self.error.is_some() || self.readable
+    }
+
+    /// Why the connection can not go on, if it can not.
+    pub(crate) fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+}
+
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("stream", &self.stream)
+            .field("noise", &self.noise)
+            .field(
+                "received_len",
+                &(self.received.len() + self.decoder.buffered_len()),
+            )
+            .field("incoming_len", &self.incoming.len())
+            .field("queued_len", &self.queued_len())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,235 @@
+use super::*;
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// The far end of a `TestStream`.
+#[derive(Debug, Default)]
+struct Pipe {
+    /// What is still to be read.
+    incoming: Vec<u8>,
+    /// What was written so far.
+    written: Vec<u8>,
+    /// How many more bytes can be written before writes would block.
+    room: usize,
+}
+
+/// A stream that never blocks, with the far end left to the test.
+#[derive(Debug, Clone, Default)]
+struct TestStream(Arc<Mutex<Pipe>>);
+
+impl TestStream {
+    fn set_room(&self, room: usize) {
+        self.0.lock().unwrap().room = room;
+    }
+
+    fn take_written(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap().written)
+    }
+
+    fn feed(&self, bytes: &[u8]) {
+        self.0.lock().unwrap().incoming.extend_from_slice(bytes);
+    }
+}
+
+impl Read for TestStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut pipe = self.0.lock().unwrap();
+        if pipe.incoming.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(pipe.incoming.len());
+        buf[..len].copy_from_slice(&pipe.incoming[..len]);
+        pipe.incoming.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for TestStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut pipe = self.0.lock().unwrap();
+        if pipe.room == 0 {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(pipe.room);
+        pipe.written.extend_from_slice(&buf[..len]);
+        pipe.room -= len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for TestStream {}
+
+fn limits() -> DecodeLimits {
+    DecodeLimits {
+        max_gossip_len: 1024,
+    }
+}
+
+fn plaintext_connection(stream: &TestStream, policy: SendQueuePolicy) -> Connection {
+    let send_limits = SendQueueLimits {
+        high_water_mark: 100,
+        policy,
+    };
+    Connection::connected(Box::new(stream.clone()), Token(1), limits(), send_limits, false).unwrap()
+}
+
+/// The packets `0..count`, 40 bytes each.
+fn packets(count: u8) -> Vec<Vec<u8>> {
+    (0..count).map(|index| vec![index; 40]).collect()
+}
+
+/// The oldest packets are dropped once the queue is past its mark, and the rest is written once
+/// the peer reads again.
+#[test]
+fn drop_oldest_test() {
+    let stream = TestStream::default();
+    let mut connection = plaintext_connection(&stream, SendQueuePolicy::DropOldest);
+    for packet in packets(10) {
+        connection.send_bytes(&packet).unwrap();
+        assert!(connection.queued_len() <= 100);
+    }
+    assert_eq!(connection.queued_len(), 80);
+    assert_eq!(connection.congested_since(), None);
+
+    // the first packet was on its way before the socket turned out to be full
+    stream.set_room(usize::MAX);
+    connection.ready();
+    assert_eq!(connection.queued_len(), 0);
+    let packets = packets(10);
+    assert_eq!(stream.take_written(), [&packets[0][..], &packets[9][..]].concat());
+
+    // a packet bigger than the whole queue may be is still sent
+    stream.set_room(0);
+    connection.send_bytes(&[1; 200]).unwrap();
+    assert_eq!(connection.queued_len(), 200);
+}
+
+#[test]
+fn drop_peer_test() {
+    let stream = TestStream::default();
+    let mut connection = plaintext_connection(&stream, SendQueuePolicy::DropPeer);
+    let packets = packets(3);
+    connection.send_bytes(&packets[0]).unwrap();
+    connection.send_bytes(&packets[1]).unwrap();
+    assert!(connection.send_bytes(&packets[2]).is_err());
+}
+
+#[test]
+fn backpressure_test() {
+    let stream = TestStream::default();
+    let mut connection = plaintext_connection(&stream, SendQueuePolicy::Backpressure);
+    for packet in packets(2) {
+        connection.send_bytes(&packet).unwrap();
+    }
+    assert_eq!(connection.congested_since(), None);
+    for packet in packets(10)[2..].iter() {
+        connection.send_bytes(packet).unwrap();
+    }
+    assert!(connection.congested_since().is_some());
+    assert_eq!(connection.queued_len(), 400);
+
+    // the peer catches up partly, which is not enough
+    stream.set_room(250);
+    connection.ready();
+    assert!(connection.congested_since().is_some());
+    stream.set_room(usize::MAX);
+    connection.ready();
+    assert_eq!(connection.congested_since(), None);
+    assert_eq!(stream.take_written(), packets(10).concat());
+}
+
+/// Dropping packets from the queue of an encrypted connection leaves the packets after them
+/// readable, since they are only encrypted when they are written.
+#[test]
+fn drop_oldest_encrypted_test() {
+    let stream = TestStream::default();
+    stream.set_room(usize::MAX);
+    let send_limits = SendQueueLimits {
+        high_water_mark: 100,
+        policy: SendQueuePolicy::DropOldest,
+    };
+    let mut connection =
+        Connection::connected(Box::new(stream.clone()), Token(1), limits(), send_limits, true)
+            .unwrap();
+    let mut responder = NoiseSession::responder().unwrap();
+    let mut plaintext = Vec::new();
+
+    // the handshake, with a `Join` standing in for the handshake messages
+    connection.send(&Message::Join).unwrap();
+    let wire = stream.take_written();
+    assert!(wire.starts_with(NOISE_CONNECTION_MAGIC.as_bytes()));
+    responder
+        .decrypt(&wire[NOISE_CONNECTION_MAGIC.len()..], &mut plaintext)
+        .unwrap();
+    let mut wire = Vec::new();
+    responder
+        .encrypt(&protocol::encode_to_vec(&Message::Join), &mut wire)
+        .unwrap();
+    stream.feed(&wire);
+    connection.ready();
+    assert!(matches!(connection.next_message(), Some(Ok(Message::Join))));
+    connection.send(&Message::Join).unwrap();
+    responder.decrypt(&stream.take_written(), &mut plaintext).unwrap();
+    assert!(!responder.is_handshaking());
+    plaintext.clear();
+
+    stream.set_room(0);
+    for packet in packets(10) {
+        connection.send_bytes(&packet).unwrap();
+    }
+    stream.set_room(usize::MAX);
+    connection.ready();
+    let wire = stream.take_written();
+    assert_eq!(responder.decrypt(&wire, &mut plaintext).unwrap(), wire.len());
+    let packets = packets(10);
+    assert_eq!(plaintext, [&packets[0][..], &packets[9][..]].concat());
+}
+
+/// An accepted connection hands out the peer's `Hello` and messages only once the last of their
+/// bytes has trickled in.
+#[test]
+fn byte_at_a_time_test() {
+    let stream = TestStream::default();
+    let send_limits = SendQueueLimits {
+        high_water_mark: 100,
+        policy: SendQueuePolicy::DropOldest,
+    };
+    let mut connection =
+        Connection::accepted(Box::new(stream.clone()), Token(1), limits(), send_limits);
+    let hello = Hello {
+        min_version: protocol::PROTOCOL_VERSION_MIN,
+        max_version: protocol::PROTOCOL_VERSION_MAX,
+        capabilities: protocol::CAPABILITIES,
+        listen_addr: "127.0.0.1:25532".parse().unwrap(),
+        node_id: crate::identity::NodeId::from_bytes([1; 32]),
+        nonce: [2; protocol::NONCE_LEN],
+    };
+    let mut wire = Vec::new();
+    protocol::write_hello(&mut wire, &hello).unwrap();
+    let hello_len = wire.len();
+    let shuffle = Message::Shuffle {
+        peers: Vec::new(),
+    };
+    wire.extend_from_slice(&protocol::encode_to_vec(&shuffle));
+
+    for (index, byte) in wire.iter().enumerate() {
+        stream.feed(&[*byte]);
+        connection.ready();
+        if index + 1 == hello_len {
+            assert_eq!(connection.take_hello(), Some(hello.clone()));
+        }
+        match connection.next_message() {
+            Some(message) => {
+                assert_eq!(index + 1, wire.len());
+                assert_eq!(message.unwrap(), shuffle);
+            }
+            None => assert!(index + 1 < wire.len()),
+        }
+    }
+    assert_eq!(connection.take_hello(), None);
+}
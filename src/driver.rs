@@ -0,0 +1,73 @@
+//! What a node runs on. `do_peer` does not touch sockets or clocks itself, it asks its `Driver` for
+//! the connections that came in or have been opened, to start opening new ones and to wait until
+//! there is something to do. Opening a connection never holds up the node, it is handed out by
+//! `Driver::dialed` once it is open, while the node goes on with its other peers. The connections
+//! themselves are `Stream`s that never block, see `Connection`.
+//!
+//! A `GossipNode` runs on a `MioDriver`, which does all of this on the node's own thread, over
+//! whichever `Transport` the node was configured with. Its `wait` blocks instead of waiting, so
+//! `do_peer` is simply run with `block_on`. With the `async` feature an `AsyncGossipNode` runs on
+//! a `TokioDriver` instead, with a task reading and a task writing every connection. Either way
+//! the node speaks the same protocol, so both kinds of node can be peers of each other.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use mio::Token;
+
+use crate::reactor::Wakeup;
+
+/// How long connecting to a peer may take before it is given up on.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to a peer. Reads and writes that can not be done right away fail with
+/// `WouldBlock`, and the connection's token is reported by `Driver::wait` once they can.
+pub(crate) trait Stream: Read + Write + Send + std::fmt::Debug {}
+
+/// A connection a driver has opened or accepted.
+#[derive(Debug)]
+pub(crate) struct OpenedStream {
+    pub(crate) stream: Box<dyn Stream>,
+    /// What `Driver::wait` reports the stream's readiness with.
+    pub(crate) token: Token,
+    /// The address of the other end of the connection.
+    pub(crate) remote_addr: SocketAddr,
+}
+
+pub(crate) trait Driver {
+    /// The address the node listens on.
+    fn local_addr(&self) -> SocketAddr;
+
+    /// The connections that have come in and not been taken yet.
+    fn accept(&mut self) -> Vec<OpenedStream>;
+
+    /// Start connecting to `addr`, without waiting for the connection to open. Returns the token
+    /// the connection is known by, which `wait` reports once there is news of it. Fails right away
+    /// if `addr` can not be reached at all.
+    fn dial(&mut self, addr: &SocketAddr) -> std::io::Result<Token>;
+
+    /// The connections started with `dial` that have opened or failed to since the last call,
+    /// with the tokens `dial` returned for them, which the opened streams keep. A connection that
+    /// is not open after `CONNECT_TIMEOUT` fails with `TimedOut`.
+    fn dialed(&mut self) -> Vec<(Token, std::io::Result<OpenedStream>)>;
+
+    /// Wait until a stream is ready, the node's handle wakes the node up or `wakeup` has come.
+    /// Returns the tokens of the streams that are ready, which may be none.
+    fn wait(&mut self, wakeup: &Wakeup)
+        -> impl Future<Output = std::io::Result<HashSet<Token>>> + Send;
+}
+
+/// Run `future` to completion on the current thread. Only for futures that never wait, like
+/// those of a `MioDriver`, which block the thread instead.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("a future that waits can only be run by an async runtime"),
+    }
+}
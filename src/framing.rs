@@ -0,0 +1,130 @@
+//! Cutting what a peer sends into whole `protocol` messages, however it is split up by the
+//! network.
+//!
+//! A `FrameDecoder` is handed the plaintext of a connection as it arrives and hands out a frame
+//! once all of it is there, never part of one. The frames carry no length, so the decoder works
+//! it out from the header of the frame with `protocol::message_len` as soon as enough of it has
+//! arrived and then simply waits for that many bytes. Each frame is decoded once, however many
+//! pieces it comes in, and a header that breaks protocol is turned away before the rest of its
+//! frame is waited for.
+//!
+//! The decoder does not touch the connection itself, see `connection`.
+
+use crate::protocol::{self, DecodeLimits, Hello, Message};
+
+#[cfg(test)]
+mod tests;
+
+/// A whole frame received from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Frame {
+    /// The first frame of an accepted connection.
+    Hello(Hello),
+    Message(Message),
+}
+
+#[derive(Debug)]
+pub(crate) struct FrameDecoder {
+    /// What has arrived, of which the first `start` bytes have been handed out already.
+    buf: Vec<u8>,
+    start: usize,
+    /// The length of the frame at `start`, once its header has arrived.
+    frame_len: Option<usize>,
+    /// Whether the next frame is a `Hello` rather than a message.
+    awaiting_hello: bool,
+    limits: DecodeLimits,
+    /// How many frames have been decoded, to check that none is decoded twice.
+    #[cfg(test)]
+    decoded: usize,
+}
+
+fn frame_len_mismatch() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "frame does not take up the length its header gives",
+    )
+}
+
+impl FrameDecoder {
+    /// A decoder for a connection the node has opened, on which only messages arrive.
+    pub(crate) fn new(limits: DecodeLimits) -> Self {
+        FrameDecoder {
+            buf: Vec::new(),
+            start: 0,
+            frame_len: None,
+            awaiting_hello: false,
+            limits,
+            #[cfg(test)]
+            decoded: 0,
+        }
+    }
+
+    /// A decoder for a connection the node has accepted, which starts with a `Hello`.
+    pub(crate) fn awaiting_hello(limits: DecodeLimits) -> Self {
+        FrameDecoder {
+            awaiting_hello: true,
+            ..FrameDecoder::new(limits)
+        }
+    }
+
+    /// Add what has arrived from the peer.
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// How many bytes have arrived and not been handed out as part of a frame yet.
+    pub(crate) fn buffered_len(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    /// The next whole frame, or `None` if it has not all arrived yet. Fails with `InvalidData`
+    /// if the peer does not follow protocol, after which the decoder is of no more use.
+    pub(crate) fn next_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        let available = &self.buf[self.start..];
+        let frame_len = match self.frame_len {
+            Some(frame_len) => frame_len,
+            None => {
+                let measured = if self.awaiting_hello {
+                    protocol::hello_len(available)?
+                } else {
+                    protocol::message_len(available, &self.limits)?
+                };
+                match measured {
+                    Some(frame_len) => *self.frame_len.insert(frame_len),
+                    None => return Ok(None),
+                }
+            }
+        };
+        if available.len() < frame_len {
+            return Ok(None);
+        }
+
+        let buf = &available[..frame_len];
+        #[cfg(test)]
+        {
+            self.decoded += 1;
+        }
+        let decoded = if self.awaiting_hello {
+            protocol::read_hello_from_slice(buf).map(|(hello, used)| (Frame::Hello(hello), used))
+        } else {
+            protocol::decode_from_slice(buf, &self.limits)
+                .map(|(message, used)| (Frame::Message(message), used))
+        };
+        // the measuring and the decoding disagreeing is a bug, but the frame is all the same
+        // turned away rather than cut in the wrong place
+        let frame = match decoded {
+            Ok((frame, used)) if used == frame_len => frame,
+            Ok(_) => return Err(frame_len_mismatch()),
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(frame_len_mismatch())
+            }
+            Err(error) => return Err(error),
+        };
+        self.awaiting_hello = false;
+        self.start += frame_len;
+        self.frame_len = None;
+        Ok(Some(frame))
+    }
+}
@@ -0,0 +1,209 @@
+use super::*;
+
+use std::net::SocketAddr;
+
+use crate::identity::{NodeId, SIGNATURE_LEN};
+use crate::protocol::{
+    Gossip, MessageId, PeerInfo, CAPABILITIES, NONCE_LEN, PROTOCOL_VERSION_MAX,
+};
+
+const LIMITS: DecodeLimits = DecodeLimits {
+    max_gossip_len: 1024,
+};
+
+fn peer_info(seed: u8, addr: &str) -> PeerInfo {
+    PeerInfo {
+        node_id: NodeId::from_bytes([seed; 32]),
+        addr: addr.parse().unwrap(),
+    }
+}
+
+fn hello() -> Hello {
+    Hello {
+        min_version: PROTOCOL_VERSION_MAX,
+        max_version: PROTOCOL_VERSION_MAX,
+        capabilities: CAPABILITIES,
+        listen_addr: "[2001:db8::1:2]:25533".parse::<SocketAddr>().unwrap(),
+        node_id: NodeId::from_bytes([0x44; 32]),
+        nonce: [0x55; NONCE_LEN],
+    }
+}
+
+/// The biggest gossip there can be, with a made up signature.
+fn gossip() -> Message {
+    Message::Gossip(Gossip {
+        ttl: 9,
+        hops: 4,
+        origin: NodeId::from_bytes([0x77; 32]),
+        seqno: 12,
+        payload: vec![0x5A; LIMITS.max_gossip_len],
+        signature: [0x88; SIGNATURE_LEN],
+    })
+}
+
+/// One message of every kind of length, with made up signatures.
+fn messages() -> Vec<Message> {
+    vec![
+        gossip(),
+        Message::Confirm {
+            version: PROTOCOL_VERSION_MAX,
+            capabilities: CAPABILITIES,
+            node_id: NodeId::from_bytes([0x11; 32]),
+            nonce: [0x22; NONCE_LEN],
+            signature: [0x33; SIGNATURE_LEN],
+        },
+        Message::Join,
+        Message::Shuffle {
+            peers: vec![
+                peer_info(1, "127.0.0.1:25532"),
+                peer_info(2, "[2001:db8::1:2]:25533"),
+            ],
+        },
+        Message::Reject {
+            reason: "no common protocol version".to_string(),
+        },
+        Message::IHave {
+            ids: vec![
+                MessageId {
+                    origin: NodeId::from_bytes([3; 32]),
+                    seqno: 3,
+                };
+                3
+            ],
+        },
+        Message::Neighbor {
+            high_priority: true,
+        },
+        Message::ForwardJoin {
+            peer: peer_info(4, "[2001:db8::1:2]:25533"),
+            ttl: 6,
+        },
+        Message::Prune,
+    ]
+}
+
+/// The frames `decoder` hands out once `bytes` have arrived.
+fn frames_after(decoder: &mut FrameDecoder, bytes: &[u8]) -> Vec<Frame> {
+    decoder.extend(bytes);
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.next_frame().unwrap() {
+        frames.push(frame);
+    }
+    frames
+}
+
+/// Fed one byte at a time, every frame comes out exactly when its last byte arrives.
+#[test]
+fn byte_at_a_time_test() {
+    let mut wire = Vec::new();
+    protocol::write_hello(&mut wire, &hello()).unwrap();
+    let mut expected = vec![(wire.len(), Frame::Hello(hello()))];
+    for message in messages() {
+        wire.extend_from_slice(&protocol::encode_to_vec(&message));
+        expected.push((wire.len(), Frame::Message(message)));
+    }
+
+    let mut decoder = FrameDecoder::awaiting_hello(LIMITS);
+    let mut received = Vec::new();
+    for (index, byte) in wire.iter().enumerate() {
+        for frame in frames_after(&mut decoder, &[*byte]) {
+            received.push((index + 1, frame));
+        }
+    }
+    assert_eq!(received, expected);
+    assert_eq!(decoder.buffered_len(), 0);
+}
+
+/// A gossip of the biggest size trickling in a byte at a time is decoded once, when it is all
+/// there, not once for every byte.
+#[test]
+fn big_frame_decoded_once_test() {
+    let wire = protocol::encode_to_vec(&gossip());
+    let mut decoder = FrameDecoder::new(LIMITS);
+    for byte in &wire[..wire.len() - 1] {
+        assert_eq!(frames_after(&mut decoder, &[*byte]), []);
+    }
+    assert_eq!(decoder.decoded, 0);
+    assert_eq!(
+        frames_after(&mut decoder, &wire[wire.len() - 1..]),
+        [Frame::Message(gossip())]
+    );
+    assert_eq!(decoder.decoded, 1);
+}
+
+/// However the frames are split up, the same frames come out.
+#[test]
+fn split_anywhere_test() {
+    let messages = messages();
+    let wire: Vec<u8> = messages.iter().flat_map(protocol::encode_to_vec).collect();
+    let expected: Vec<Frame> = messages.into_iter().map(Frame::Message).collect();
+
+    let mut decoder = FrameDecoder::new(LIMITS);
+    assert_eq!(frames_after(&mut decoder, &wire), expected);
+
+    for chunk_len in [2, 7, 100, 1500] {
+        let mut decoder = FrameDecoder::new(LIMITS);
+        let received: Vec<Frame> = wire
+            .chunks(chunk_len)
+            .flat_map(|chunk| frames_after(&mut decoder, chunk))
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    // half a frame stays behind until the rest of it comes
+    let mut wire = protocol::encode_to_vec(&Message::Join);
+    wire.extend_from_slice(&protocol::encode_to_vec(&gossip()));
+    let mut decoder = FrameDecoder::new(LIMITS);
+    assert_eq!(
+        frames_after(&mut decoder, &wire[..11]),
+        [Frame::Message(Message::Join)]
+    );
+    assert_eq!(decoder.buffered_len(), 10);
+    assert_eq!(
+        frames_after(&mut decoder, &wire[11..]),
+        [Frame::Message(gossip())]
+    );
+}
+
+/// A header that breaks protocol fails as soon as it is there, the rest of its frame is not
+/// waited for.
+#[test]
+fn broken_header_test() {
+    let mut decoder = FrameDecoder::new(LIMITS);
+    decoder.extend(&[42]);
+    let error = decoder.next_frame().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // a gossip announcing more than the limit
+    let mut decoder = FrameDecoder::new(LIMITS);
+    let header = [&[1, 0, 0][..], &[0; 32], &[0; 8], &[0xFF; 4]].concat();
+    for byte in &header[..header.len() - 1] {
+        assert_eq!(frames_after(&mut decoder, &[*byte]), []);
+    }
+    decoder.extend(&header[header.len() - 1..]);
+    let error = decoder.next_frame().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // what is checked only when the message is decoded fails once the message is complete
+    let mut decoder = FrameDecoder::new(LIMITS);
+    assert_eq!(frames_after(&mut decoder, &[13]), []);
+    decoder.extend(&[2]);
+    let error = decoder.next_frame().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut wire = Vec::new();
+    protocol::write_hello(&mut wire, &hello()).unwrap();
+    wire[0] ^= 1;
+    let mut decoder = FrameDecoder::awaiting_hello(LIMITS);
+    decoder.extend(&wire);
+    let error = decoder.next_frame().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // a `Hello` where only messages are expected is garbage
+    let mut wire = Vec::new();
+    protocol::write_hello(&mut wire, &hello()).unwrap();
+    let mut decoder = FrameDecoder::new(LIMITS);
+    decoder.extend(&wire);
+    let error = decoder.next_frame().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,150 @@
+//! Node identities. Every node owns an ed25519 keypair and is known to the rest of the network by
+//! the public half of it, its `NodeId`. Unlike a listening address a node id can not be claimed
+//! by just anyone, the handshake makes both sides prove they hold the matching secret key.
+
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+
+#[cfg(test)]
+mod tests;
+
+/// The length of an ed25519 signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// An ed25519 signature as it is sent over the wire.
+pub type Signature = [u8; SIGNATURE_LEN];
+
+/// The public key of a node, which is what the network knows it by.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; 32]);
+
+impl NodeId {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        NodeId(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Check that `signature` over `message` was made with the secret key of this node. Node ids
+    /// that are not valid public keys never verify anything.
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        match VerifyingKey::from_bytes(&self.0) {
+            Ok(key) => key
+                .verify(message, &ed25519_dalek::Signature::from_bytes(signature))
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the hex form a node id is displayed in.
+impl FromStr for NodeId {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> std::io::Result<Self> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{:?} is not a node id, which is 64 hex digits", s),
+            )
+        };
+        let mut bytes = [0; 32];
+        if s.len() != 2 * bytes.len() || !s.is_ascii() {
+            return Err(invalid());
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(NodeId(bytes))
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+/// The keypair of a node. The secret key never leaves this struct, not even through `Debug`.
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    /// A fresh random identity. It is gone once the node stops, use `load_or_generate` for one
+    /// that survives restarts.
+    pub fn generate() -> Self {
+        Identity {
+            signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    /// Load the identity stored in the key file at `path`, or generate one and store it there if
+    /// the file does not exist yet. The key file holds the 32 bytes of the secret key and nothing
+    /// else. On unix it is created readable by its owner only.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                let secret_key: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "key file {} holds {} bytes instead of a 32 byte key",
+                            path.display(),
+                            bytes.len()
+                        ),
+                    )
+                })?;
+                Ok(Identity {
+                    signing_key: SigningKey::from_bytes(&secret_key),
+                })
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options
+                    .open(path)?
+                    .write_all(&identity.signing_key.to_bytes())?;
+                Ok(identity)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        NodeId(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Sign `message` with the secret key.
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.signing_key.sign(message).to_bytes()
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("node_id", &self.node_id())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,44 @@
+use super::*;
+
+#[test]
+fn sign_and_verify_test() {
+    let identity = Identity::generate();
+    let signature = identity.sign(b"some message");
+    assert!(identity.node_id().verify(b"some message", &signature));
+    assert!(!identity.node_id().verify(b"another message", &signature));
+    assert!(!Identity::generate().node_id().verify(b"some message", &signature));
+
+    let mut bad_signature = signature;
+    bad_signature[10] ^= 1;
+    assert!(!identity.node_id().verify(b"some message", &bad_signature));
+}
+
+#[test]
+fn key_file_test() {
+    let path = std::env::temp_dir().join(format!(
+        "p2p_gossip_key_file_test_{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+
+    let generated = Identity::load_or_generate(&path).unwrap();
+    let loaded = Identity::load_or_generate(&path).unwrap();
+    assert_eq!(generated.node_id(), loaded.node_id());
+    assert_eq!(std::fs::read(&path).unwrap().len(), 32);
+
+    std::fs::write(&path, b"not a key").unwrap();
+    let error = Identity::load_or_generate(&path).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn node_id_from_str_test() {
+    let node_id = Identity::generate().node_id();
+    assert_eq!(node_id.to_string().parse::<NodeId>().unwrap(), node_id);
+    assert_eq!(node_id.to_string().to_uppercase().parse::<NodeId>().unwrap(), node_id);
+    assert!("abcd".parse::<NodeId>().is_err());
+    assert!("z".repeat(64).parse::<NodeId>().is_err());
+    assert!(format!("{}é", &node_id.to_string()[..62]).parse::<NodeId>().is_err());
+}
@@ -0,0 +1,719 @@
+//! A simple peer-to-peer gossiping library.
+//!
+//! Each `GossipNode` acts as a "peer". It listens for other peers, keeps connections to a few of
+//! the peers it learns about and forwards every gossip message it hears to them, so that in the
+//! end every peer in the network has received every message. Peers that go down or misbehave are
+//! simply dropped and replaced with others the node knows about, and the network continues
+//! without them.
+//!
+//! Every node has an ed25519 keypair and is known to the network by its public key, its `NodeId`.
+//! The keypair can be kept in a key file so that a node keeps its id across restarts. The
+//! connections between nodes are encrypted with the Noise protocol unless configured otherwise.
+//!
+//! A `GossipNode` runs on a thread of its own. With the `async` feature a node can run as a task
+//! of a tokio runtime instead, see `AsyncGossipNode` and `run`. Both kinds of node speak the same
+//! protocol and can be peers of each other. The connections go over TCP, Unix domain sockets or
+//! an in-process `MemoryNetwork`, see `TransportKind`.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use p2p_gossip::{GossipNode, NodeEvent};
+//!
+//! let node = GossipNode::builder()
+//!     .bind_addr("127.0.0.1:25532".parse().unwrap())
+//!     .bootstrap_peer("127.0.0.1:25533".parse().unwrap())
+//!     .key_file("node.key")
+//!     .spawn()
+//!     .expect("failed to start the node");
+//! let events = node.subscribe();
+//! node.publish("hello peers").expect("the node has stopped");
+//! for event in events {
+//!     if let NodeEvent::GossipReceived { payload, id, .. } = event {
+//!         println!("{} says {:?}", id.origin, payload);
+//!     }
+//! }
+//! ```
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+mod address_book;
+mod anti_entropy;
+#[cfg(feature = "async")]
+mod async_node;
+mod backoff;
+mod connection;
+mod driver;
+mod framing;
+mod identity;
+mod membership;
+mod node;
+mod noise;
+mod plumtree;
+pub mod protocol;
+mod reactor;
+mod reconnect;
+mod seen_cache;
+mod transport;
+
+#[cfg(test)]
+mod tests;
+
+use address_book::AddressBook;
+use driver::{block_on, Driver};
+use node::{EventSubscribers, NodeCommand, NodeConfig, Reply, Subscriber};
+use protocol::{Gossip, PeerInfo};
+use reactor::MioDriver;
+use transport::{TcpTransport, Transport};
+#[cfg(unix)]
+use transport::UnixTransport;
+
+#[cfg(feature = "async")]
+pub use async_node::{run, AsyncGossipNode};
+pub use identity::{Identity, NodeId, Signature};
+pub use node::{
+    DEFAULT_ACTIVE_VIEW_SIZE, DEFAULT_ANTI_ENTROPY_RETENTION, DEFAULT_GOSSIP_TTL,
+    DEFAULT_MAX_GOSSIP_LEN, DEFAULT_MAX_INBOUND, DEFAULT_MAX_OUTBOUND, DEFAULT_MAX_TOTAL,
+    DEFAULT_PASSIVE_VIEW_SIZE, DEFAULT_RECONNECT_ATTEMPTS, DEFAULT_SEND_QUEUE_HIGH_WATER_MARK,
+};
+pub use protocol::MessageId;
+pub use transport::MemoryNetwork;
+
+/// Something that happened on a running node. Obtained through `GossipNode::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeEvent {
+    /// A peer sent the node gossip it had not heard before. `id` tells it apart from all other
+    /// gossip, `id.origin` is the node that published it, whose signature on it has been
+    /// checked. `from` is the node id of the peer that relayed it, which is not necessarily the
+    /// origin. `hops` is the number of peers that relayed it before `from`, so it is 0 if it came
+    /// straight from the origin.
+    GossipReceived {
+        payload: Vec<u8>,
+        id: MessageId,
+        from: NodeId,
+        hops: u8,
+    },
+    /// The handshake with the peer `node_id`, listening on `addr`, has completed.
+    PeerConnected { node_id: NodeId, addr: SocketAddr },
+    /// The node has lost its last connection to the peer `node_id`, listening on `addr`, either
+    /// because the connection failed or because the peer broke protocol.
+    PeerDisconnected { node_id: NodeId, addr: SocketAddr },
+    /// The node is connecting to the peer `node_id`, listening on `addr`, again after losing its
+    /// connection to it. This is the `attempt`th time, counting from 1. A `PeerConnected` follows
+    /// if the peer is back.
+    ReconnectAttempt {
+        node_id: NodeId,
+        addr: SocketAddr,
+        attempt: u32,
+    },
+    /// The node has given up on reconnecting to the peer `node_id`, listening on `addr`, after
+    /// `attempts` attempts.
+    ReconnectGaveUp {
+        node_id: NodeId,
+        addr: SocketAddr,
+        attempts: u32,
+    },
+}
+
+/// A snapshot of the state of a running node. Obtained through `GossipNode::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeStats {
+    /// The peers the node is connected to, its active view.
+    pub connected_peers: Vec<PeerInfo>,
+    /// How many peers the node knows about without being connected to them, its passive view.
+    pub passive_peers: usize,
+    /// The peers the node lost its connection to and is trying to reconnect to, the ones lost
+    /// first first.
+    pub reconnecting_peers: Vec<ReconnectingPeer>,
+}
+
+/// A peer the node lost its connection to and is trying to reconnect to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectingPeer {
+    pub node_id: NodeId,
+    pub addr: SocketAddr,
+    /// How many times the node tried to reconnect so far.
+    pub attempts: u32,
+    /// How long until the next attempt, or until the node gives up if it has made all of them.
+    pub next_attempt_in: Duration,
+}
+
+/// Whether a node encrypts its peer connections. Encryption is decided by the peer that opens a
+/// connection, the accepting peer can only turn it away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// The connections the node opens are plaintext. Peers that connect to it with encryption
+    /// still get it.
+    Off,
+    /// The connections the node opens are encrypted, and peers may connect to it either way.
+    Preferred,
+    /// The connections the node opens are encrypted, and peers that connect to it in plaintext
+    /// are rejected.
+    Required,
+}
+
+/// How a node remembers the gossip it has already heard, so that it delivers and relays every
+/// gossip only once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateSuppression {
+    /// Remember the id of every gossip. Never mistakes fresh gossip for a duplicate, but the
+    /// memory it takes grows with the traffic.
+    Exact,
+    /// Remember gossip ids in rotating bloom filters that take a fixed amount of memory. They are
+    /// sized for `capacity` gossips per retention time and take fresh gossip for a duplicate, and
+    /// drop it, with a probability of at most `false_positive_rate`. Past `capacity` the false
+    /// positive rate holds, but gossip is forgotten early.
+    Bloom {
+        capacity: usize,
+        false_positive_rate: f64,
+    },
+}
+
+/// Which peers a node passes gossip on to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardingStrategy {
+    /// Send every gossip to every peer, including the one it came from.
+    Flood,
+    /// Send every gossip to every peer except the one it came from, which already has it.
+    ExcludeSender,
+    /// Send every gossip to this many peers picked at random, never the one it came from. This
+    /// bounds the bandwidth a gossip takes per node, but a fanout that is too low for the size
+    /// of the network leaves some nodes without it. The gossip the node publishes itself goes to
+    /// as many random peers.
+    RandomFanout(usize),
+    /// Build a broadcast tree with plumtree. Gossip is pushed along the tree and only announced to
+    /// the other peers, which ask for it if the tree breaks and it does not arrive. This takes
+    /// far less bandwidth than flooding once the network is bigger than a handful of nodes. Peers
+    /// that do not run plumtree themselves are always sent every gossip.
+    Plumtree,
+}
+
+/// What a node does when the send queue of a peer that does not keep up with what it is sent
+/// grows past its high-water mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendQueuePolicy {
+    /// Drop the oldest packets in the queue until it is back under the mark. The peer misses them,
+    /// which plumtree and anti-entropy make up for if the node runs them.
+    DropOldest,
+    /// Close the connection to the peer. It is reconnected to like any other peer whose connection
+    /// failed.
+    DropPeer,
+    /// Stop reading from the other peers until the queue is back under the mark, so that the gossip
+    /// coming in slows down to what the slowest peer takes and nothing is lost. The slow peer
+    /// itself is still read, so that two peers filling each other's queues do not wait for each
+    /// other. A peer whose queue stays full for 10 seconds is dropped.
+    Backpressure,
+}
+
+/// What a node's connections to its peers are carried over. Peers are known by socket addresses on
+/// every transport, and a node only reaches the peers on the same transport as itself.
+///
+/// A `GossipNode` runs on all of them. An async node, started with `spawn_async` with the `async`
+/// feature, only runs on `Tcp` so far, and fails to start with `Unsupported` on the others.
+#[derive(Debug, Clone)]
+pub enum TransportKind {
+    /// TCP, over any network.
+    Tcp,
+    /// Unix domain sockets in the directory, for nodes that run as processes on the same host.
+    /// The node listening on an address has its socket in the directory under the address as
+    /// its name, so the addresses are just names and a node bound to port 0 is given a free
+    /// one. The directory has to exist already, and its path has to be short since a socket path
+    /// is at most about a hundred bytes.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A `MemoryNetwork` shared by nodes of the same process. Nothing leaves the process and no
+    /// ports are used, so it fits tests that run a lot of nodes.
+    Memory(MemoryNetwork),
+}
+
+/// Configures and starts a `GossipNode`. Obtained from `GossipNode::builder`.
+///
+/// By default the node listens on an OS assigned ipv4 loopback port, only sends the gossip that is
+/// published through its handle, starts out without any peers and runs until it is shut down. It
+/// gets a fresh identity that is forgotten when it stops, its encryption is
+/// `Encryption::Preferred`, its duplicate suppression is `DuplicateSuppression::Exact`, its
+/// forwarding strategy is `ForwardingStrategy::Flood`, it runs no anti-entropy rounds, it drops
+/// the oldest packets queued for peers that do not keep up, the peers it hears of are forgotten
+/// when it stops and its connections are carried over TCP.
+#[derive(Debug, Clone)]
+pub struct GossipNodeBuilder {
+    config: NodeConfig,
+    key_file: Option<PathBuf>,
+    address_book: Option<PathBuf>,
+    transport: TransportKind,
+}
+
+impl GossipNodeBuilder {
+    /// The address the node listens on. This is also the address the node tells other peers
+    /// to reach it on, so it should not be an unspecified address like `0.0.0.0`.
+    pub fn bind_addr(mut self, bind_addr: SocketAddr) -> Self {
+        self.config.bind_addr = bind_addr;
+        self
+    }
+
+    /// Makes the node send a random gossip message to its peers every `period`, on top of what
+    /// is published through `GossipNode::publish`. This is a demo mode, it gives a network
+    /// something to talk about when nobody is publishing real data.
+    pub fn random_gossip_period(mut self, period: Duration) -> Self {
+        self.config.random_gossip_period = Some(period);
+        self
+    }
+
+    /// A peer to connect to when starting. Through it the node discovers the rest of the
+    /// network. Called more than once it adds more bootstrap peers, which are tried in random
+    /// order until one of them can be reached. If none can, the node keeps trying them, backing
+    /// off exponentially, until it gets into the network.
+    pub fn bootstrap_peer(mut self, bootstrap_peer: SocketAddr) -> Self {
+        self.config.bootstrap_peers.push(bootstrap_peer);
+        self
+    }
+
+    /// The largest gossip payload, in bytes, the node sends or accepts. A peer that sends a bigger
+    /// gossip is violating the protocol and gets dropped, so all the nodes in a network should
+    /// agree on this. Defaults to `DEFAULT_MAX_GOSSIP_LEN`.
+    pub fn max_gossip_len(mut self, max_gossip_len: usize) -> Self {
+        self.config.max_gossip_len = max_gossip_len;
+        self
+    }
+
+    /// How many times the gossip the node publishes may be relayed before it stops spreading. The
+    /// gossip can then reach peers `ttl + 1` hops away. Defaults to `DEFAULT_GOSSIP_TTL`.
+    pub fn gossip_ttl(mut self, ttl: u8) -> Self {
+        self.config.gossip_ttl = ttl;
+        self
+    }
+
+    /// The identity the node is known by in the network.
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.config.identity = identity;
+        self.key_file = None;
+        self
+    }
+
+    /// Keep the node's identity in the key file at `path`, so that the node has the same node id
+    /// every time it is started. The key file is created with a fresh identity if it does not
+    /// exist yet.
+    pub fn key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.key_file = Some(path.into());
+        self
+    }
+
+    /// Keep the peers the node hears of in the address book file at `path`, with when they were
+    /// last seen, how often connecting to them worked and failed and how the node heard of them.
+    /// A restarted node reads the book back and joins the network through the peers in it if its
+    /// bootstrap peers are down, or if it has none. The file is created if it does not exist yet.
+    pub fn address_book(mut self, path: impl Into<PathBuf>) -> Self {
+        self.address_book = Some(path.into());
+        self
+    }
+
+    /// Whether the node encrypts its peer connections, see `Encryption`.
+    pub fn encryption(mut self, encryption: Encryption) -> Self {
+        self.config.encryption = encryption;
+        self
+    }
+
+    /// How the node recognizes gossip it has already heard, see `DuplicateSuppression`.
+    pub fn duplicate_suppression(mut self, duplicate_suppression: DuplicateSuppression) -> Self {
+        self.config.duplicate_suppression = duplicate_suppression;
+        self
+    }
+
+    /// Which peers the node passes gossip on to, see `ForwardingStrategy`.
+    pub fn forwarding_strategy(mut self, forwarding_strategy: ForwardingStrategy) -> Self {
+        self.config.forwarding_strategy = forwarding_strategy;
+        self
+    }
+
+    /// How many peers the node keeps connections to, its active view. The node joins the network
+    /// through its bootstrap peer and learns about the peers to connect to, and about those it
+    /// keeps in its passive view to replace the ones that fail, from the network. Defaults to
+    /// `DEFAULT_ACTIVE_VIEW_SIZE`.
+    pub fn active_view_size(mut self, active_view_size: usize) -> Self {
+        self.config.active_view_size = active_view_size;
+        self
+    }
+
+    /// How many peers the node knows about without being connected to them, its passive view.
+    /// Defaults to `DEFAULT_PASSIVE_VIEW_SIZE`.
+    pub fn passive_view_size(mut self, passive_view_size: usize) -> Self {
+        self.config.passive_view_size = passive_view_size;
+        self
+    }
+
+    /// The most connections other peers may open to the node. A peer connecting beyond it is sent
+    /// a list of other peers to try and is turned away. Connections that are still in their
+    /// handshake count too. Defaults to `DEFAULT_MAX_INBOUND`.
+    pub fn max_inbound(mut self, max_inbound: usize) -> Self {
+        self.config.max_inbound = max_inbound;
+        self
+    }
+
+    /// The most connections the node opens to other peers. Past it the peers the node would
+    /// connect to are kept in its passive view for later. Defaults to `DEFAULT_MAX_OUTBOUND`.
+    pub fn max_outbound(mut self, max_outbound: usize) -> Self {
+        self.config.max_outbound = max_outbound;
+        self
+    }
+
+    /// The most connections the node has, in both directions together. Defaults to
+    /// `DEFAULT_MAX_TOTAL`.
+    pub fn max_total(mut self, max_total: usize) -> Self {
+        self.config.max_total = max_total;
+        self
+    }
+
+    /// How many times the node tries to reconnect to a peer whose connection failed before it
+    /// gives up on it, waiting twice as long after every attempt. 0 turns reconnecting off.
+    /// Defaults to `DEFAULT_RECONNECT_ATTEMPTS`.
+    pub fn reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.config.reconnect_attempts = attempts;
+        self
+    }
+
+    /// How many bytes the node queues for a peer before its send queue counts as full. Defaults to
+    /// `DEFAULT_SEND_QUEUE_HIGH_WATER_MARK`.
+    pub fn send_queue_high_water_mark(mut self, high_water_mark: usize) -> Self {
+        self.config.send_queue_high_water_mark = high_water_mark;
+        self
+    }
+
+    /// What the node does when a peer's send queue is full. Defaults to
+    /// `SendQueuePolicy::DropOldest`.
+    pub fn send_queue_policy(mut self, policy: SendQueuePolicy) -> Self {
+        self.config.send_queue_policy = policy;
+        self
+    }
+
+    /// Makes the node run an anti-entropy round every `period`. It sends a digest of the gossip it
+    /// holds to a random peer, which sends back the gossip the node missed, for example while it
+    /// was cut off from the network. The node also answers the digests of its peers. Only peers
+    /// that run anti-entropy themselves take part.
+    pub fn anti_entropy_period(mut self, period: Duration) -> Self {
+        self.config.anti_entropy_period = Some(period);
+        self
+    }
+
+    /// How long the node holds on to gossip for anti-entropy. Gossip the node missed for longer
+    /// than this is not recovered. Defaults to `DEFAULT_ANTI_ENTROPY_RETENTION`.
+    pub fn anti_entropy_retention(mut self, retention: Duration) -> Self {
+        self.config.anti_entropy_retention = retention;
+        self
+    }
+
+    /// What the node's connections are carried over, see `TransportKind`. The bind address and
+    /// the bootstrap peers are addresses on this transport.
+    pub fn transport(mut self, transport: TransportKind) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Makes the node stop on its own after running for `lifetime`.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.config.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Start the node on a thread of its own. This fails if the duplicate suppression is
+    /// configured with a capacity of zero or a false positive rate outside of `(0, 1)`, if the
+    /// active view size, the send queue high-water mark or one of the connection limits is zero,
+    /// if the key file or address book cannot be read, if the key file cannot be created or if
+    /// the listening socket cannot be bound. The node joins the network through its bootstrap peers once it runs, so gossip
+    /// published before it has any peers is not sent to anybody. A node whose bootstrap peers can
+    /// not be reached still starts, and keeps trying to reach them.
+    pub fn spawn(self) -> std::io::Result<GossipNode> {
+        match self.transport.clone() {
+            TransportKind::Tcp => self.spawn_on(TcpTransport),
+            #[cfg(unix)]
+            TransportKind::Unix(dir) => self.spawn_on(UnixTransport::new(dir)),
+            TransportKind::Memory(network) => self.spawn_on(network),
+        }
+    }
+
+    /// Start the node on a thread of its own, with its connections carried by `transport`.
+    fn spawn_on<T: Transport>(mut self, transport: T) -> std::io::Result<GossipNode> {
+        let address_book = self.prepare()?;
+        let driver = MioDriver::bind(transport, self.config.bind_addr)?;
+        let waker = NodeWaker::Reactor(driver.waker());
+        let started = node::start_peer(&self.config, address_book, driver);
+        let (handle, commands) = NodeHandle::new(&self.config, started.driver.local_addr(), waker);
+        let config = self.config;
+        let shutdown = handle.shutdown.clone();
+        let subscribers = handle.subscribers.clone();
+        let next_seqno = handle.next_seqno.clone();
+        let thread = std::thread::spawn(move || {
+            block_on(node::do_peer(
+                started,
+                &config,
+                &shutdown,
+                commands,
+                &subscribers,
+                &next_seqno,
+            ));
+        });
+
+        Ok(GossipNode {
+            handle,
+            thread: Some(thread),
+        })
+    }
+
+    /// Check the configuration and read the key file and address book, see `spawn`. Returns the
+    /// address book.
+    fn prepare(&mut self) -> std::io::Result<AddressBook> {
+        if let DuplicateSuppression::Bloom {
+            capacity,
+            false_positive_rate,
+        } = self.config.duplicate_suppression
+        {
+            if capacity == 0 || !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "a bloom filter for {} gossips with a false positive rate of {} is not possible",
+                        capacity, false_positive_rate
+                    ),
+                ));
+            }
+        }
+        if self.config.active_view_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the active view has to have room for at least one peer",
+            ));
+        }
+        if self.config.send_queue_high_water_mark == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the send queues have to have room for at least one byte",
+            ));
+        }
+        if self.config.max_inbound == 0
+            || self.config.max_outbound == 0
+            || self.config.max_total == 0
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the connection limits have to allow at least one connection each",
+            ));
+        }
+        if let Some(key_file) = &self.key_file {
+            self.config.identity = Identity::load_or_generate(key_file)?;
+        }
+        match &self.address_book {
+            Some(path) => AddressBook::load(path.clone(), self.config.identity.node_id()),
+            None => Ok(AddressBook::in_memory(self.config.identity.node_id())),
+        }
+    }
+}
+
+/// A handle to a running node. Dropping the handle shuts the node down.
+#[derive(Debug)]
+pub struct GossipNode {
+    handle: NodeHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl GossipNode {
+    pub fn builder() -> GossipNodeBuilder {
+        GossipNodeBuilder {
+            config: NodeConfig {
+                bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+                random_gossip_period: None,
+                bootstrap_peers: Vec::new(),
+                lifetime: None,
+                max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+                gossip_ttl: DEFAULT_GOSSIP_TTL,
+                identity: Identity::generate(),
+                encryption: Encryption::Preferred,
+                duplicate_suppression: DuplicateSuppression::Exact,
+                forwarding_strategy: ForwardingStrategy::Flood,
+                anti_entropy_period: None,
+                anti_entropy_retention: DEFAULT_ANTI_ENTROPY_RETENTION,
+                active_view_size: DEFAULT_ACTIVE_VIEW_SIZE,
+                passive_view_size: DEFAULT_PASSIVE_VIEW_SIZE,
+                max_inbound: DEFAULT_MAX_INBOUND,
+                max_outbound: DEFAULT_MAX_OUTBOUND,
+                max_total: DEFAULT_MAX_TOTAL,
+                reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+                send_queue_high_water_mark: DEFAULT_SEND_QUEUE_HIGH_WATER_MARK,
+                send_queue_policy: SendQueuePolicy::DropOldest,
+            },
+            key_file: None,
+            address_book: None,
+            transport: TransportKind::Tcp,
+        }
+    }
+
+    /// The address the node is listening on and advertising to other peers.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.local_addr
+    }
+
+    /// The node id the node is known by in the network.
+    pub fn node_id(&self) -> NodeId {
+        self.handle.identity.node_id()
+    }
+
+    /// Broadcast `gossip` to the network, signed with the node's identity. The node sends it out
+    /// on its next loop iteration, to the peers its `ForwardingStrategy` picks. Every call
+    /// publishes a new gossip, even with a payload that was published before, and returns the id
+    /// the network knows it by. Fails if the gossip is longer than the node's maximum gossip
+    /// length or if the node is no longer running.
+    pub fn publish(&self, gossip: impl Into<Vec<u8>>) -> std::io::Result<MessageId> {
+        self.handle.publish(gossip.into())
+    }
+
+    /// A snapshot of the node's state, taken on its next loop iteration. Fails if the node is no
+    /// longer running.
+    pub fn stats(&self) -> std::io::Result<NodeStats> {
+        let (sender, receiver) = mpsc::channel();
+        self.handle.send_command(NodeCommand::Stats(Reply::Blocking(sender)))?;
+        receiver.recv().map_err(|_| node_stopped())
+    }
+
+    /// Subscribe to the node's events. Every event that happens after this call is delivered to
+    /// the returned receiver, in order. The receiver disconnects when the node stops. Events pile
+    /// up in the receiver until they are received, so drop the receiver once you lose interest.
+    pub fn subscribe(&self) -> mpsc::Receiver<NodeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.handle.subscribe(Subscriber::Blocking(sender));
+        receiver
+    }
+
+    /// Ask the node to stop. The node finishes its current loop iteration and then closes all
+    /// of its connections. Use `join` to wait for that to happen.
+    pub fn shutdown(&self) {
+        self.handle.shutdown();
+    }
+
+    /// Wait for the node to stop, either because of `shutdown` or because its lifetime ran out.
+    /// Returns an error if the node's thread panicked.
+    pub fn join(mut self) -> std::thread::Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Wakes a node up when it is waiting for its sockets, so that it sees the commands and the
+/// shutdown right away.
+#[derive(Debug)]
+enum NodeWaker {
+    Reactor(Arc<mio::Waker>),
+    #[cfg(feature = "async")]
+    Task(Arc<tokio::sync::Notify>),
+}
+
+impl NodeWaker {
+    fn wake(&self) -> std::io::Result<()> {
+        match self {
+            NodeWaker::Reactor(waker) => waker.wake(),
+            #[cfg(feature = "async")]
+            NodeWaker::Task(notify) => {
+                notify.notify_one();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What a handle shares with the node it controls. `GossipNode` and `AsyncGossipNode` only differ
+/// in what they run the node on.
+#[derive(Debug)]
+struct NodeHandle {
+    local_addr: SocketAddr,
+    identity: Identity,
+    shutdown: Arc<AtomicBool>,
+    commands: mpsc::Sender<NodeCommand>,
+    waker: NodeWaker,
+    subscribers: Arc<EventSubscribers>,
+    next_seqno: Arc<AtomicU64>,
+    max_gossip_len: usize,
+    gossip_ttl: u8,
+}
+
+impl NodeHandle {
+    /// A handle to the node that is about to run with `config`, listening on `local_addr`.
+    /// Returns the receiving end of the handle's commands, for the node.
+    fn new(
+        config: &NodeConfig,
+        local_addr: SocketAddr,
+        waker: NodeWaker,
+    ) -> (Self, mpsc::Receiver<NodeCommand>) {
+        let (commands, receiver) = mpsc::channel();
+        // sequence numbers start at the current time so that a restarted node does not reuse the
+        // ones it had before, unless it published more than a million gossips per second
+        let next_seqno = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("the clock is set after 1970")
+            .as_micros() as u64;
+        let handle = NodeHandle {
+            local_addr,
+            identity: config.identity.clone(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            commands,
+            waker,
+            subscribers: Arc::new(Mutex::new(Some(Vec::new()))),
+            next_seqno: Arc::new(AtomicU64::new(next_seqno)),
+            max_gossip_len: config.max_gossip_len,
+            gossip_ttl: config.gossip_ttl,
+        };
+        (handle, receiver)
+    }
+
+    fn publish(&self, gossip: Vec<u8>) -> std::io::Result<MessageId> {
+        if gossip.len() > self.max_gossip_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "gossip is {} bytes, the maximum is {}",
+                    gossip.len(),
+                    self.max_gossip_len
+                ),
+            ));
+        }
+        let seqno = self.next_seqno.fetch_add(1, Ordering::Relaxed);
+        let gossip = Gossip::sign(&self.identity, seqno, self.gossip_ttl, gossip);
+        let id = gossip.id();
+        self.send_command(NodeCommand::Publish(gossip))?;
+        Ok(id)
+    }
+
+    /// Hand `command` to the node and wake it up to pick it up. Fails if the node is no longer
+    /// running.
+    fn send_command(&self, command: NodeCommand) -> std::io::Result<()> {
+        self.commands.send(command).map_err(|_| node_stopped())?;
+        self.waker.wake()
+    }
+
+    fn subscribe(&self, subscriber: Subscriber) {
+        if let Some(subscribers) = self.subscribers.lock().unwrap().as_mut() {
+            subscribers.push(subscriber);
+        } // otherwise the node has stopped and the subscriber is dropped right away
+    }
+
+    fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        // the node only fails to be woken if it has stopped already
+        let _ = self.waker.wake();
+    }
+}
+
+fn node_stopped() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "the node is no longer running",
+    )
+}
+
+impl Drop for GossipNode {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,148 @@
+//! The bookkeeping of the partial view membership of HyParView, by Leitão, Pereira and Rodrigues.
+//!
+//! A node only keeps connections to a handful of peers, its active view, and merely knows about
+//! a bigger number of others, its passive view. Gossip is spread over the active view alone, which
+//! keeps the number of connections per node bounded whatever the size of the network.
+//!
+//! A node joins the network by sending a `Join` to its bootstrap peer. That peer takes it into its
+//! active view and sends a `ForwardJoin` on a random walk through the network from each of its
+//! other active peers. The node where a walk ends connects to the joining node, and the nodes it
+//! passes at a set depth add the joining node to their passive view. A node whose active view is
+//! full makes room by sending a random active peer a `Disconnect`, which moves that peer to the
+//! passive view on both sides.
+//!
+//! When an active peer fails, a random passive peer is connected to and sent a `Neighbor`. It may
+//! turn the node away with a `Disconnect` if its own active view is full, unless the node has no
+//! active peers left and asks with high priority. The passive views are kept fresh by regular
+//! `Shuffle`s, in which two active peers swap a sample of the peers they know.
+//!
+//! The active view is made up of the confirmed connections of the node, what is kept here is the
+//! passive view.
+
+use rand::seq::{index, SliceRandom};
+
+use crate::identity::NodeId;
+use crate::protocol::PeerInfo;
+
+#[cfg(test)]
+mod tests;
+
+/// The number of steps a `ForwardJoin` walks before the joining node is taken into an active view.
+pub(crate) const ACTIVE_RANDOM_WALK_LEN: u8 = 6;
+
+/// The number of steps left on a `ForwardJoin` when the node it reaches adds the joining node to
+/// its passive view.
+pub(crate) const PASSIVE_RANDOM_WALK_LEN: u8 = 3;
+
+/// How many active peers a node puts in the sample it sends in a `Shuffle`.
+const SHUFFLE_ACTIVE_LEN: usize = 3;
+
+/// How many passive peers a node puts in the sample it sends in a `Shuffle`.
+const SHUFFLE_PASSIVE_LEN: usize = 4;
+
+/// The peers a node knows about without being connected to them.
+#[derive(Debug)]
+pub(crate) struct PassiveView {
+    size: usize,
+    peers: Vec<PeerInfo>,
+}
+
+impl PassiveView {
+    pub(crate) fn new(size: usize) -> Self {
+        PassiveView {
+            size,
+            peers: Vec::new(),
+        }
+    }
+
+    /// Add `peer` to the view, making room by forgetting a random peer if it is full. A peer that
+    /// is already in the view only has its address updated. `own_node_id` and the peers that are
+    /// `active` never make it into the view.
+    pub(crate) fn insert(
+        &mut self,
+        peer: PeerInfo,
+        own_node_id: &NodeId,
+        active: impl Fn(&NodeId) -> bool,
+    ) {
+        if self.size == 0 || peer.node_id == *own_node_id || active(&peer.node_id) {
+            return;
+        }
+        if let Some(known) = self.peers.iter_mut().find(|known| known.node_id == peer.node_id) {
+            known.addr = peer.addr;
+            return;
+        }
+        if self.peers.len() >= self.size {
+            let evicted = rand::random::<usize>() % self.peers.len();
+            self.peers.swap_remove(evicted);
+        }
+        self.peers.push(peer);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub(crate) fn remove(&mut self, node_id: &NodeId) {
+        self.peers.retain(|peer| peer.node_id != *node_id);
+    }
+
+    /// Take a random peer out of the view, to be connected to.
+    pub(crate) fn take_random(&mut self) -> Option<PeerInfo> {
+        if self.peers.is_empty() {
+            return None;
+        }
+        let index = rand::random::<usize>() % self.peers.len();
+        Some(self.peers.swap_remove(index))
+    }
+
+    /// Up to `len` random peers from the view.
+    pub(crate) fn sample(&self, len: usize) -> Vec<PeerInfo> {
+        self.peers
+            .choose_multiple(&mut rand::thread_rng(), len)
+            .copied()
+            .collect()
+    }
+
+    /// The peers a node sends in a `Shuffle`, itself followed by a random sample of its `active`
+    /// peers and of this view.
+    pub(crate) fn shuffle_sample(&self, own: PeerInfo, active: &[PeerInfo]) -> Vec<PeerInfo> {
+        let mut sample = vec![own];
+        sample.extend(
+            index::sample(
+                &mut rand::thread_rng(),
+                active.len(),
+                SHUFFLE_ACTIVE_LEN.min(active.len()),
+            )
+            .into_iter()
+            .map(|index| active[index]),
+        );
+        sample.extend(self.sample(SHUFFLE_PASSIVE_LEN));
+        sample
+    }
+}
+
+/// What to do with a `ForwardJoin` that arrives with `ttl` steps left at a node with
+/// `active_len` active peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ForwardJoinAction {
+    /// The walk ends here, connect to the joining node.
+    Accept,
+    /// Pass the `ForwardJoin` on with `ttl` steps left, adding the joining node to the passive
+    /// view first if `passive` is set.
+    Forward { ttl: u8, passive: bool },
+}
+
+pub(crate) fn forward_join_action(ttl: u8, active_len: usize) -> ForwardJoinAction {
+    // a node whose only active peer is the one the walk came from has nowhere to pass it on to
+    if ttl == 0 || active_len <= 1 {
+        return ForwardJoinAction::Accept;
+    }
+    ForwardJoinAction::Forward {
+        ttl: ttl - 1,
+        passive: ttl == PASSIVE_RANDOM_WALK_LEN,
+    }
+}
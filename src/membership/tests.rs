@@ -0,0 +1,62 @@
+use super::*;
+
+fn peer_info(seed: u8) -> PeerInfo {
+    PeerInfo {
+        node_id: NodeId::from_bytes([seed; 32]),
+        addr: ([127, 0, 0, 1], 10000 + seed as u16).into(),
+    }
+}
+
+#[test]
+fn passive_view_test() {
+    let own = peer_info(0);
+    let mut view = PassiveView::new(3);
+    let active = |node_id: &NodeId| node_id.as_bytes()[0] == 9;
+    view.insert(own, &own.node_id, active);
+    view.insert(peer_info(9), &own.node_id, active);
+    assert!(view.peers.is_empty());
+
+    view.insert(peer_info(1), &own.node_id, active);
+    let mut moved = peer_info(1);
+    moved.addr.set_port(1);
+    view.insert(moved, &own.node_id, active);
+    assert_eq!(view.peers, [moved]);
+
+    // a full view forgets a random peer to make room
+    for seed in 2..=5 {
+        view.insert(peer_info(seed), &own.node_id, active);
+    }
+    assert_eq!(view.peers.len(), 3);
+    assert!(view.peers.contains(&peer_info(5)));
+
+    let sample = view.shuffle_sample(own, &[peer_info(6), peer_info(7), peer_info(8), peer_info(9)]);
+    assert_eq!(sample[0], own);
+    assert_eq!(sample.len(), 1 + SHUFFLE_ACTIVE_LEN + 3);
+
+    view.remove(&peer_info(5).node_id);
+    assert_eq!(view.peers.len(), 2);
+    let taken = [view.take_random().unwrap(), view.take_random().unwrap()];
+    assert!(!taken.contains(&peer_info(5)));
+    assert_eq!(view.take_random(), None);
+}
+
+#[test]
+fn forward_join_action_test() {
+    assert_eq!(forward_join_action(0, 5), ForwardJoinAction::Accept);
+    // the walk can not go anywhere but back
+    assert_eq!(forward_join_action(ACTIVE_RANDOM_WALK_LEN, 1), ForwardJoinAction::Accept);
+    assert_eq!(
+        forward_join_action(ACTIVE_RANDOM_WALK_LEN, 5),
+        ForwardJoinAction::Forward {
+            ttl: ACTIVE_RANDOM_WALK_LEN - 1,
+            passive: false
+        }
+    );
+    assert_eq!(
+        forward_join_action(PASSIVE_RANDOM_WALK_LEN, 5),
+        ForwardJoinAction::Forward {
+            ttl: PASSIVE_RANDOM_WALK_LEN - 1,
+            passive: true
+        }
+    );
+}
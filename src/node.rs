@@ -0,0 +1,1884 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use mio::Token;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as FmtWrite;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex};
+
+use rand::seq::{index, SliceRandom};
+
+use crate::address_book::{AddressBook, AddressSource, ADDRESS_BOOK_SAVE_TIME};
+use crate::anti_entropy::AntiEntropy;
+use crate::backoff::Backoff;
+use crate::connection::{Connection, SendQueueLimits};
+use crate::driver::{Driver, OpenedStream, CONNECT_TIMEOUT};
+use crate::identity::{Identity, NodeId};
+use crate::membership::{forward_join_action, ForwardJoinAction, PassiveView, ACTIVE_RANDOM_WALK_LEN};
+use crate::plumtree::Plumtree;
+use crate::reactor::Wakeup;
+use crate::reconnect::{Reconnect, Reconnector};
+use crate::protocol::{
+    self, DecodeLimits, Gossip, Hello, Message, MessageId, Nonce, PeerInfo,
+    CAPABILITY_ANTI_ENTROPY, CAPABILITY_PLUMTREE, MESSAGE_ID_COUNT_MAX, PROTOCOL_VERSION_MAX,
+    PROTOCOL_VERSION_MIN,
+};
+use crate::seen_cache::{BloomSeenCache, ExactSeenCache, SeenCache};
+use crate::{
+    DuplicateSuppression, Encryption, ForwardingStrategy, NodeEvent, NodeStats, SendQueuePolicy,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Every so often a random active peer is sent a `Shuffle`, to swap some of the peers both know
+/// about. This duration is how often that should be done.
+const SHUFFLE_TIME: Duration = Duration::from_millis(2000);
+
+/// While the active view has room, a random passive peer is connected to this often.
+const PROMOTE_PASSIVE_PEER_TIME: Duration = Duration::from_millis(200);
+
+/// A handshake has to be performed before two peers can be properly connected. This duration
+/// is the time allowed for that handshake to be performed. This handshake is the "confirming" of
+/// the connecting peer and the "authentication" of the accepted one.
+const PEER_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Each peer needs to keep track of the gossip they have already heard, by `MessageId`, in order
+/// to avoid double sending or gossip that keeps getting sent around in the network. This presents
+/// a problem because we cannot accumulate gossip endlessly or we will run out of memory. To avoid
+/// this memory leak, the already heard gossip has a decay time. Gossips are forgotten after this
+/// duration, see `SeenCache`.
+const ALREADY_HEARD_GOSSIP_DECAY_TIME: Duration = Duration::from_secs(50);
+
+/// The largest gossip payload a node accepts unless it is configured otherwise. Gossip packets
+/// are length prefixed and a packet announcing more than the maximum is treated as a protocol
+/// violation instead of being read.
+pub const DEFAULT_MAX_GOSSIP_LEN: usize = 64 * 1024;
+
+/// How many times a gossip may be relayed unless the node is configured otherwise. Each relay
+/// brings a gossip one peer further away from its origin, so this should be more than the
+/// diameter of the network.
+pub const DEFAULT_GOSSIP_TTL: u8 = 32;
+
+/// How long a node running anti-entropy holds on to gossip unless it is configured otherwise.
+/// Gossip missed for longer than this can not be recovered.
+pub const DEFAULT_ANTI_ENTROPY_RETENTION: Duration = Duration::from_secs(30);
+
+/// How many peers a node keeps connections to unless it is configured otherwise. Gossip only
+/// travels over these connections, so this should be enough for the network to stay connected
+/// when some of them fail, which is about the logarithm of the network size.
+pub const DEFAULT_ACTIVE_VIEW_SIZE: usize = 5;
+
+/// How many peers a node knows about, without being connected to them, unless it is configured
+/// otherwise. Failed connections are replaced with these.
+pub const DEFAULT_PASSIVE_VIEW_SIZE: usize = 30;
+
+/// The most connections other peers may open to a node unless it is configured otherwise.
+pub const DEFAULT_MAX_INBOUND: usize = 32;
+
+/// The most connections a node opens to other peers unless it is configured otherwise.
+pub const DEFAULT_MAX_OUTBOUND: usize = 16;
+
+/// The most connections a node has, in both directions, unless it is configured otherwise.
+pub const DEFAULT_MAX_TOTAL: usize = 40;
+
+/// How many times a node tries to reconnect to a peer whose connection failed unless it is
+/// configured otherwise.
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+
+/// How many bytes a node queues for a peer that does not keep up before its send queue counts as
+/// full, unless it is configured otherwise.
+pub const DEFAULT_SEND_QUEUE_HIGH_WATER_MARK: usize = 1024 * 1024;
+
+/// How long a peer's send queue may stay full with backpressure before the peer is dropped. A
+/// peer that stopped reading altogether would otherwise hold up the node for good.
+const SEND_QUEUE_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many other peers a node names in the `Busy` it turns connecting peers away with.
+const BUSY_PEERS_LEN: usize = 8;
+
+/// How many peers from its address book a node tries to join the network through when it can
+/// not reach any of its bootstrap peers. They are tried one after the other, and each one that is
+/// down may take a connection timeout.
+const ADDRESS_BOOK_BOOTSTRAP_ATTEMPTS: usize = 8;
+
+/// How long a node that could not join the network waits before trying again. The wait doubles
+/// with every failed attempt, see `Backoff`.
+const BOOTSTRAP_RETRY_INITIAL: Duration = Duration::from_millis(500);
+
+/// The longest a node that could not join the network waits before trying again.
+const BOOTSTRAP_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// The size of the gossip sent in the random gossip demo mode.
+const RANDOM_GOSSIP_LEN: usize = 10;
+
+/// Gossip is logged as hex. Longer gossips are cut short after this many bytes so that a big
+/// payload does not flood the console.
+const LOGGED_GOSSIP_LEN_MAX: usize = 16;
+
+/// Everything `do_peer` needs to know in order to run a node. It is filled in by
+/// `GossipNodeBuilder` and owned by the node for its whole lifetime.
+#[derive(Debug, Clone)]
+pub(crate) struct NodeConfig {
+    pub(crate) bind_addr: SocketAddr,
+    pub(crate) random_gossip_period: Option<Duration>,
+    pub(crate) bootstrap_peers: Vec<SocketAddr>,
+    pub(crate) lifetime: Option<Duration>,
+    pub(crate) max_gossip_len: usize,
+    pub(crate) gossip_ttl: u8,
+    pub(crate) identity: Identity,
+    pub(crate) encryption: Encryption,
+    pub(crate) duplicate_suppression: DuplicateSuppression,
+    pub(crate) forwarding_strategy: ForwardingStrategy,
+    pub(crate) anti_entropy_period: Option<Duration>,
+    pub(crate) anti_entropy_retention: Duration,
+    pub(crate) active_view_size: usize,
+    pub(crate) passive_view_size: usize,
+    pub(crate) max_inbound: usize,
+    pub(crate) max_outbound: usize,
+    pub(crate) max_total: usize,
+    pub(crate) reconnect_attempts: u32,
+    pub(crate) send_queue_high_water_mark: usize,
+    pub(crate) send_queue_policy: SendQueuePolicy,
+}
+
+impl NodeConfig {
+    /// The protocol capabilities the node claims in its handshakes. It only claims the optional
+    /// features it is configured to use.
+    fn capabilities(&self) -> u32 {
+        let mut capabilities = 0;
+        if self.forwarding_strategy == ForwardingStrategy::Plumtree {
+            capabilities |= CAPABILITY_PLUMTREE;
+        }
+        if self.anti_entropy_period.is_some() {
+            capabilities |= CAPABILITY_ANTI_ENTROPY;
+        }
+        capabilities
+    }
+
+    /// What the node accepts from its peers.
+    fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_gossip_len: self.max_gossip_len,
+        }
+    }
+
+    /// How much the node queues for a peer.
+    fn send_queue_limits(&self) -> SendQueueLimits {
+        SendQueueLimits {
+            high_water_mark: self.send_queue_high_water_mark,
+            policy: self.send_queue_policy,
+        }
+    }
+
+    /// Whether the node has room for another connection from a peer, next to `remote_peers` and
+    /// the connections in `dials` that are still opening.
+    fn room_to_accept(&self, remote_peers: &[Peer], dials: &[Dial]) -> bool {
+        let inbound = remote_peers.iter().filter(|peer| !peer.outbound).count();
+        inbound < self.max_inbound && remote_peers.len() + dials.len() < self.max_total
+    }
+
+    /// Whether the node has room for another connection to a peer, next to `remote_peers` and
+    /// the connections in `dials` that are still opening.
+    fn room_to_connect(&self, remote_peers: &[Peer], dials: &[Dial]) -> bool {
+        let outbound = remote_peers.iter().filter(|peer| peer.outbound).count() + dials.len();
+        outbound < self.max_outbound && remote_peers.len() + dials.len() < self.max_total
+    }
+}
+
+/// The requests a `GossipNode` handle can make of the node it controls. They are sent over a
+/// channel and picked up by `do_peer` once per loop iteration.
+#[derive(Debug)]
+pub(crate) enum NodeCommand {
+    /// Broadcast this gossip, already signed by the handle, to the network.
+    Publish(Gossip),
+    /// Send a snapshot of the node's state back over this channel.
+    Stats(Reply<NodeStats>),
+}
+
+/// The channel a handle gets something back from its node over. A `GossipNode` blocks on it, an
+/// `AsyncGossipNode` awaits it.
+#[derive(Debug)]
+pub(crate) enum Reply<T> {
+    Blocking(mpsc::Sender<T>),
+    #[cfg(feature = "async")]
+    Async(tokio::sync::oneshot::Sender<T>),
+}
+
+impl<T> Reply<T> {
+    /// Send `value` back. The handle may have given up on it, in which case it is dropped.
+    fn send(self, value: T) {
+        let _ = match self {
+            Reply::Blocking(sender) => sender.send(value).is_ok(),
+            #[cfg(feature = "async")]
+            Reply::Async(sender) => sender.send(value).is_ok(),
+        };
+    }
+}
+
+/// The channel a subscriber receives a node's events from, see `Reply`.
+#[derive(Debug)]
+pub(crate) enum Subscriber {
+    Blocking(mpsc::Sender<NodeEvent>),
+    #[cfg(feature = "async")]
+    Async(tokio::sync::mpsc::UnboundedSender<NodeEvent>),
+}
+
+impl Subscriber {
+    /// Hand `event` to the subscriber. Returns false if it has dropped its receiver.
+    fn send(&self, event: NodeEvent) -> bool {
+        match self {
+            Subscriber::Blocking(sender) => sender.send(event).is_ok(),
+            #[cfg(feature = "async")]
+            Subscriber::Async(sender) => sender.send(event).is_ok(),
+        }
+    }
+}
+
+/// Everyone who has subscribed to a node's events. It is `None` once the node has stopped, at
+/// which point there is nothing more to subscribe to.
+pub(crate) type EventSubscribers = Mutex<Option<Vec<Subscriber>>>;
+
+/// Hand `event` to every subscriber. Subscribers that have dropped their receiver are forgotten.
+fn emit_event(subscribers: &EventSubscribers, event: NodeEvent) {
+    if let Some(subscribers) = subscribers.lock().unwrap().as_mut() {
+        subscribers.retain(|subscriber| subscriber.send(event.clone()));
+    }
+}
+
+/// How far the handshake with a peer has come. The channel bindings are what the next handshake
+/// signature has to cover, see `protocol::confirm_signed_data`.
+#[derive(Debug)]
+enum Handshake {
+    /// We connected and sent a `Hello` carrying `nonce`. The `Confirm` has not arrived yet.
+    AwaitingConfirm { nonce: Nonce, channel_binding: Vec<u8> },
+    /// We accepted the connection and answered the `Hello` carrying `hello_nonce` with a
+    /// `Confirm` carrying `nonce`. The peer has not proven it owns its node id yet.
+    AwaitingAuth {
+        nonce: Nonce,
+        hello_nonce: Nonce,
+        channel_binding: Vec<u8>,
+    },
+    /// The peer is confirmed and authenticated.
+    Done,
+}
+
+/// This is the data structure that bundles a peer connection. The connection itself, the remote
+/// peer's listening address and node id, the handshake state, the connection instant and what was
+/// negotiated in the handshake. Until the peer is confirmed the version and capabilities are
+/// meaningless and the node id is only what we expect or what the peer claims. The packets we
+/// want to send the peer before then are held back in `pending_packets`.
+///
+/// In plumtree mode a peer is either eager, it gets sent gossip in full, or lazy, it only gets
+/// the ids of the gossip in `IHave`s. The ids of the gossip to ask it for are collected in
+/// `graft_ids` until they are sent.
+#[derive(Debug)]
+pub(crate) struct Peer {
+    connection: Connection,
+    addr: SocketAddr,
+    node_id: Option<NodeId>,
+    outbound: bool,
+
+    handshake: Handshake,
+    connect_instant : Instant,
+    pending_packets: Vec<Vec<u8>>,
+
+    version: u16,
+    capabilities: u32,
+
+    eager: bool,
+    graft_ids: Vec<MessageId>,
+}
+
+impl Peer {
+    fn new(
+        connection: Connection,
+        addr: SocketAddr,
+        node_id: Option<NodeId>,
+        outbound: bool,
+        handshake: Handshake,
+    ) -> Self {
+        Peer {
+            connection,
+            addr,
+            node_id,
+            outbound,
+            handshake,
+            connect_instant : Instant::now(),
+            pending_packets: Vec::new(),
+            version: 0,
+            capabilities: 0,
+            eager: true,
+            graft_ids: Vec::new(),
+        }
+    }
+
+    fn confirmed(&self) -> bool {
+        matches!(self.handshake, Handshake::Done)
+    }
+
+    /// Whether both sides of the connection run plumtree. Peers that do not are always eager.
+    fn plumtree(&self) -> bool {
+        self.capabilities & CAPABILITY_PLUMTREE != 0
+    }
+
+    /// Whether both sides of the connection run anti-entropy rounds.
+    fn anti_entropy(&self) -> bool {
+        self.capabilities & CAPABILITY_ANTI_ENTROPY != 0
+    }
+
+    /// Mark the handshake as done and send the packets that were held back while it was going on.
+    fn complete_handshake(&mut self) -> std::io::Result<()> {
+        self.handshake = Handshake::Done;
+        for packet in self.pending_packets.drain(..) {
+            self.connection.send_bytes(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+/// A connection the node has accepted, while it waits for the peer's `Hello`.
+#[derive(Debug)]
+struct AcceptedConnection {
+    connection: Connection,
+    remote_addr: SocketAddr,
+    accept_instant: Instant,
+}
+
+/// A connection the node is opening to a remote peer, until the driver has opened it or given
+/// up on it.
+#[derive(Debug)]
+struct Dial {
+    token: Token,
+    addr: SocketAddr,
+    /// The node id we expect to reach, if we know who we are connecting to.
+    node_id: Option<NodeId>,
+    /// What asks the peer to take us in, sent once the handshake is done.
+    introduction: Message,
+    dial_instant: Instant,
+}
+
+/// Start connecting to the remote peer at `addr` through the `driver`, which hands the
+/// connection out once it is open, see `connected_peer`. Returns `None` if the driver can not
+/// even start connecting.
+fn dial_peer(
+    addr: SocketAddr,
+    node_id: Option<NodeId>,
+    introduction: Message,
+    driver: &mut impl Driver,
+) -> Option<Dial> {
+    let token = driver.dial(&addr).ok()?;
+    Some(Dial {
+        token,
+        addr,
+        node_id,
+        introduction,
+        dial_instant: Instant::now(),
+    })
+}
+
+/// Take over the connection the driver `opened` for `dial`. It can fail and it therefore returns
+/// an option. If any error occurs the function simply aborts and no new peer connection is
+/// produced.
+///
+/// A `protocol::Hello` is sent, telling the remote peer what we can speak, who we are and our
+/// listening address. The remote peer's answer is handled by `do_peer`, which checks that it
+/// comes from the node id of the `dial` if we know who we are connecting to. Unless the config
+/// turns encryption off the connection is encrypted.
+fn connected_peer(
+    opened: OpenedStream,
+    dial: &Dial,
+    listener_addr: &SocketAddr,
+    config: &NodeConfig,
+) -> Option<Peer> {
+    let hello = Hello {
+        min_version: PROTOCOL_VERSION_MIN,
+        max_version: PROTOCOL_VERSION_MAX,
+        capabilities: config.capabilities(),
+        listen_addr: *listener_addr,
+        node_id: config.identity.node_id(),
+        nonce: rand::random(),
+    };
+    let encrypted = config.encryption != Encryption::Off;
+    let mut connection =
+        Connection::connected(
+            opened.stream,
+            opened.token,
+            config.decode_limits(),
+            config.send_queue_limits(),
+            encrypted,
+        )
+        .ok()?;
+    if connection.send_hello(&hello).is_err() {
+        return None;
+    }
+
+    let handshake = Handshake::AwaitingConfirm {
+        nonce: hello.nonce,
+        channel_binding: connection.channel_binding(),
+    };
+    let mut peer = Peer::new(connection, opened.remote_addr, dial.node_id, true, handshake);
+    peer.pending_packets.push(protocol::encode_to_vec(&dial.introduction));
+    Some(peer)
+}
+
+/// Answer the `hello` that arrived on an incomming connection. If the remote peer is not following
+/// protocol, return the reason the connection was not accepted. A peer we have no protocol version
+/// in common with is sent a `Reject` explaining that before the connection is closed.
+///
+/// The `Confirm` sent to an accepted peer proves we own our node id. The peer still has to prove
+/// the same in an `Auth`, which is handled by `do_peer`.
+///
+/// The connection is encrypted if the peer asks for it. A peer that does not is rejected if
+/// `encryption` is required.
+///
+/// If the node has no room for the connection, `busy_peers` holds the peers to suggest instead.
+/// The peer is then sent a `Busy` naming them in place of the `Confirm`.
+fn accept_connection(
+    mut connection: Connection,
+    hello: Hello,
+    identity: &Identity,
+    encryption: Encryption,
+    capabilities: u32,
+    busy_peers: Option<Vec<PeerInfo>>,
+) -> Result<Peer, String> {
+    let version = match protocol::negotiate_version(hello.min_version, hello.max_version) {
+        Some(version) => version,
+        None => {
+            let reason = format!(
+                "no common protocol version, peer speaks versions {} to {} and I speak {} to {}",
+                hello.min_version, hello.max_version, PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX
+            );
+            let _ = connection.send(&Message::Reject { reason: reason.clone() });
+            return Err(reason);
+        }
+    };
+
+    if encryption == Encryption::Required && !connection.is_encrypted() {
+        let reason = "encryption is required".to_string();
+        let _ = connection.send(&Message::Reject { reason: reason.clone() });
+        return Err(reason);
+    }
+
+    if hello.node_id == identity.node_id() {
+        let reason = "you have connected to yourself".to_string();
+        let _ = connection.send(&Message::Reject { reason: reason.clone() });
+        return Err(reason);
+    }
+
+    if let Some(peers) = busy_peers {
+        let _ = connection.send(&Message::Busy { peers });
+        return Err("too many connections".to_string());
+    }
+
+    let nonce: Nonce = rand::random();
+    let signed_data =
+        protocol::confirm_signed_data(&hello.nonce, &nonce, &connection.channel_binding());
+    let confirm = Message::Confirm {
+        version,
+        capabilities,
+        node_id: identity.node_id(),
+        nonce,
+        signature: identity.sign(&signed_data),
+    };
+    if let Err(error) = connection.send(&confirm) {
+        return Err(error.to_string());
+    }
+
+    let handshake = Handshake::AwaitingAuth {
+        nonce,
+        hello_nonce: hello.nonce,
+        channel_binding: connection.channel_binding(),
+    };
+    let mut peer = Peer::new(connection, hello.listen_addr, Some(hello.node_id), false, handshake);
+    peer.version = version;
+    peer.capabilities = hello.capabilities & capabilities;
+    Ok(peer)
+}
+
+/// Format gossip as hex for logging. Only the first `LOGGED_GOSSIP_LEN_MAX` bytes are shown.
+pub(crate) fn gossip_to_hex(gossip: &[u8]) -> String {
+    let mut s = String::with_capacity(2 * LOGGED_GOSSIP_LEN_MAX + 20);
+    s.push_str("0x");
+    for byte in gossip.iter().take(LOGGED_GOSSIP_LEN_MAX) {
+        write!(s, "{:02X}", byte).unwrap();
+    }
+    if gossip.len() > LOGGED_GOSSIP_LEN_MAX {
+        write!(s, ".. ({} bytes)", gossip.len()).unwrap();
+    }
+    s
+}
+
+/// Compare the confirmed peers in `remote_peers` with `connected_peers`, the confirmed peers
+/// from the last time this was called, and tell the subscribers about the differences. The peers
+/// that disconnected were last seen now, which is noted in the `address_book`. Returns the peers
+/// that disconnected.
+fn announce_peer_changes(
+    remote_peers: &[Peer],
+    connected_peers: &mut HashMap<NodeId, SocketAddr>,
+    subscribers: &EventSubscribers,
+    address_book: &mut AddressBook,
+    listener_addr: &SocketAddr,
+) -> Vec<PeerInfo> {
+    let mut disconnected_peers = Vec::new();
+    let mut still_connected_peers = HashMap::<NodeId, SocketAddr>::new();
+    for peer in remote_peers {
+        if let (true, Some(node_id)) = (peer.confirmed(), peer.node_id) {
+            still_connected_peers.insert(node_id, peer.addr);
+        }
+    }
+    for (node_id, addr) in connected_peers.iter() {
+        if !still_connected_peers.contains_key(node_id) {
+            println!("{}: Peer({}) has disconnected", listener_addr, addr);
+            address_book.disconnected(node_id, SystemTime::now());
+            disconnected_peers.push(PeerInfo {
+                node_id: *node_id,
+                addr: *addr,
+            });
+            emit_event(
+                subscribers,
+                NodeEvent::PeerDisconnected {
+                    node_id: *node_id,
+                    addr: *addr,
+                },
+            );
+        }
+    }
+    for (node_id, addr) in still_connected_peers.iter() {
+        if !connected_peers.contains_key(node_id) {
+            emit_event(
+                subscribers,
+                NodeEvent::PeerConnected {
+                    node_id: *node_id,
+                    addr: *addr,
+                },
+            );
+        }
+    }
+    *connected_peers = still_connected_peers;
+    disconnected_peers
+}
+
+/// Two peers can end up with two connections between them, for example when they learn about
+/// each other at the same time and both connect. Only one confirmed connection per node id is
+/// kept. Both sides have to agree on which one, so it is the one opened by the peer with the
+/// lower node id. If that leaves a choice, the newest connection wins since the older one is
+/// likely to be dead.
+fn drop_duplicate_connections(
+    remote_peers: Vec<Peer>,
+    own_node_id: &NodeId,
+    listener_addr: &SocketAddr,
+) -> Vec<Peer> {
+    let mut keep_peers = Vec::<Peer>::new();
+    let mut node_id_indices = HashMap::<NodeId, usize>::new();
+    for peer in remote_peers {
+        let node_id = match (peer.confirmed(), peer.node_id) {
+            (true, Some(node_id)) => node_id,
+            _ => {
+                keep_peers.push(peer);
+                continue;
+            }
+        };
+        match node_id_indices.get(&node_id) {
+            Some(&index) => {
+                let outbound_wins = *own_node_id < node_id;
+                if keep_peers[index].outbound == outbound_wins && peer.outbound != outbound_wins {
+                    println!(
+                        "{}: Closing duplicate connection to peer({})",
+                        listener_addr, peer.addr
+                    );
+                    continue;
+                }
+                println!(
+                    "{}: Closing duplicate connection to peer({})",
+                    listener_addr, keep_peers[index].addr
+                );
+                keep_peers[index] = peer;
+            }
+            None => {
+                node_id_indices.insert(node_id, keep_peers.len());
+                keep_peers.push(peer);
+            }
+        }
+    }
+    keep_peers
+}
+
+/// Keep the confirmed peers in `remote_peers`, the active view, within `active_view_size` by
+/// sending random ones a `Disconnect` and closing the connection. The peers in `protected_node_ids`
+/// have just been taken into the view and are not picked. Returns the peers that were closed, to
+/// be kept in the passive view.
+fn trim_active_view(
+    remote_peers: &mut Vec<Peer>,
+    active_view_size: usize,
+    protected_node_ids: &[NodeId],
+) -> Vec<PeerInfo> {
+    let mut evicted = Vec::new();
+    loop {
+        let active_len = remote_peers.iter().filter(|peer| peer.confirmed()).count();
+        let candidates: Vec<usize> = (0..remote_peers.len())
+            .filter(|&index| {
+                let peer = &remote_peers[index];
+                peer.confirmed() && !peer.node_id.is_some_and(|node_id| protected_node_ids.contains(&node_id))
+            })
+            .collect();
+        if active_len <= active_view_size || candidates.is_empty() {
+            return evicted;
+        }
+        let mut peer = remote_peers.remove(candidates[rand::random::<usize>() % candidates.len()]);
+        let _ = peer.connection.send(&Message::Disconnect);
+        evicted.push(PeerInfo {
+            node_id: peer.node_id.expect("confirmed peers have a node id"),
+            addr: peer.addr,
+        });
+    }
+}
+
+/// Pick the peers a gossip is sent to. `peer_node_ids` are the node ids of the peers, `from` that
+/// of the peer the gossip came from, `None` if the node publishes it itself. Returns whether to
+/// send it for every peer, in the same order.
+fn forwarding_targets(
+    strategy: ForwardingStrategy,
+    peer_node_ids: &[Option<NodeId>],
+    from: Option<NodeId>,
+) -> Vec<bool> {
+    let is_sender = |node_id: &Option<NodeId>| from.is_some() && *node_id == from;
+    match strategy {
+        ForwardingStrategy::Flood => vec![true; peer_node_ids.len()],
+        ForwardingStrategy::ExcludeSender | ForwardingStrategy::Plumtree => {
+            peer_node_ids.iter().map(|node_id| !is_sender(node_id)).collect()
+        }
+        ForwardingStrategy::RandomFanout(fanout) => {
+            let candidates: Vec<usize> = (0..peer_node_ids.len())
+                .filter(|&index| !is_sender(&peer_node_ids[index]))
+                .collect();
+            let mut targets = vec![false; peer_node_ids.len()];
+            let picked = index::sample(
+                &mut rand::thread_rng(),
+                candidates.len(),
+                fanout.min(candidates.len()),
+            );
+            for candidate in picked {
+                targets[candidates[candidate]] = true;
+            }
+            targets
+        }
+    }
+}
+
+/// What `start_peer` hands over to `do_peer`, the driver the node runs on, the attempt to join
+/// the network it started, if any, and its address book.
+#[derive(Debug)]
+pub(crate) struct StartedPeer<D> {
+    pub(crate) driver: D,
+    joining: Option<Joining>,
+    dials: Vec<Dial>,
+    address_book: AddressBook,
+}
+
+/// Joining the network through a peer that is asked to let the node join. The candidates are
+/// dialed one at a time, each one only once the one before it could not be reached.
+#[derive(Debug)]
+struct Joining {
+    /// The peers still to be tried, with their node ids if they are known.
+    candidates: VecDeque<(SocketAddr, Option<NodeId>)>,
+    /// The token of the dial to the candidate being tried.
+    token: Token,
+    /// Whether the candidate being tried is one of the bootstrap peers.
+    bootstrap_peer: bool,
+}
+
+impl Joining {
+    /// Start joining the network. The bootstrap peers in `config` are tried in random order, so
+    /// that nodes sharing a list of them do not all go to the first. If none of them can be
+    /// reached the most promising peers in the `address_book` are tried. Returns `None` if none
+    /// of them can be dialed at all.
+    fn start(
+        config: &NodeConfig,
+        address_book: &mut AddressBook,
+        listener_addr: &SocketAddr,
+        dials: &mut Vec<Dial>,
+        driver: &mut impl Driver,
+    ) -> Option<Joining> {
+        let mut bootstrap_peers = config.bootstrap_peers.clone();
+        bootstrap_peers.shuffle(&mut rand::thread_rng());
+        let mut candidates: VecDeque<(SocketAddr, Option<NodeId>)> = bootstrap_peers
+            .into_iter()
+            .map(|addr| (addr, None))
+            .chain(
+                address_book
+                    .candidates()
+                    .into_iter()
+                    .take(ADDRESS_BOOK_BOOTSTRAP_ATTEMPTS)
+                    .map(|candidate| (candidate.addr, Some(candidate.node_id))),
+            )
+            .collect();
+        let mut bootstrap_peer = false;
+        let dial = dial_candidate(
+            &mut candidates,
+            &mut bootstrap_peer,
+            address_book,
+            listener_addr,
+            driver,
+        )?;
+        let joining = Joining {
+            candidates,
+            token: dial.token,
+            bootstrap_peer,
+        };
+        dials.push(dial);
+        Some(joining)
+    }
+
+    /// Dial the next candidate, the one before it could not be reached. Returns false if there
+    /// are none left that can be dialed.
+    fn dial_next(
+        &mut self,
+        address_book: &mut AddressBook,
+        listener_addr: &SocketAddr,
+        dials: &mut Vec<Dial>,
+        driver: &mut impl Driver,
+    ) -> bool {
+        match dial_candidate(
+            &mut self.candidates,
+            &mut self.bootstrap_peer,
+            address_book,
+            listener_addr,
+            driver,
+        ) {
+            Some(dial) => {
+                self.token = dial.token;
+                dials.push(dial);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Dial the first of the `candidates` to join the network through that can be dialed at all.
+/// `bootstrap_peer` tells whether the one dialed before was a bootstrap peer, and is updated.
+fn dial_candidate(
+    candidates: &mut VecDeque<(SocketAddr, Option<NodeId>)>,
+    bootstrap_peer: &mut bool,
+    address_book: &mut AddressBook,
+    listener_addr: &SocketAddr,
+    driver: &mut impl Driver,
+) -> Option<Dial> {
+    while let Some((addr, node_id)) = candidates.pop_front() {
+        if node_id.is_some() && *bootstrap_peer {
+            println!(
+                "I({}) failed to connect to my bootstrap peers, trying my address book",
+                listener_addr
+            );
+        }
+        *bootstrap_peer = node_id.is_none();
+        match dial_peer(addr, node_id, Message::Join, driver) {
+            Some(dial) => return Some(dial),
+            None => {
+                if let Some(node_id) = node_id {
+                    address_book.failed(&node_id);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Start a first attempt at joining the network through the `driver`, which is already
+/// listening, see `Joining`. Not reaching anybody to join through is not an error, `do_peer`
+/// keeps trying.
+pub(crate) fn start_peer<D: Driver>(
+    config: &NodeConfig,
+    mut address_book: AddressBook,
+    mut driver: D,
+) -> StartedPeer<D> {
+    let listener_addr = driver.local_addr();
+    let mut dials = Vec::new();
+    let joining = Joining::start(
+        config,
+        &mut address_book,
+        &listener_addr,
+        &mut dials,
+        &mut driver,
+    );
+    if joining.is_none() && !config.bootstrap_peers.is_empty() {
+        println!(
+            "I({}) could not reach any of my bootstrap peers, I will keep trying",
+            listener_addr
+        );
+    }
+
+    StartedPeer {
+        driver,
+        joining,
+        dials,
+        address_book,
+    }
+}
+
+/// Perform the functionality of a peer in the p2p network.
+/// The function is goes through different phases in a loop once it has finished setup.
+///
+/// First it accepts the incomming connections and answers the `Hello`s that have arrived on
+/// them. A connection whose `Hello` does not arrive within `PEER_CONFIRMATION_TIMEOUT` is closed.
+/// The connections it dialed that have opened since are sent their `Hello`s.
+///
+/// The second phase is responding to incomming data packets. Every connection reads and decodes
+/// what arrived on it when the driver reports it ready, see `Connection`, and only 1 packet is
+/// handled per peer per loop to provide natural interleaving of the work to be done. The packets
+/// are the `protocol::Message`s. What is sent to a peer that does not keep up waits in its send
+/// queue, and once the queue is past its high-water mark the `SendQueuePolicy` decides what
+/// happens. With backpressure the other peers are not read from until the slow one has caught up.
+///
+/// The third phase keeps the active view, the confirmed peers, within its size. The messages for
+/// other peers that came up in the second phase are passed on and, if there are too many active
+/// peers, random ones are moved to the passive view. If there are too few, a random passive peer is
+/// promoted. Then the peers we have been made aware of in the second phase or are promoting are
+/// dialed, without waiting for the connections to open. A peer that can not be reached within
+/// `CONNECT_TIMEOUT` is simply forgotten about. Peers whose connection failed are connected to
+/// again, up to `reconnect_attempts` times and waiting longer after every attempt, see
+/// `Reconnector`. A node that is left without any peer, active or passive, tries to join the
+/// network again through its bootstrap peers, backing off exponentially while they are down.
+///
+/// In the final and fourth stage the function does most of it's sending. Gossips are broadcast and
+/// every `SHUFFLE_TIME` a random active peer is sent a `Shuffle`. The gossips broadcast are the
+/// fresh ones heard from peers, the ones published through `commands` and, if the config asks for
+/// it, a random one every `random_gossip_period`. The random ones take their sequence numbers from
+/// `next_seqno`, which is shared with the handle publishing through `commands`.
+/// In plumtree mode the lazy peers are only sent `IHave`s for the gossip, and the gossip that was
+/// announced by a peer but did not arrive in time is asked for with a `Graft`.
+/// With anti-entropy a `Digest` of the gossip the node holds goes to a random peer every
+/// `anti_entropy_period`, and the gossip missing from the digests of others is sent back to them.
+///
+/// At the end of every loop duplicate connections to the same node id are closed and the confirmed
+/// peers are compared with those of the previous comparison. The `subscribers` are told about the
+/// peers that have connected or disconnected. They are also told about every fresh gossip heard
+/// from a peer. Then the node sleeps in the driver until a connection is ready, the handle wakes
+/// it up because it sent a command or it is time for one of the timed phases above.
+///
+/// The peers the node hears of and the connections it makes or fails to make are noted in the
+/// `address_book`, which is written to disk every `ADDRESS_BOOK_SAVE_TIME`. The most promising
+/// peers in it make up the passive view the node starts out with.
+///
+/// The function does the above loop until `shutdown` is set or, if the config has one, the
+/// node's lifetime has run out. The driver, initial peers and address book come from
+/// `start_peer`. When the function returns it writes the address book one last time and drops
+/// all the subscribers so that they know the node has stopped.
+pub(crate) async fn do_peer<D: Driver>(
+    started: StartedPeer<D>,
+    config: &NodeConfig,
+    shutdown: &AtomicBool,
+    commands: mpsc::Receiver<NodeCommand>,
+    subscribers: &EventSubscribers,
+    next_seqno: &AtomicU64,
+) {
+    let StartedPeer {
+        mut driver,
+        mut joining,
+        mut dials,
+        mut address_book,
+    } = started;
+    let listener_addr = driver.local_addr();
+    println!("I'm doing peer({})!", listener_addr);
+
+    let node_id = config.identity.node_id();
+    println!("{}: My node id is {}", listener_addr, node_id);
+
+    let start_instant = Instant::now();
+
+    let mut plumtree = Plumtree::default();
+    let mut passive_view = PassiveView::new(config.passive_view_size);
+    for candidate in address_book.candidates().into_iter().take(config.passive_view_size) {
+        passive_view.insert(candidate, &node_id, |node_id| {
+            dials.iter().any(|dial| dial.node_id == Some(*node_id))
+        });
+    }
+    let mut last_address_book_save_instant = Instant::now();
+    let mut reconnector = Reconnector::new(config.reconnect_attempts);
+    let mut bootstrap_backoff = Backoff::new(BOOTSTRAP_RETRY_INITIAL, BOOTSTRAP_RETRY_MAX);
+    let mut next_bootstrap_instant = Instant::now();
+    if joining.is_none() {
+        next_bootstrap_instant += bootstrap_backoff.next_delay();
+    }
+    let mut last_shuffle_instant = Instant::now();
+    let mut last_promotion_instant = Instant::now();
+    let mut anti_entropy = AntiEntropy::new(config.anti_entropy_retention);
+    let mut already_heard_gossips: Box<dyn SeenCache + Send> = match config.duplicate_suppression {
+        DuplicateSuppression::Exact => Box::new(ExactSeenCache::new(ALREADY_HEARD_GOSSIP_DECAY_TIME)),
+        DuplicateSuppression::Bloom {
+            capacity,
+            false_positive_rate,
+        } => Box::new(BloomSeenCache::new(
+            ALREADY_HEARD_GOSSIP_DECAY_TIME,
+            capacity,
+            false_positive_rate,
+            Instant::now(),
+        )),
+    };
+    let mut last_self_gossip_instant = Instant::now();
+    let mut last_anti_entropy_instant = Instant::now();
+    let mut connected_peers = HashMap::<NodeId, SocketAddr>::new();
+    let mut remote_peers = Vec::<Peer>::new();
+    let mut accepted_connections = Vec::<AcceptedConnection>::new();
+    let mut ready_tokens = HashSet::<Token>::new();
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        if config.lifetime.is_some_and(|lifetime| start_instant.elapsed() > lifetime) {
+            break;
+        }
+
+        // take in all the connections that are waiting
+        for opened in driver.accept() {
+            accepted_connections.push(AcceptedConnection {
+                connection: Connection::accepted(
+                    opened.stream,
+                    opened.token,
+                    config.decode_limits(),
+                    config.send_queue_limits(),
+                ),
+                remote_addr: opened.remote_addr,
+                accept_instant: Instant::now(),
+            });
+        }
+
+        // take in the connections that have opened, a failed one to a peer we are joining the
+        // network through moves on to the next candidate
+        let mut join_failed = false;
+        for (token, result) in driver.dialed() {
+            let Some(index) = dials.iter().position(|dial| dial.token == token) else {
+                continue;
+            };
+            let dial = dials.remove(index);
+            let joining_through = joining.as_ref().is_some_and(|joining| joining.token == token);
+            let peer = match result {
+                Ok(opened) => connected_peer(opened, &dial, &listener_addr, config),
+                Err(error) => {
+                    println!(
+                        "{}: Failed to connect to peer({}): {}",
+                        listener_addr, dial.addr, error
+                    );
+                    None
+                }
+            };
+            match peer {
+                Some(peer) => {
+                    if joining_through {
+                        println!(
+                            "I({}) have connected to my initial peer, {}",
+                            listener_addr, peer.addr
+                        );
+                        joining = None;
+                        next_bootstrap_instant = Instant::now() + bootstrap_backoff.next_delay();
+                    }
+                    remote_peers.push(peer);
+                }
+                None => {
+                    if let Some(node_id) = dial.node_id {
+                        address_book.failed(&node_id);
+                    }
+                    if joining_through {
+                        let dialed_next = joining.as_mut().is_some_and(|joining| {
+                            joining.dial_next(
+                                &mut address_book,
+                                &listener_addr,
+                                &mut dials,
+                                &mut driver,
+                            )
+                        });
+                        if !dialed_next {
+                            joining = None;
+                            join_failed = true;
+                        }
+                    }
+                }
+            }
+        }
+        for connection in accepted_connections
+            .iter_mut()
+            .map(|accepted| &mut accepted.connection)
+            .chain(remote_peers.iter_mut().map(|peer| &mut peer.connection))
+        {
+            if ready_tokens.contains(&connection.token()) {
+                connection.ready();
+            }
+        }
+
+        // answer the connections whose `Hello` has arrived
+        let mut still_accepted_connections = Vec::<AcceptedConnection>::new();
+        for mut accepted in accepted_connections {
+            let hello = match accepted.connection.take_hello() {
+                Some(hello) => hello,
+                None => {
+                    if let Some(error) = accepted.connection.take_error() {
+                        println!(
+                            "{}: Rejected incomming connection from {}: {}",
+                            listener_addr, accepted.remote_addr, error
+                        );
+                    } else if accepted.accept_instant.elapsed() > PEER_CONFIRMATION_TIMEOUT {
+                        println!(
+                            "{}: Rejected incomming connection from {}: no hello in time",
+                            listener_addr, accepted.remote_addr
+                        );
+                    } else {
+                        still_accepted_connections.push(accepted);
+                    }
+                    continue;
+                }
+            };
+
+            // a peer we have no room for is pointed to the peers we know instead
+            let busy_peers = if config.room_to_accept(&remote_peers, &dials) {
+                None
+            } else {
+                let mut peers: Vec<PeerInfo> = remote_peers
+                    .iter()
+                    .filter(|peer| peer.confirmed())
+                    .filter_map(|peer| peer.node_id.map(|node_id| PeerInfo { node_id, addr: peer.addr }))
+                    .collect();
+                peers.extend(passive_view.sample(BUSY_PEERS_LEN));
+                peers.shuffle(&mut rand::thread_rng());
+                peers.truncate(BUSY_PEERS_LEN);
+                Some(peers)
+            };
+
+            match accept_connection(
+                accepted.connection,
+                hello,
+                &config.identity,
+                config.encryption,
+                config.capabilities(),
+                busy_peers,
+            ) {
+                Ok(peer) => {
+                    println!(
+                        "{}: New peer({}) has connected to me, speaking protocol version {}{}",
+                        listener_addr,
+                        peer.addr,
+                        peer.version,
+                        if peer.connection.is_encrypted() { ", encrypted" } else { "" }
+                    );
+                    remote_peers.push(peer);
+                }
+                Err(reason) => {
+                    println!(
+                        "{}: Rejected incomming connection from {}: {}",
+                        listener_addr, accepted.remote_addr, reason
+                    );
+                }
+            }
+        }
+        accepted_connections = still_accepted_connections;
+
+        // decay old gossip to save memory
+        already_heard_gossips.expire(Instant::now());
+        plumtree.expire(Instant::now());
+        anti_entropy.expire(Instant::now());
+
+        // the gossip to pass on, together with the peer it came from so that it is not sent back
+        let mut to_broadcast_gossip = Vec::<(Gossip, Option<NodeId>)>::new();
+
+        // the peers we are connected to, or about to be, and our active view
+        let mut known_node_ids = vec![node_id];
+        let mut confirmed_peers = Vec::<PeerInfo>::new();
+        known_node_ids.extend(dials.iter().filter_map(|dial| dial.node_id));
+        for peer in &remote_peers {
+            known_node_ids.extend(peer.node_id);
+            if let (true, Some(node_id)) = (peer.confirmed(), peer.node_id) {
+                confirmed_peers.push(PeerInfo {
+                    node_id,
+                    addr: peer.addr,
+                });
+            }
+        }
+        // the peers to connect to, each with the message that asks it to take us in
+        let mut new_peers = Vec::<(PeerInfo, Message)>::new();
+        // the messages for other peers than the one that is being read from
+        let mut outgoing_messages = Vec::<(NodeId, Message)>::new();
+        // the peers that have been taken into the active view and must not be evicted to make room
+        let mut protected_node_ids = Vec::<NodeId>::new();
+        // the peers that are let go on purpose and must not be reconnected to
+        let mut dismissed_node_ids = Vec::<NodeId>::new();
+
+        // with backpressure a peer that does not keep up holds back what comes in from the others
+        let backpressure = remote_peers.iter().any(|peer| peer.connection.congested_since().is_some());
+
+        let mut keep_peers = Vec::<Peer>::new();
+        for mut peer in remote_peers {
+
+            if peer.connect_instant.elapsed() > PEER_CONFIRMATION_TIMEOUT && !peer.confirmed()
+            {
+                if let (true, Some(remote_node_id)) = (peer.outbound, peer.node_id) {
+                    address_book.failed(&remote_node_id);
+                }
+                continue;
+            } // peer failed to confirm in time, dropping
+
+            if peer
+                .connection
+                .congested_since()
+                .is_some_and(|since| since.elapsed() > SEND_QUEUE_STALL_TIMEOUT)
+            {
+                println!(
+                    "{}: Peer({}) has not read what it was sent for too long, dropping it",
+                    listener_addr, peer.addr
+                );
+                continue;
+            } // it would hold up the node for good
+
+            if backpressure && peer.confirmed() && peer.connection.congested_since().is_none()
+            {
+                keep_peers.push(peer);
+                continue;
+            } // its messages wait until the slow peer has caught up
+
+            let message = match peer.connection.next_message() {
+                None => {
+                    keep_peers.push(peer);
+                    continue;
+                } // no activity, keep the peer
+                Some(Ok(message)) => message,
+                Some(Err(error)) => {
+                    if error.kind() == std::io::ErrorKind::InvalidData {
+                        eprintln!(
+                            "{}: Protocol violation by peer({}): {}",
+                            listener_addr, peer.addr, error
+                        );
+                        dismissed_node_ids.extend(peer.node_id);
+                    }
+                    continue;
+                } // read error or protocol violation, drop the peer
+            };
+
+            let is_handshake = matches!(
+                message,
+                Message::Confirm { .. }
+                    | Message::Reject { .. }
+                    | Message::Busy { .. }
+                    | Message::Auth { .. }
+            );
+            if !is_handshake && !peer.confirmed()
+            {
+                eprintln!(
+                    "{}: Protocol violation by peer({}): message before the handshake was done",
+                    listener_addr, peer.addr
+                );
+                continue;
+            }
+            if matches!(message, Message::Digest(_)) && !peer.anti_entropy()
+            {
+                eprintln!(
+                    "{}: Protocol violation by peer({}): digest without the anti-entropy capability",
+                    listener_addr, peer.addr
+                );
+                dismissed_node_ids.extend(peer.node_id);
+                continue;
+            }
+            let is_plumtree = matches!(
+                message,
+                Message::IHave { .. } | Message::Graft { .. } | Message::Prune
+            );
+            if is_plumtree && !peer.plumtree()
+            {
+                eprintln!(
+                    "{}: Protocol violation by peer({}): plumtree message without the capability",
+                    listener_addr, peer.addr
+                );
+                dismissed_node_ids.extend(peer.node_id);
+                continue;
+            }
+            match message {
+                Message::Gossip(mut gossip) =>
+                {
+                    if !already_heard_gossips.contains(&gossip.id())
+                    // new gossip
+                    {
+                        // only fresh gossip is checked, the copies of it that follow are dropped anyway
+                        if !gossip.verify()
+                        {
+                            eprintln!(
+                                "{}: Peer({}) relayed gossip with a bad signature, claiming to be from {}",
+                                listener_addr, peer.addr, gossip.origin
+                            );
+                            dismissed_node_ids.extend(peer.node_id);
+                            continue;
+                        } // forged or altered gossip, honest peers never relay it so drop the peer
+                        println!(
+                            "{}: Received fresh gossip, {}, from {}",
+                            listener_addr, gossip_to_hex(&gossip.payload), peer.addr
+                        );
+                        already_heard_gossips.insert(gossip.id(), Instant::now());
+                        // we tag the instant so that we can purge very old gossips later
+
+                        emit_event(
+                            subscribers,
+                            NodeEvent::GossipReceived {
+                                payload: gossip.payload.clone(),
+                                id: gossip.id(),
+                                from: peer.node_id.expect("confirmed peers have a node id"),
+                                hops: gossip.hops,
+                            },
+                        );
+                        // in plumtree mode the peer is on the shortest path from the origin
+                        plumtree.received(&gossip.id());
+                        peer.eager = true;
+                        if gossip.ttl > 0 {
+                            gossip.ttl -= 1;
+                            gossip.hops = gossip.hops.saturating_add(1);
+                            to_broadcast_gossip.push((gossip, peer.node_id));
+                        } else if config.anti_entropy_period.is_some() {
+                            // its time to live has run out, it is not passed on but our digests
+                            // have to show that we have it
+                            anti_entropy.hold(gossip.id(), None, Instant::now());
+                        }
+                    }
+                    else if peer.plumtree() && peer.eager
+                    // a copy, the gossip also reaches us another way so the peer can stop pushing it
+                    {
+                        peer.eager = false;
+                        if peer.connection.send(&Message::Prune).is_err() {
+                            continue;
+                        }
+                    }
+                }
+                Message::IHave { ids } =>
+                {
+                    let announcer = peer.node_id.expect("confirmed peers have a node id");
+                    for id in ids {
+                        if !already_heard_gossips.contains(&id) {
+                            plumtree.announced(id, announcer, Instant::now());
+                        }
+                    }
+                }
+                Message::Graft { ids } =>
+                {
+                    peer.eager = true;
+                    let grafted: Vec<Message> = ids
+                        .iter()
+                        .filter_map(|id| plumtree.cached(id))
+                        .map(|gossip| Message::Gossip(gossip.clone()))
+                        .collect();
+                    if grafted
+                        .iter()
+                        .any(|message| peer.connection.send(message).is_err())
+                    {
+                        continue;
+                    }
+                }
+                Message::Prune => peer.eager = false,
+                Message::Digest(digest) =>
+                {
+                    let missing = anti_entropy.missing(&digest, Instant::now());
+                    if !missing.is_empty() {
+                        println!(
+                            "{}: Sending peer({}) {} gossips missing from its digest",
+                            listener_addr, peer.addr, missing.len()
+                        );
+                    }
+                    if missing
+                        .into_iter()
+                        .any(|gossip| peer.connection.send(&Message::Gossip(gossip)).is_err())
+                    {
+                        continue;
+                    }
+                }
+                Message::Join =>
+                {
+                    let joining_peer = PeerInfo {
+                        node_id: peer.node_id.expect("confirmed peers have a node id"),
+                        addr: peer.addr,
+                    };
+                    println!("{}: Peer({}) is joining the network through me", listener_addr, peer.addr);
+                    protected_node_ids.push(joining_peer.node_id);
+                    for active_peer in &confirmed_peers {
+                        if active_peer.node_id != joining_peer.node_id {
+                            let forward_join = Message::ForwardJoin {
+                                peer: joining_peer,
+                                ttl: ACTIVE_RANDOM_WALK_LEN,
+                            };
+                            outgoing_messages.push((active_peer.node_id, forward_join));
+                        }
+                    }
+                }
+                Message::ForwardJoin { peer: joining_peer, ttl } =>
+                {
+                    let sender = peer.node_id.expect("confirmed peers have a node id");
+                    address_book.learned(joining_peer, SystemTime::now());
+                    let next_hop = match forward_join_action(ttl, confirmed_peers.len()) {
+                        ForwardJoinAction::Accept => None,
+                        ForwardJoinAction::Forward { ttl, passive } => {
+                            if passive {
+                                passive_view.insert(joining_peer, &node_id, |node_id| {
+                                    known_node_ids.contains(node_id)
+                                });
+                            }
+                            confirmed_peers
+                                .iter()
+                                .filter(|info| info.node_id != sender && info.node_id != joining_peer.node_id)
+                                .collect::<Vec<&PeerInfo>>()
+                                .choose(&mut rand::thread_rng())
+                                .map(|info| (info.node_id, ttl))
+                        }
+                    };
+                    match next_hop {
+                        Some((next_node_id, ttl)) => {
+                            outgoing_messages.push((next_node_id, Message::ForwardJoin { peer: joining_peer, ttl }));
+                        }
+                        // the walk ends here, unless we already know the joining peer or are it
+                        None if !known_node_ids.contains(&joining_peer.node_id) => {
+                            known_node_ids.push(joining_peer.node_id);
+                            passive_view.remove(&joining_peer.node_id);
+                            new_peers.push((joining_peer, Message::Neighbor { high_priority: true }));
+                        }
+                        None => {}
+                    }
+                }
+                Message::Neighbor { high_priority } =>
+                {
+                    let remote_node_id = peer.node_id.expect("confirmed peers have a node id");
+                    let other_active_peers = confirmed_peers
+                        .iter()
+                        .filter(|info| info.node_id != remote_node_id)
+                        .count();
+                    if !high_priority && other_active_peers >= config.active_view_size
+                    {
+                        println!("{}: No room for peer({}) in my active view", listener_addr, peer.addr);
+                        let _ = peer.connection.send(&Message::Disconnect);
+                        let info = PeerInfo { node_id: remote_node_id, addr: peer.addr };
+                        passive_view.insert(info, &node_id, |_| false);
+                        dismissed_node_ids.push(remote_node_id);
+                        continue;
+                    } // turned away, drop the peer
+                    protected_node_ids.push(remote_node_id);
+                }
+                Message::Disconnect =>
+                {
+                    println!("{}: Peer({}) has moved me to its passive view", listener_addr, peer.addr);
+                    let info = PeerInfo {
+                        node_id: peer.node_id.expect("confirmed peers have a node id"),
+                        addr: peer.addr,
+                    };
+                    passive_view.insert(info, &node_id, |_| false);
+                    dismissed_node_ids.push(info.node_id);
+                    continue;
+                }
+                Message::Shuffle { peers } =>
+                {
+                    let reply = Message::ShuffleReply {
+                        peers: passive_view.sample(peers.len()),
+                    };
+                    for info in peers {
+                        address_book.learned(info, SystemTime::now());
+                        passive_view.insert(info, &node_id, |node_id| known_node_ids.contains(node_id));
+                    }
+                    if peer.connection.send(&reply).is_err() {
+                        continue;
+                    }
+                }
+                Message::ShuffleReply { peers } =>
+                {
+                    for info in peers {
+                        address_book.learned(info, SystemTime::now());
+                        passive_view.insert(info, &node_id, |node_id| known_node_ids.contains(node_id));
+                    }
+                }
+                Message::Confirm { version, capabilities, node_id: remote_node_id, nonce, signature } =>
+                {
+                    let (hello_nonce, channel_binding) = match &peer.handshake {
+                        Handshake::AwaitingConfirm { nonce, channel_binding } => (*nonce, channel_binding.clone()),
+                        _ => {
+                            eprintln!(
+                                "{}: Protocol violation by peer({}): confirm that was not asked for",
+                                listener_addr, peer.addr
+                            );
+                            continue;
+                        }
+                    };
+                    if !(PROTOCOL_VERSION_MIN..=PROTOCOL_VERSION_MAX).contains(&version)
+                    {
+                        eprintln!(
+                            "{}: Peer({}) confirmed protocol version {}, which was not offered",
+                            listener_addr, peer.addr, version
+                        );
+                        continue;
+                    } // protocol violation, drop the peer
+                    if !remote_node_id.verify(&protocol::confirm_signed_data(&hello_nonce, &nonce, &channel_binding), &signature)
+                    {
+                        eprintln!(
+                            "{}: Peer({}) failed to prove it owns node id {}",
+                            listener_addr, peer.addr, remote_node_id
+                        );
+                        continue;
+                    }
+                    if peer.node_id.is_some_and(|expected| expected != remote_node_id)
+                    {
+                        eprintln!(
+                            "{}: Peer({}) is node {} instead of the expected {}",
+                            listener_addr, peer.addr, remote_node_id, peer.node_id.unwrap()
+                        );
+                        continue;
+                    }
+                    if remote_node_id == node_id
+                    {
+                        println!("{}: Peer({}) turned out to be myself", listener_addr, peer.addr);
+                        continue;
+                    }
+                    let signed_data = protocol::auth_signed_data(&hello_nonce, &nonce, &peer.connection.channel_binding());
+                    let auth = Message::Auth {
+                        signature: config.identity.sign(&signed_data),
+                    };
+                    if peer.connection.send(&auth).is_err() {
+                        continue;
+                    }
+                    // a peer we connected to without knowing who it is can only be a bootstrap peer
+                    let source = if peer.node_id.is_some() { AddressSource::Peer } else { AddressSource::Seed };
+                    address_book.connected(PeerInfo { node_id: remote_node_id, addr: peer.addr }, source, SystemTime::now());
+                    peer.node_id = Some(remote_node_id);
+                    peer.version = version;
+                    peer.capabilities = capabilities & config.capabilities();
+                    if peer.complete_handshake().is_err() {
+                        continue;
+                    }
+                }
+                Message::Auth { signature } =>
+                {
+                    let signed_data = match &peer.handshake {
+                        Handshake::AwaitingAuth { nonce, hello_nonce, channel_binding } =>
+                            protocol::auth_signed_data(hello_nonce, nonce, channel_binding),
+                        _ => {
+                            eprintln!(
+                                "{}: Protocol violation by peer({}): auth that was not asked for",
+                                listener_addr, peer.addr
+                            );
+                            continue;
+                        }
+                    };
+                    let remote_node_id = peer.node_id.expect("accepted peers have a node id");
+                    if !remote_node_id.verify(&signed_data, &signature)
+                    {
+                        eprintln!(
+                            "{}: Peer({}) failed to prove it owns node id {}",
+                            listener_addr, peer.addr, remote_node_id
+                        );
+                        continue;
+                    }
+                    println!(
+                        "{}: Peer({}) has proven it is node {}",
+                        listener_addr, peer.addr, remote_node_id
+                    );
+                    address_book.connected(PeerInfo { node_id: remote_node_id, addr: peer.addr }, AddressSource::Inbound, SystemTime::now());
+                    if peer.complete_handshake().is_err() {
+                        continue;
+                    }
+                }
+                Message::Busy { peers } =>
+                {
+                    if !matches!(peer.handshake, Handshake::AwaitingConfirm { .. })
+                    {
+                        eprintln!(
+                            "{}: Protocol violation by peer({}): busy reply that was not asked for",
+                            listener_addr, peer.addr
+                        );
+                        continue;
+                    }
+                    println!(
+                        "{}: Peer({}) is busy and suggested {} other peers",
+                        listener_addr, peer.addr, peers.len()
+                    );
+                    for info in peers {
+                        address_book.learned(info, SystemTime::now());
+                        passive_view.insert(info, &node_id, |node_id| known_node_ids.contains(node_id));
+                    }
+                    continue;
+                }
+                Message::Reject { reason } =>
+                {
+                    eprintln!(
+                        "{}: Peer({}) rejected the connection: {}",
+                        listener_addr, peer.addr, reason
+                    );
+                    continue;
+                }
+            }
+            // done, now we can keep the peer
+            keep_peers.push(peer);
+        }
+        remote_peers = keep_peers;
+
+        for (target, message) in outgoing_messages {
+            if let Some(index) = remote_peers
+                .iter()
+                .position(|peer| peer.confirmed() && peer.node_id == Some(target))
+            {
+                if remote_peers[index].connection.send(&message).is_err() {
+                    remote_peers.remove(index);
+                } // on error drop peer
+            }
+        }
+
+        for evicted in trim_active_view(&mut remote_peers, config.active_view_size, &protected_node_ids) {
+            println!("{}: Moving peer({}) to my passive view", listener_addr, evicted.addr);
+            dismissed_node_ids.push(evicted.node_id);
+            passive_view.insert(evicted, &node_id, |_| false);
+        }
+
+        // connect to the peers whose connection failed again, unless they are already back
+        let due_reconnects = reconnector.due(
+            Instant::now(),
+            |node_id| connected_peers.contains_key(node_id),
+            |node_id| {
+                remote_peers.iter().any(|peer| peer.node_id == Some(*node_id))
+                    || dials.iter().any(|dial| dial.node_id == Some(*node_id))
+            },
+        );
+        for reconnect in due_reconnects {
+            match reconnect {
+                Reconnect::Attempt { peer, attempt } => {
+                    println!(
+                        "{}: Reconnecting to peer({}), attempt {} of {}",
+                        listener_addr, peer.addr, attempt, config.reconnect_attempts
+                    );
+                    emit_event(
+                        subscribers,
+                        NodeEvent::ReconnectAttempt {
+                            node_id: peer.node_id,
+                            addr: peer.addr,
+                            attempt,
+                        },
+                    );
+                    passive_view.remove(&peer.node_id);
+                    let high_priority = !remote_peers.iter().any(|peer| peer.confirmed());
+                    new_peers.push((peer, Message::Neighbor { high_priority }));
+                }
+                Reconnect::GiveUp { peer, attempts } => {
+                    println!(
+                        "{}: Giving up on peer({}) after {} attempts to reconnect",
+                        listener_addr, peer.addr, attempts
+                    );
+                    emit_event(
+                        subscribers,
+                        NodeEvent::ReconnectGaveUp {
+                            node_id: peer.node_id,
+                            addr: peer.addr,
+                            attempts,
+                        },
+                    );
+                }
+            }
+        }
+
+        // fill the active view up from the passive view, one peer at a time
+        if remote_peers.len() + dials.len() + new_peers.len() < config.active_view_size
+            && config.room_to_connect(&remote_peers, &dials)
+            && last_promotion_instant.elapsed() > PROMOTE_PASSIVE_PEER_TIME
+        {
+            last_promotion_instant = Instant::now();
+            if let Some(promoted) = passive_view.take_random() {
+                let high_priority = !remote_peers.iter().any(|peer| peer.confirmed());
+                new_peers.push((promoted, Message::Neighbor { high_priority }));
+            }
+        }
+
+        // with nobody left to talk to, or nobody found in the first place, join the network anew
+        if remote_peers.is_empty()
+            && dials.is_empty()
+            && new_peers.is_empty()
+            && passive_view.is_empty()
+            && !join_failed
+            && Instant::now() >= next_bootstrap_instant
+        {
+            joining = Joining::start(
+                config,
+                &mut address_book,
+                &listener_addr,
+                &mut dials,
+                &mut driver,
+            );
+            join_failed = joining.is_none();
+        }
+        if join_failed {
+            let delay = bootstrap_backoff.next_delay();
+            next_bootstrap_instant = Instant::now() + delay;
+            if !config.bootstrap_peers.is_empty() {
+                println!(
+                    "{}: Failed to join the network {} times in a row, trying again in {:?}",
+                    listener_addr, bootstrap_backoff.failures(), delay
+                );
+            }
+        }
+        if remote_peers.iter().any(|peer| peer.confirmed()) {
+            bootstrap_backoff.reset();
+        }
+
+        for (new_peer, introduction) in new_peers
+        {
+            if !config.room_to_connect(&remote_peers, &dials)
+            {
+                println!("{}: Too many connections to connect to peer({})", listener_addr, new_peer.addr);
+                passive_view.insert(new_peer, &node_id, |_| false);
+                continue;
+            } // keep it for when there is room
+            match dial_peer(new_peer.addr, Some(new_peer.node_id), introduction, &mut driver) {
+                Some(dial) => dials.push(dial),
+                None => address_book.failed(&new_peer.node_id),
+            }
+        }
+
+        // gossip that the application wants to publish
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                NodeCommand::Publish(gossip) => {
+                    already_heard_gossips.insert(gossip.id(), Instant::now());
+                    println!(
+                        "{}: Publishing gossip {} to the peers my forwarding strategy picks, {}",
+                        listener_addr, gossip.seqno, gossip_to_hex(&gossip.payload)
+                    );
+                    to_broadcast_gossip.push((gossip, None));
+                }
+                NodeCommand::Stats(reply) => {
+                    let stats = NodeStats {
+                        connected_peers: remote_peers
+                            .iter()
+                            .filter(|peer| peer.confirmed())
+                            .filter_map(|peer| peer.node_id.map(|node_id| PeerInfo { node_id, addr: peer.addr }))
+                            .collect(),
+                        passive_peers: passive_view.len(),
+                        reconnecting_peers: reconnector.reconnecting_peers(Instant::now()),
+                    };
+                    reply.send(stats);
+                }
+            }
+        }
+
+        // ask for the announced gossip that has not arrived in time, which also repairs the tree
+        let due_grafts = plumtree.due_grafts(Instant::now(), |node_id| {
+            remote_peers
+                .iter()
+                .any(|peer| peer.confirmed() && peer.node_id == Some(*node_id))
+        });
+        for (announcer, id) in due_grafts {
+            if let Some(peer) = remote_peers
+                .iter_mut()
+                .find(|peer| peer.confirmed() && peer.node_id == Some(announcer))
+            {
+                println!("{}: Grafting gossip {} from peer({})", listener_addr, id, peer.addr);
+                peer.eager = true;
+                peer.graft_ids.push(id);
+            }
+        }
+
+        // if we should gossip, send some random gossip
+        if config
+            .random_gossip_period
+            .is_some_and(|period| last_self_gossip_instant.elapsed() > period)
+        {
+            let gossip_buf: Vec<u8> = (0..RANDOM_GOSSIP_LEN).map(|_| rand::random()).collect();
+            let seqno = next_seqno.fetch_add(1, Ordering::Relaxed);
+            let gossip = Gossip::sign(&config.identity, seqno, config.gossip_ttl, gossip_buf);
+            already_heard_gossips.insert(gossip.id(), Instant::now()); // we already know about our own gossip
+            last_self_gossip_instant = Instant::now();
+
+            println!(
+                "{}: Publishing random gossip to the peers my forwarding strategy picks, {}",
+                listener_addr, gossip_to_hex(&gossip.payload)
+            );
+            to_broadcast_gossip.push((gossip, None));
+        }
+
+        // every peer gets the same bytes, so the gossips are only encoded once
+        let peer_node_ids: Vec<Option<NodeId>> = remote_peers.iter().map(|peer| peer.node_id).collect();
+        let mut gossip_packets = Vec::<(MessageId, Vec<u8>, Vec<bool>)>::new();
+        for (gossip, from) in to_broadcast_gossip.drain(..) {
+            if config.forwarding_strategy == ForwardingStrategy::Plumtree {
+                plumtree.cache(&gossip, Instant::now());
+            }
+            if config.anti_entropy_period.is_some() {
+                anti_entropy.hold(gossip.id(), Some(&gossip), Instant::now());
+            }
+            let targets = forwarding_targets(config.forwarding_strategy, &peer_node_ids, from);
+            let gossip_packet = protocol::encode_to_vec(&Message::Gossip(gossip.clone()));
+            gossip_packets.push((gossip.id(), gossip_packet, targets));
+        }
+
+        let mut keep_peers = Vec::<Peer>::new(); // we do the same thing again but now with gossip sending business
+        'peer_loop: for (peer_index, mut peer) in remote_peers.into_iter().enumerate() {
+            // lazy peers only get to know the ids, unconfirmed peers are never lazy
+            let lazy = peer.plumtree() && !peer.eager;
+            let peer_packets = gossip_packets
+                .iter()
+                .filter(|(_, _, targets)| targets[peer_index] && !lazy)
+                .map(|(_, gossip_packet, _)| gossip_packet);
+            if !peer.confirmed() {
+                peer.pending_packets.extend(peer_packets.cloned());
+                keep_peers.push(peer);
+                continue;
+            } // the gossip is sent once the handshake is done
+            // send gossips
+            for gossip_packet in peer_packets {
+                if peer.connection.send_bytes(gossip_packet).is_err()
+                // if we fail, drop the peer
+                {
+                    continue 'peer_loop;
+                }
+            }
+            // send plumtree announcements and grafts
+            let ihave_ids: Vec<MessageId> = gossip_packets
+                .iter()
+                .filter(|(_, _, targets)| targets[peer_index] && lazy)
+                .map(|(id, _, _)| *id)
+                .collect();
+            let graft_ids = std::mem::take(&mut peer.graft_ids);
+            let control_messages = ihave_ids
+                .chunks(MESSAGE_ID_COUNT_MAX as usize)
+                .map(|ids| Message::IHave { ids: ids.to_vec() })
+                .chain(
+                    graft_ids
+                        .chunks(MESSAGE_ID_COUNT_MAX as usize)
+                        .map(|ids| Message::Graft { ids: ids.to_vec() }),
+                );
+            for message in control_messages {
+                if peer.connection.send(&message).is_err() {
+                    continue 'peer_loop;
+                }
+            }
+            // done, now we can keep the peer
+            keep_peers.push(peer);
+        }
+        remote_peers = keep_peers;
+
+        // swap some of the peers we know about with a random active peer
+        if last_shuffle_instant.elapsed() > SHUFFLE_TIME {
+            last_shuffle_instant = Instant::now();
+            let active_peers: Vec<PeerInfo> = remote_peers
+                .iter()
+                .filter(|peer| peer.confirmed())
+                .filter_map(|peer| peer.node_id.map(|node_id| PeerInfo { node_id, addr: peer.addr }))
+                .collect();
+            if let Some(target) = active_peers.choose(&mut rand::thread_rng()) {
+                let others: Vec<PeerInfo> = active_peers
+                    .iter()
+                    .filter(|info| info.node_id != target.node_id)
+                    .copied()
+                    .collect();
+                let own = PeerInfo {
+                    node_id,
+                    addr: listener_addr,
+                };
+                let shuffle = Message::Shuffle {
+                    peers: passive_view.shuffle_sample(own, &others),
+                };
+                if let Some(index) = remote_peers
+                    .iter()
+                    .position(|peer| peer.confirmed() && peer.node_id == Some(target.node_id))
+                {
+                    if remote_peers[index].connection.send(&shuffle).is_err() {
+                        remote_peers.remove(index);
+                    } // on error drop peer
+                }
+            }
+        }
+
+        // send a digest of the gossip we hold to a random peer, which sends back what we missed
+        if config
+            .anti_entropy_period
+            .is_some_and(|period| last_anti_entropy_instant.elapsed() > period)
+        {
+            last_anti_entropy_instant = Instant::now();
+            let candidates: Vec<usize> = (0..remote_peers.len())
+                .filter(|&index| remote_peers[index].confirmed() && remote_peers[index].anti_entropy())
+                .collect();
+            if !candidates.is_empty() {
+                let index = candidates[rand::random::<usize>() % candidates.len()];
+                let digest = Message::Digest(anti_entropy.digest());
+                if remote_peers[index].connection.send(&digest).is_err() {
+                    remote_peers.remove(index);
+                } // on error drop peer
+            }
+        }
+
+        remote_peers = drop_duplicate_connections(remote_peers, &node_id, &listener_addr);
+        let disconnected_peers =
+            announce_peer_changes(&remote_peers, &mut connected_peers, subscribers, &mut address_book, &listener_addr);
+        for peer in disconnected_peers {
+            if !dismissed_node_ids.contains(&peer.node_id) {
+                reconnector.lost(peer, Instant::now());
+            }
+        }
+        for dismissed_node_id in &dismissed_node_ids {
+            reconnector.forget(dismissed_node_id);
+        }
+
+        if last_address_book_save_instant.elapsed() > ADDRESS_BOOK_SAVE_TIME {
+            last_address_book_save_instant = Instant::now();
+            if let Err(error) = address_book.save() {
+                eprintln!("{}: Failed to save my address book: {}", listener_addr, error);
+            }
+        }
+
+        // sleep until there is something to do
+        let mut wakeup = Wakeup::default();
+        if let Some(lifetime) = config.lifetime {
+            wakeup.at(start_instant + lifetime);
+        }
+        for accepted in &accepted_connections {
+            wakeup.at(accepted.accept_instant + PEER_CONFIRMATION_TIMEOUT);
+        }
+        for dial in &dials {
+            wakeup.at(dial.dial_instant + CONNECT_TIMEOUT);
+        }
+        let backpressure = remote_peers.iter().any(|peer| peer.connection.congested_since().is_some());
+        for peer in &remote_peers {
+            let held_back =
+                backpressure && peer.confirmed() && peer.connection.congested_since().is_none();
+            if peer.connection.has_incoming() && !held_back {
+                wakeup.now();
+            }
+            if let Some(since) = peer.connection.congested_since() {
+                wakeup.at(since + SEND_QUEUE_STALL_TIMEOUT);
+            }
+            if !peer.confirmed() {
+                wakeup.at(peer.connect_instant + PEER_CONFIRMATION_TIMEOUT);
+            }
+        }
+        if remote_peers.len() + dials.len() < config.active_view_size
+            && config.room_to_connect(&remote_peers, &dials)
+            && !passive_view.is_empty()
+        {
+            wakeup.at(last_promotion_instant + PROMOTE_PASSIVE_PEER_TIME);
+        }
+        if remote_peers.is_empty() && dials.is_empty() && passive_view.is_empty() {
+            wakeup.at(next_bootstrap_instant);
+        }
+        let reconnecting = reconnector.next_attempt(|node_id| {
+            remote_peers.iter().any(|peer| peer.node_id == Some(*node_id))
+                || dials.iter().any(|dial| dial.node_id == Some(*node_id))
+        });
+        if let Some(instant) = reconnecting {
+            wakeup.at(instant);
+        }
+        if let Some(instant) = plumtree.next_graft() {
+            wakeup.at(instant);
+        }
+        if remote_peers.iter().any(|peer| peer.confirmed()) {
+            wakeup.at(last_shuffle_instant + SHUFFLE_TIME);
+        }
+        if let Some(period) = config.random_gossip_period {
+            wakeup.at(last_self_gossip_instant + period);
+        }
+        if let Some(period) = config.anti_entropy_period {
+            wakeup.at(last_anti_entropy_instant + period);
+        }
+        wakeup.at(last_address_book_save_instant + ADDRESS_BOOK_SAVE_TIME);
+        ready_tokens = driver.wait(&wakeup).await.expect("failed to wait for the sockets");
+    }
+
+    if let Err(error) = address_book.save() {
+        eprintln!("{}: Failed to save my address book: {}", listener_addr, error);
+    }
+    *subscribers.lock().unwrap() = None;
+}
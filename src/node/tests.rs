@@ -0,0 +1,41 @@
+use super::*;
+
+fn node_id(seed: u8) -> Option<NodeId> {
+    Some(NodeId::from_bytes([seed; 32]))
+}
+
+#[test]
+fn forwarding_targets_test() {
+    // the sender is connected twice, which both connections have to be spared from
+    let peers = [node_id(1), node_id(2), None, node_id(3), node_id(2)];
+    let sender = node_id(2);
+
+    assert_eq!(
+        forwarding_targets(ForwardingStrategy::Flood, &peers, sender),
+        [true; 5]
+    );
+    assert_eq!(
+        forwarding_targets(ForwardingStrategy::ExcludeSender, &peers, sender),
+        [true, false, true, true, false]
+    );
+    // the node's own gossip goes everywhere
+    assert_eq!(
+        forwarding_targets(ForwardingStrategy::ExcludeSender, &peers, None),
+        [true; 5]
+    );
+
+    for _ in 0..100 {
+        let targets = forwarding_targets(ForwardingStrategy::RandomFanout(2), &peers, sender);
+        assert_eq!(targets.iter().filter(|&&target| target).count(), 2);
+        assert!(!targets[1] && !targets[4]);
+    }
+    // a fanout bigger than the number of peers sends to all of them
+    assert_eq!(
+        forwarding_targets(ForwardingStrategy::RandomFanout(10), &peers, sender),
+        [true, false, true, true, false]
+    );
+    assert_eq!(
+        forwarding_targets(ForwardingStrategy::RandomFanout(0), &peers, None),
+        [false; 5]
+    );
+}
@@ -0,0 +1,174 @@
+//! Encrypted peer connections, using the `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake.
+//!
+//! A connecting peer that wants encryption sends `protocol::NOISE_CONNECTION_MAGIC` in place of
+//! the plaintext magic. After it everything on the connection is a Noise message with a length
+//! prefix:
+//! ```text
+//! %MESSAGE LENGTH, u16%
+//! %MESSAGE%
+//! ```
+//! The three messages of the XX handshake carry the `Hello`, the `Confirm` (or `Reject`) and the
+//! `Auth` as their payloads, so encryption does not cost any extra round trips. Everything after
+//! that is encrypted transport messages.
+//!
+//! The Noise static keys are generated for every connection and mean nothing on their own. The
+//! peers are authenticated by the signatures in the `Confirm` and the `Auth`, which cover the
+//! Noise handshake hash and so tie the node ids to this one encrypted session.
+//!
+//! A `NoiseSession` does not touch the connection itself. It turns what is to be sent into Noise
+//! messages and what was received back into plaintext, buffer to buffer, so that the connection
+//! can be read and written without blocking, see `connection`.
+
+use snow::{HandshakeState, TransportState};
+
+#[cfg(test)]
+mod tests;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// The largest Noise message, which is what fits behind a u16 length.
+const NOISE_MESSAGE_LEN_MAX: usize = u16::MAX as usize;
+
+/// The authentication tag every encrypted Noise message carries on top of its payload.
+const NOISE_TAG_LEN: usize = 16;
+
+fn noise_error(error: snow::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// The Noise state of one connection. What is encrypted goes out as length prefixed Noise
+/// messages and what is decrypted is the payloads of those messages, so the `protocol` messages
+/// are sent over it just like over a plain connection.
+///
+/// During the handshake every call to `encrypt` becomes one handshake message, so each handshake
+/// message has to be encrypted in a single call. Once the last handshake message has been sent
+/// or received the session switches to transport messages by itself.
+pub(crate) struct NoiseSession {
+    handshake: Option<Box<HandshakeState>>,
+    transport: Option<Box<TransportState>>,
+}
+
+impl NoiseSession {
+    fn new(initiator: bool) -> std::io::Result<Self> {
+        let builder = snow::Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?);
+        let keypair = builder.generate_keypair().map_err(noise_error)?;
+        let builder = builder.local_private_key(&keypair.private);
+        let handshake = if initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        }
+        .map_err(noise_error)?;
+        Ok(NoiseSession {
+            handshake: Some(Box::new(handshake)),
+            transport: None,
+        })
+    }
+
+    /// The session of the connecting peer, which sends the first handshake message. The magic
+    /// that goes in front of it is up to the connection.
+    pub(crate) fn initiator() -> std::io::Result<Self> {
+        NoiseSession::new(true)
+    }
+
+    /// The session of the accepting peer, once the magic has said the connection is encrypted.
+    pub(crate) fn responder() -> std::io::Result<Self> {
+        NoiseSession::new(false)
+    }
+
+    /// The hash of the handshake so far. Both peers get the same hash at the same point of the
+    /// handshake, and nobody else can. Empty once the handshake is done.
+    pub(crate) fn handshake_hash(&self) -> Vec<u8> {
+        match &self.handshake {
+            Some(handshake) => handshake.get_handshake_hash().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether the handshake is still going on, so that the next `encrypt` makes a handshake
+    /// message.
+    pub(crate) fn is_handshaking(&self) -> bool {
+        self.handshake.is_some()
+    }
+
+    fn finish_handshake_if_done(&mut self) -> std::io::Result<()> {
+        if self
+            .handshake
+            .as_ref()
+            .is_some_and(|handshake| handshake.is_handshake_finished())
+        {
+            let handshake = self.handshake.take().unwrap();
+            let transport = handshake.into_transport_mode().map_err(noise_error)?;
+            self.transport = Some(Box::new(transport));
+        }
+        Ok(())
+    }
+
+    /// Encrypt `payload` and append the Noise messages carrying it to `out`. Transport payloads
+    /// are split over as many messages as they need, during the handshake `payload` has to fit
+    /// into one.
+    pub(crate) fn encrypt(&mut self, payload: &[u8], out: &mut Vec<u8>) -> std::io::Result<()> {
+        let mut message = vec![0; NOISE_MESSAGE_LEN_MAX];
+        let mut rest = payload;
+        loop {
+            let (payload_len, message_len) = match (&mut self.handshake, &mut self.transport) {
+                (Some(handshake), _) => {
+                    let message_len = handshake
+                        .write_message(rest, &mut message)
+                        .map_err(noise_error)?;
+                    (rest.len(), message_len)
+                }
+                (None, Some(transport)) => {
+                    let payload_len = rest.len().min(NOISE_MESSAGE_LEN_MAX - NOISE_TAG_LEN);
+                    let message_len = transport
+                        .write_message(&rest[..payload_len], &mut message)
+                        .map_err(noise_error)?;
+                    (payload_len, message_len)
+                }
+                (None, None) => unreachable!("a noise session is always in one of the two modes"),
+            };
+            out.extend_from_slice(&(message_len as u16).to_be_bytes());
+            out.extend_from_slice(&message[..message_len]);
+            self.finish_handshake_if_done()?;
+            rest = &rest[payload_len..];
+            if rest.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Decrypt the whole Noise messages at the start of `input` and append their payloads to
+    /// `out`. A message that has not been received completely is left for the next call. Returns
+    /// how many bytes of `input` were used up.
+    pub(crate) fn decrypt(&mut self, input: &[u8], out: &mut Vec<u8>) -> std::io::Result<usize> {
+        let mut used = 0;
+        let mut payload = Vec::new();
+        while input.len() - used >= 2 {
+            let len = u16::from_be_bytes([input[used], input[used + 1]]) as usize;
+            let message = match input.get(used + 2..used + 2 + len) {
+                Some(message) => message,
+                None => break,
+            };
+            // only made room for once a whole message is there, not for every piece of one
+            payload.resize(NOISE_MESSAGE_LEN_MAX, 0);
+            let payload_len = match (&mut self.handshake, &mut self.transport) {
+                (Some(handshake), _) => handshake.read_message(message, &mut payload),
+                (None, Some(transport)) => transport.read_message(message, &mut payload),
+                (None, None) => unreachable!("a noise session is always in one of the two modes"),
+            }
+            .map_err(noise_error)?;
+            out.extend_from_slice(&payload[..payload_len]);
+            used += 2 + len;
+            self.finish_handshake_if_done()?;
+        }
+        Ok(used)
+    }
+}
+
+impl std::fmt::Debug for NoiseSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseSession")
+            .field("handshake_done", &self.transport.is_some())
+            .finish_non_exhaustive()
+    }
+}
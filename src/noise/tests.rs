@@ -0,0 +1,75 @@
+use super::*;
+
+/// Hand everything in `wire` to `session` and return the decrypted payloads.
+fn decrypt_all(session: &mut NoiseSession, wire: &mut Vec<u8>) -> Vec<u8> {
+    let mut plaintext = Vec::new();
+    let used = session.decrypt(wire, &mut plaintext).unwrap();
+    wire.drain(..used);
+    plaintext
+}
+
+/// Runs the three handshake messages and then some transport data between a pair of sessions.
+#[test]
+fn handshake_and_transport_test() {
+    let mut initiator = NoiseSession::initiator().unwrap();
+    let mut responder = NoiseSession::responder().unwrap();
+    let mut wire = Vec::new();
+    assert!(initiator.is_handshaking() && responder.is_handshaking());
+
+    initiator.encrypt(b"hello", &mut wire).unwrap();
+    assert_eq!(decrypt_all(&mut responder, &mut wire), b"hello");
+    assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+
+    responder.encrypt(b"confirm", &mut wire).unwrap();
+    assert_eq!(decrypt_all(&mut initiator, &mut wire), b"confirm");
+    assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+
+    // the last handshake message and the first transport data arrive together
+    initiator.encrypt(b"auth", &mut wire).unwrap();
+    initiator.encrypt(b" and more", &mut wire).unwrap();
+    assert_eq!(decrypt_all(&mut responder, &mut wire), b"auth and more");
+    assert!(!initiator.is_handshaking() && !responder.is_handshaking());
+
+    // more than fits into one noise message, in both directions
+    let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    initiator.encrypt(&data, &mut wire).unwrap();
+    assert_eq!(decrypt_all(&mut responder, &mut wire), data);
+    responder.encrypt(&data, &mut wire).unwrap();
+    assert_eq!(decrypt_all(&mut initiator, &mut wire), data);
+    assert!(wire.is_empty());
+}
+
+/// Messages that arrive a few bytes at a time are only decrypted once they are complete.
+#[test]
+fn partial_message_test() {
+    let mut initiator = NoiseSession::initiator().unwrap();
+    let mut responder = NoiseSession::responder().unwrap();
+    let mut wire = Vec::new();
+    initiator.encrypt(b"hello", &mut wire).unwrap();
+
+    let mut received = Vec::new();
+    let mut plaintext = Vec::new();
+    for byte in wire {
+        received.push(byte);
+        let used = responder.decrypt(&received, &mut plaintext).unwrap();
+        received.drain(..used);
+    }
+    assert_eq!(plaintext, b"hello");
+    assert!(received.is_empty());
+}
+
+/// A message that was tampered with on the way is refused.
+#[test]
+fn tampered_message_test() {
+    let mut initiator = NoiseSession::initiator().unwrap();
+    let mut responder = NoiseSession::responder().unwrap();
+    let mut wire = Vec::new();
+    initiator.encrypt(b"ping", &mut wire).unwrap();
+    decrypt_all(&mut responder, &mut wire);
+
+    responder.encrypt(b"pong", &mut wire).unwrap();
+    let last = wire.len() - 1;
+    wire[last] ^= 1;
+    let error = initiator.decrypt(&wire, &mut Vec::new()).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
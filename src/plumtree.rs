@@ -0,0 +1,129 @@
+//! The bookkeeping of plumtree, the epidemic broadcast tree of Leitão, Pereira and Rodrigues.
+//!
+//! Every peer connection is either eager or lazy. Gossip is pushed in full over the eager ones and
+//! only announced in an `IHave` over the lazy ones. Connections start out eager, and one over
+//! which a gossip arrives that the node already had is made lazy with a `Prune`. What is left of
+//! the eager connections is a spanning tree of the network.
+//!
+//! When a link of the tree breaks, the gossip announced over the lazy connections stops arriving
+//! in full. If it is not there `GRAFT_TIMEOUT` after its announcement, the node asks the
+//! announcing peer for it with a `Graft`, which also makes that connection eager again and so
+//! repairs the tree. The gossip a node has passed on is kept for a while so that it can answer
+//! the `Graft`s of others.
+//!
+//! Whether a connection is eager is part of the peer in `node`. What is kept here is the state
+//! that outlives connections, the cached gossip and the gossip that was announced but not
+//! received yet.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::identity::NodeId;
+use crate::protocol::{Gossip, MessageId};
+
+#[cfg(test)]
+mod tests;
+
+/// How long announced gossip may take to arrive in full before it is asked for. It is also how
+/// long a `Graft` may take to be answered before the next announcing peer is asked.
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long gossip that was passed on is kept for answering `Graft`s.
+const MESSAGE_CACHE_TIME: Duration = Duration::from_secs(30);
+
+/// The most gossips kept for answering `Graft`s. Past it the oldest are dropped early, so that
+/// the cache can not grow without bounds under heavy traffic.
+const MESSAGE_CACHE_LEN_MAX: usize = 10_000;
+
+/// Gossip that was announced to the node but has not arrived yet.
+#[derive(Debug)]
+struct MissingGossip {
+    /// When to ask the next announcing peer for it.
+    deadline: Instant,
+    /// The peers that announced it and have not been asked for it yet, in the order they did.
+    announcers: VecDeque<NodeId>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Plumtree {
+    message_cache: HashMap<MessageId, Gossip>,
+    /// The ids of the cached gossip in the order it was cached in, which is also the order it
+    /// expires in.
+    cache_queue: VecDeque<(Instant, MessageId)>,
+    missing: HashMap<MessageId, MissingGossip>,
+}
+
+impl Plumtree {
+    /// Keep `gossip`, as it is passed on, for answering `Graft`s.
+    pub(crate) fn cache(&mut self, gossip: &Gossip, now: Instant) {
+        if self.message_cache.len() >= MESSAGE_CACHE_LEN_MAX {
+            if let Some((_, id)) = self.cache_queue.pop_front() {
+                self.message_cache.remove(&id);
+            }
+        }
+        if self.message_cache.insert(gossip.id(), gossip.clone()).is_none() {
+            self.cache_queue.push_back((now, gossip.id()));
+        }
+    }
+
+    pub(crate) fn cached(&self, id: &MessageId) -> Option<&Gossip> {
+        self.message_cache.get(id)
+    }
+
+    /// Note that `announcer` has gossip the node has not heard yet.
+    pub(crate) fn announced(&mut self, id: MessageId, announcer: NodeId, now: Instant) {
+        let missing = self.missing.entry(id).or_insert_with(|| MissingGossip {
+            deadline: now + GRAFT_TIMEOUT,
+            announcers: VecDeque::new(),
+        });
+        if !missing.announcers.contains(&announcer) {
+            missing.announcers.push_back(announcer);
+        }
+    }
+
+    /// Note that the gossip has arrived, so it does not have to be asked for.
+    pub(crate) fn received(&mut self, id: &MessageId) {
+        self.missing.remove(id);
+    }
+
+    /// The peers to send a `Graft` to, and for which gossip, because it was announced by them
+    /// but has not arrived in time. Announcing peers that are no longer `connected` are passed
+    /// over, and gossip that nobody is left to ask for is given up on.
+    pub(crate) fn due_grafts(
+        &mut self,
+        now: Instant,
+        connected: impl Fn(&NodeId) -> bool,
+    ) -> Vec<(NodeId, MessageId)> {
+        let mut grafts = Vec::new();
+        self.missing.retain(|id, missing| {
+            if now < missing.deadline {
+                return true;
+            }
+            while let Some(announcer) = missing.announcers.pop_front() {
+                if connected(&announcer) {
+                    grafts.push((announcer, *id));
+                    missing.deadline = now + GRAFT_TIMEOUT;
+                    return true;
+                }
+            }
+            false
+        });
+        grafts
+    }
+
+    /// When the next `Graft` may be due, `None` if no announced gossip is missing.
+    pub(crate) fn next_graft(&self) -> Option<Instant> {
+        self.missing.values().map(|missing| missing.deadline).min()
+    }
+
+    /// Drop the gossip that has been cached for longer than `MESSAGE_CACHE_TIME`.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        while let Some(&(cache_instant, id)) = self.cache_queue.front() {
+            if now.duration_since(cache_instant) <= MESSAGE_CACHE_TIME {
+                break;
+            }
+            self.cache_queue.pop_front();
+            self.message_cache.remove(&id);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use super::*;
+use crate::identity::Identity;
+
+fn node_id(seed: u8) -> NodeId {
+    NodeId::from_bytes([seed; 32])
+}
+
+#[test]
+fn graft_announced_gossip_test() {
+    let start = Instant::now();
+    let id = MessageId {
+        origin: node_id(9),
+        seqno: 1,
+    };
+    let mut plumtree = Plumtree::default();
+    plumtree.announced(id, node_id(1), start);
+    plumtree.announced(id, node_id(2), start + Duration::from_millis(100));
+    plumtree.announced(id, node_id(3), start + Duration::from_millis(200));
+    plumtree.announced(id, node_id(1), start + Duration::from_millis(300));
+
+    assert_eq!(plumtree.next_graft(), Some(start + GRAFT_TIMEOUT));
+
+    let connected = |node_id: &NodeId| node_id.as_bytes()[0] != 2;
+    assert_eq!(plumtree.due_grafts(start, connected), []);
+    // the announcers are asked one after the other, passing over the one that is gone
+    assert_eq!(
+        plumtree.due_grafts(start + GRAFT_TIMEOUT, connected),
+        [(node_id(1), id)]
+    );
+    assert_eq!(
+        plumtree.due_grafts(start + GRAFT_TIMEOUT + GRAFT_TIMEOUT / 2, connected),
+        []
+    );
+    assert_eq!(
+        plumtree.due_grafts(start + GRAFT_TIMEOUT * 2, connected),
+        [(node_id(3), id)]
+    );
+    assert_eq!(plumtree.due_grafts(start + GRAFT_TIMEOUT * 3, connected), []);
+    assert!(plumtree.missing.is_empty());
+    assert_eq!(plumtree.next_graft(), None);
+
+    // gossip that arrives in time is not asked for
+    plumtree.announced(id, node_id(1), start);
+    plumtree.received(&id);
+    assert_eq!(plumtree.due_grafts(start + GRAFT_TIMEOUT, connected), []);
+}
+
+#[test]
+fn message_cache_test() {
+    let start = Instant::now();
+    let identity = Identity::generate();
+    let mut plumtree = Plumtree::default();
+    let gossip = Gossip::sign(&identity, 0, 3, b"cached".to_vec());
+    plumtree.cache(&gossip, start);
+    assert_eq!(plumtree.cached(&gossip.id()), Some(&gossip));
+
+    plumtree.expire(start + MESSAGE_CACHE_TIME);
+    assert!(plumtree.cached(&gossip.id()).is_some());
+    plumtree.expire(start + MESSAGE_CACHE_TIME + Duration::from_secs(1));
+    assert!(plumtree.cached(&gossip.id()).is_none());
+
+    // past its maximum length the oldest gossip makes room
+    for seqno in 0..MESSAGE_CACHE_LEN_MAX as u64 + 1 {
+        let mut gossip = gossip.clone();
+        gossip.seqno = seqno;
+        plumtree.cache(&gossip, start);
+    }
+    assert_eq!(plumtree.message_cache.len(), MESSAGE_CACHE_LEN_MAX);
+    assert!(plumtree.cached(&gossip.id()).is_none());
+}
@@ -0,0 +1,1059 @@
+//! The wire format spoken between peers. Everything that is sent over a peer connection is
+//! encoded and decoded here and nowhere else.
+//!
+//! A connection starts with the connecting peer sending a `Hello`. After that both sides only
+//! send `Message`s, each identified by its first byte:
+//! ```text
+//! 1 - gossip
+//! 2, 3 - unused, they were the peer request and peer data before protocol version 5
+//! 4 - confirmation/ack from a peer you have connected to
+//! 5 - rejection of a peer that can not be talked to
+//! 6 - authentication of the connecting peer
+//! 7 - announcement of gossip ids, with the plumtree capability
+//! 8 - request for announced gossip, with the plumtree capability
+//! 9 - request to stop pushing gossip, with the plumtree capability
+//! 10 - digest of the gossip held, with the anti-entropy capability
+//! 11 - request to join the network
+//! 12 - a join passed on through the network
+//! 13 - request to become an active peer
+//! 14 - notice that the connection is closed to make room for other peers
+//! 15 - a sample of known peers to swap with the receiver
+//! 16 - the answer to a shuffle
+//! 17 - refusal of a peer the accepting peer has no room for
+//! ```
+//! All integers are big endian.
+//!
+//! The `Hello` carries the range of protocol versions and the capabilities the connecting peer
+//! supports. The accepting peer picks the highest version both sides support and answers with a
+//! `Confirm` holding that version and its own capabilities, or with a `Reject` explaining why the
+//! two can not talk. Only the capabilities both sides have are used on the connection. An accepting
+//! peer that has too many connections answers with a `Busy` instead, naming other peers to try.
+//!
+//! The handshake also proves that both peers own the `NodeId` they claim. Each side sends a
+//! random nonce, the `Hello` one and the `Confirm` one, and each side signs both nonces with its
+//! secret key. The accepting peer's signature travels in the `Confirm` and the connecting peer's
+//! in an `Auth` sent in answer to it. Neither peer sends anything else before the handshake is
+//! complete.
+//!
+//! A connection can be encrypted by starting it with `NOISE_CONNECTION_MAGIC` instead of the
+//! `Hello`. The messages are then the same, but they are carried inside Noise messages as
+//! described in the `noise` module, and the handshake signatures also cover the Noise handshake
+//! hash.
+//!
+//! Every gossip is signed by the node that published it, its origin. Peers relay gossip untouched
+//! apart from its hop count and time to live, so the signature can be checked by every peer it
+//! reaches no matter how many relays it went through.
+//!
+//! Who is connected to whom is decided by the HyParView messages, see the `membership` module.
+
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::identity::{Identity, NodeId, Signature, SIGNATURE_LEN};
+
+#[cfg(test)]
+mod tests;
+
+/// This byte sequence is a rudimentary guard against garbage incomming connections. Which
+/// version of the protocol is spoken is negotiated after it, so it never needs to change.
+pub const INITIAL_CONNECTION_MAGIC: &str = "p2p_gossip peer";
+
+/// The first bytes of a connection that is encrypted. It has the length of
+/// `INITIAL_CONNECTION_MAGIC` so that the accepting peer can tell the two apart by reading just
+/// as many bytes.
+pub const NOISE_CONNECTION_MAGIC: &str = "p2p_gossip safe";
+
+/// The magic sent by peers from before version negotiation existed. It is only used to explain
+/// why such a peer is turned away.
+const LEGACY_CONNECTION_MAGIC: &str =
+    "Hello there I am a peer from the fantastic p2p_gossip program version v0.1";
+
+/// The oldest protocol version this implementation can speak, which is the newest one as well: no
+/// older version is supported. Versions 1 to 4 signed, identified and found peers differently and
+/// the decoder only knows the layout of version 5, so peers that only speak those are rejected in
+/// the handshake. This stays at `PROTOCOL_VERSION_MAX` unless a new version is added while the
+/// layout of the old one is still decoded for the peers that speak it.
+pub const PROTOCOL_VERSION_MIN: u16 = 5;
+
+/// The newest protocol version this implementation can speak. It is bumped whenever the format of
+/// an existing message changes, and `PROTOCOL_VERSION_MIN` with it unless the old format is kept
+/// for older peers.
+pub const PROTOCOL_VERSION_MAX: u16 = 5;
+
+/// The capabilities this implementation supports, as a bitset. Each bit stands for an optional
+/// feature of the protocol that is only used when both sides of a connection have it.
+pub const CAPABILITIES: u32 = CAPABILITY_PLUMTREE | CAPABILITY_ANTI_ENTROPY;
+
+/// The peer builds a plumtree broadcast tree and understands `IHave`, `Graft` and `Prune`. Nodes
+/// only claim it when they run in plumtree mode.
+pub const CAPABILITY_PLUMTREE: u32 = 1 << 0;
+
+/// The peer holds on to recent gossip, answers `Digest`s with the gossip missing from them and
+/// sends `Digest`s of its own. Nodes only claim it when they run anti-entropy rounds.
+pub const CAPABILITY_ANTI_ENTROPY: u32 = 1 << 1;
+
+/// `Reject` reasons are for humans to read and have no business being long.
+pub const REJECT_REASON_LEN_MAX: u16 = 1024;
+
+/// Each peer runs in a single threaded fashion. This means that if the processing of an incomming
+/// packet takes a long time, all other peer connections will get neglected and potentially
+/// disconnected. The peer lists of `Shuffle`s and their answers are variable size and bounded by
+/// this constant in order to avoid blocking or malicious attacks.
+pub const PEER_LIST_LEN_MAX: u16 = 32;
+
+/// The most gossip ids an `IHave` or a `Graft` can hold. More have to be split over several
+/// messages.
+pub const MESSAGE_ID_COUNT_MAX: u16 = 256;
+
+/// The largest bloom filter, in bytes, a `Digest` can hold. It fits tens of thousands of ids at
+/// a false positive rate of a percent.
+pub const DIGEST_FILTER_LEN_MAX: u32 = 64 * 1024;
+
+const GOSSIP_TYPE: u8 = 1;
+const CONFIRM_TYPE: u8 = 4;
+const REJECT_TYPE: u8 = 5;
+const AUTH_TYPE: u8 = 6;
+const IHAVE_TYPE: u8 = 7;
+const GRAFT_TYPE: u8 = 8;
+const PRUNE_TYPE: u8 = 9;
+const DIGEST_TYPE: u8 = 10;
+const JOIN_TYPE: u8 = 11;
+const FORWARD_JOIN_TYPE: u8 = 12;
+const NEIGHBOR_TYPE: u8 = 13;
+const DISCONNECT_TYPE: u8 = 14;
+const SHUFFLE_TYPE: u8 = 15;
+const SHUFFLE_REPLY_TYPE: u8 = 16;
+const BUSY_TYPE: u8 = 17;
+
+/// The length of the handshake nonces.
+pub const NONCE_LEN: usize = 32;
+
+/// A random challenge sent during the handshake. The other side proves it owns its node id by
+/// signing it.
+pub type Nonce = [u8; NONCE_LEN];
+
+/// The first thing a connecting peer sends. It tells the accepting peer which protocol versions
+/// and capabilities the connecting peer supports and where it can be reached.
+/// ```text
+/// %MAGIC%
+/// %MIN VERSION, u16%
+/// %MAX VERSION, u16%
+/// %CAPABILITIES, u32%
+/// %ADDRESS% - the listening address of the connecting peer
+/// %NODE ID, 32 bytes%
+/// %NONCE, 32 bytes%
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: u32,
+    pub listen_addr: SocketAddr,
+    pub node_id: NodeId,
+    pub nonce: Nonce,
+}
+
+/// Identifies a gossip in the whole network. Every node numbers the gossips it publishes, so the
+/// origin and the sequence number together are unique. Two gossips with the same payload are
+/// still two different gossips.
+/// ```text
+/// %ORIGIN, 32 bytes%
+/// %SEQNO, u64%
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId {
+    pub origin: NodeId,
+    pub seqno: u64,
+}
+
+impl std::fmt::Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.origin, self.seqno)
+    }
+}
+
+/// A gossip together with where it comes from. `seqno` tells apart the gossips of one origin and
+/// `signature` is made by `origin` over `Gossip::signed_data`.
+///
+/// `ttl` is the number of times the gossip may still be relayed and `hops` the number of times it
+/// has been relayed so far. Every relay takes one from the first and adds one to the second, and
+/// a gossip that arrives with a `ttl` of 0 is not relayed any further. Neither is covered by the
+/// signature, since they change on the way.
+/// ```text
+/// %TTL, u8%
+/// %HOPS, u8%
+/// %ORIGIN NODE ID, 32 bytes%
+/// %SEQUENCE NUMBER, u64%
+/// %PAYLOAD LENGTH, u32%
+/// %PAYLOAD%
+/// %SIGNATURE, 64 bytes%
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gossip {
+    pub ttl: u8,
+    pub hops: u8,
+    pub origin: NodeId,
+    pub seqno: u64,
+    pub payload: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl Gossip {
+    /// Create a gossip originating from `identity`, which may be relayed `ttl` times, and sign it.
+    pub fn sign(identity: &Identity, seqno: u64, ttl: u8, payload: Vec<u8>) -> Self {
+        let origin = identity.node_id();
+        let signature = identity.sign(&Gossip::signed_data(&origin, seqno, &payload));
+        Gossip {
+            ttl,
+            hops: 0,
+            origin,
+            seqno,
+            payload,
+            signature,
+        }
+    }
+
+    pub fn id(&self) -> MessageId {
+        MessageId {
+            origin: self.origin,
+            seqno: self.seqno,
+        }
+    }
+
+    /// Check that the gossip was signed by its origin and has not been altered since.
+    pub fn verify(&self) -> bool {
+        self.origin.verify(
+            &Gossip::signed_data(&self.origin, self.seqno, &self.payload),
+            &self.signature,
+        )
+    }
+
+    /// The data the origin of a gossip signs.
+    pub fn signed_data(origin: &NodeId, seqno: u64, payload: &[u8]) -> Vec<u8> {
+        [
+            &b"p2p_gossip gossip"[..],
+            origin.as_bytes(),
+            &seqno.to_be_bytes(),
+            payload,
+        ]
+        .concat()
+    }
+}
+
+/// A peer as it is handed around in peer lists, its node id and its listening address.
+/// ```text
+/// %NODE ID, 32 bytes%
+/// %ADDRESS%
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub node_id: NodeId,
+    pub addr: SocketAddr,
+}
+
+/// A summary of the gossip a node holds, sent to a peer so that it answers with the gossip that
+/// is missing from it. `filter` is a bloom filter over the ids of the gossip, hashed with
+/// `seed` and `hash_count` hash functions, see `Digest::contains`. Every digest gets a fresh random
+/// seed, so that gossip a digest falsely claims to hold is not missed again in the next round.
+///
+/// The node holds gossip for `max_age_ms` milliseconds, anything older than that may be missing
+/// from the digest without the node missing it, so it is not sent back.
+/// ```text
+/// %MAX AGE, u32% - in milliseconds
+/// %SEED, u64%
+/// %HASH COUNT, u8%
+/// %FILTER LENGTH, u32%
+/// %FILTER%
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub max_age_ms: u32,
+    pub seed: u64,
+    pub hash_count: u8,
+    pub filter: Vec<u8>,
+}
+
+impl Digest {
+    /// A digest of `ids` that claims to hold an id it does not with a probability of about
+    /// `false_positive_rate`. Past what fits in `DIGEST_FILTER_LEN_MAX` the rate goes up.
+    pub fn new(ids: &[MessageId], false_positive_rate: f64, max_age_ms: u32, seed: u64) -> Self {
+        let ln_2 = std::f64::consts::LN_2;
+        let count = ids.len().max(1) as f64;
+        let bit_count = (-count * false_positive_rate.ln() / (ln_2 * ln_2))
+            .ceil()
+            .clamp(64.0, DIGEST_FILTER_LEN_MAX as f64 * 8.0) as u64;
+        let hash_count = ((bit_count as f64 / count) * ln_2).round().clamp(1.0, 255.0) as u8;
+        let mut digest = Digest {
+            max_age_ms,
+            seed,
+            hash_count,
+            filter: vec![0; bit_count.div_ceil(8) as usize],
+        };
+        for id in ids {
+            for bit in digest.bit_indices(id) {
+                digest.filter[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        digest
+    }
+
+    /// Whether the gossip `id` is held by the node that made the digest, or looks like it.
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.bit_indices(id)
+            .all(|bit| self.filter[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    /// The bits of the filter `id` sets, by double hashing. Both sides of a connection have to
+    /// compute the same bits, so the hashes are spelled out here instead of coming from `std`.
+    /// They are not cryptographic, a peer that makes up colliding ids only hides its own gossip.
+    fn bit_indices(&self, id: &MessageId) -> impl Iterator<Item = u64> {
+        // the finalizer of splitmix64
+        let mix = |mut x: u64| {
+            x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            x ^ (x >> 31)
+        };
+        let mut h1 = self.seed;
+        for word in id.origin.as_bytes().chunks(8) {
+            h1 = mix(h1 ^ u64::from_be_bytes(word.try_into().unwrap()));
+        }
+        h1 = mix(h1 ^ id.seqno);
+        // a step of zero would have every hash function pick the same bit
+        let h2 = mix(h1 ^ self.seed.rotate_left(32)) | 1;
+        let bit_count = self.filter.len() as u64 * 8;
+        (0..self.hash_count as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+    }
+}
+
+/// The packets sent between peers once the `Hello` is out of the way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Gossip to be spread through the network.
+    /// ```text
+    /// 1
+    /// %GOSSIP%
+    /// ```
+    Gossip(Gossip),
+    /// The accepting peer's answer to a `Hello` it is happy with. Until it arrives the connecting
+    /// peer may not receive anything else. `version` is the protocol version spoken on the
+    /// connection from here on and `capabilities` are those of the accepting peer. `signature`
+    /// is made by `node_id` over `confirm_signed_data`.
+    /// ```text
+    /// 4
+    /// %MAGIC%
+    /// %VERSION, u16%
+    /// %CAPABILITIES, u32%
+    /// %NODE ID, 32 bytes%
+    /// %NONCE, 32 bytes%
+    /// %SIGNATURE, 64 bytes%
+    /// ```
+    Confirm {
+        version: u16,
+        capabilities: u32,
+        node_id: NodeId,
+        nonce: Nonce,
+        signature: Signature,
+    },
+    /// Sent right before closing a connection that can not continue, for example because the two
+    /// peers have no protocol version in common. The reason is at most `REJECT_REASON_LEN_MAX`
+    /// bytes of utf-8.
+    /// ```text
+    /// 5
+    /// %REASON LENGTH, u16%
+    /// %REASON%
+    /// ```
+    Reject { reason: String },
+    /// The connecting peer's answer to the `Confirm`, proving that it owns the node id from its
+    /// `Hello`. `signature` is made over `auth_signed_data`. Until it arrives the accepting peer
+    /// may not receive anything else.
+    /// ```text
+    /// 6
+    /// %SIGNATURE, 64 bytes%
+    /// ```
+    Auth { signature: Signature },
+    /// Announces gossip the sender has, without sending it. Goes to the peers the sender only
+    /// pushes gossip to lazily. It holds at most `MESSAGE_ID_COUNT_MAX` ids.
+    /// ```text
+    /// 7
+    /// %ID COUNT, u16%
+    /// %MESSAGE ID% * ID COUNT
+    /// ```
+    IHave { ids: Vec<MessageId> },
+    /// Asks for the announced gossip with these ids and for all gossip to be pushed eagerly from
+    /// now on. It holds at most `MESSAGE_ID_COUNT_MAX` ids.
+    /// ```text
+    /// 8
+    /// %ID COUNT, u16%
+    /// %MESSAGE ID% * ID COUNT
+    /// ```
+    Graft { ids: Vec<MessageId> },
+    /// Asks for gossip to only be announced from now on, since the receiver already gets it
+    /// another way.
+    /// ```text
+    /// 9
+    /// ```
+    Prune,
+    /// Asks for the recent gossip that is missing from the `Digest`, which the receiver sends back
+    /// as ordinary gossip.
+    /// ```text
+    /// 10
+    /// %DIGEST%
+    /// ```
+    Digest(Digest),
+    /// Sent by a node joining the network to its bootstrap peer, which takes it into its active
+    /// view and tells the network about it.
+    /// ```text
+    /// 11
+    /// ```
+    Join,
+    /// Tells the network about `peer`, which has joined it. It is passed on from peer to peer
+    /// until `ttl` runs out, and the last one connects to `peer`.
+    /// ```text
+    /// 12
+    /// %TTL, u8%
+    /// %PEER INFO%
+    /// ```
+    ForwardJoin { peer: PeerInfo, ttl: u8 },
+    /// Sent by a node that connected to a passive peer to make it an active one. The receiver
+    /// turns a node away with a `Disconnect` if it has no room for it, unless it asks with
+    /// `high_priority` because it has no active peers at all.
+    /// ```text
+    /// 13
+    /// %HIGH PRIORITY, u8%
+    /// ```
+    Neighbor { high_priority: bool },
+    /// Sent right before closing a connection that the sender has no room for. The receiver keeps
+    /// the sender as a passive peer.
+    /// ```text
+    /// 14
+    /// ```
+    Disconnect,
+    /// A random sample of the peers the sender knows, starting with the sender itself. The
+    /// receiver answers with a `ShuffleReply` and both keep what they learn as passive peers. It
+    /// holds at most `PEER_LIST_LEN_MAX` peers.
+    /// ```text
+    /// 15
+    /// %PEER COUNT, u16%
+    /// %PEER INFO% * PEER COUNT
+    /// ```
+    Shuffle { peers: Vec<PeerInfo> },
+    /// The answer to a `Shuffle`, a random sample of the passive peers of the sender. It holds at
+    /// most `PEER_LIST_LEN_MAX` peers.
+    /// ```text
+    /// 16
+    /// %PEER COUNT, u16%
+    /// %PEER INFO% * PEER COUNT
+    /// ```
+    ShuffleReply { peers: Vec<PeerInfo> },
+    /// The accepting peer's answer to a `Hello` when it has too many connections to take another
+    /// one. It is sent instead of the `Confirm`, right before closing the connection, and names
+    /// other peers to connect to instead. It holds at most `PEER_LIST_LEN_MAX` peers.
+    /// ```text
+    /// 17
+    /// %PEER COUNT, u16%
+    /// %PEER INFO% * PEER COUNT
+    /// ```
+    Busy { peers: Vec<PeerInfo> },
+}
+
+/// The data the accepting peer signs in its `Confirm`. `channel_binding` ties the signature to
+/// the connection it is made on. It is the Noise handshake hash on an encrypted connection, right
+/// after the `Hello`, and empty on a plaintext one.
+pub fn confirm_signed_data(
+    hello_nonce: &Nonce,
+    confirm_nonce: &Nonce,
+    channel_binding: &[u8],
+) -> Vec<u8> {
+    [
+        &b"p2p_gossip confirm"[..],
+        hello_nonce,
+        confirm_nonce,
+        channel_binding,
+    ]
+    .concat()
+}
+
+/// The data the connecting peer signs in its `Auth`. It differs from `confirm_signed_data` so
+/// that a signature from one can never be passed off as the other. `channel_binding` is the Noise
+/// handshake hash right after the `Confirm`, or empty on a plaintext connection.
+pub fn auth_signed_data(
+    hello_nonce: &Nonce,
+    confirm_nonce: &Nonce,
+    channel_binding: &[u8],
+) -> Vec<u8> {
+    [
+        &b"p2p_gossip auth"[..],
+        hello_nonce,
+        confirm_nonce,
+        channel_binding,
+    ]
+    .concat()
+}
+
+/// Pick the protocol version to speak with a peer that supports `min_version..=max_version`. This
+/// is the highest version both sides support, or `None` if they have none in common.
+pub fn negotiate_version(min_version: u16, max_version: u16) -> Option<u16> {
+    let version = max_version.min(PROTOCOL_VERSION_MAX);
+    if version < min_version.max(PROTOCOL_VERSION_MIN) {
+        return None;
+    }
+    Some(version)
+}
+
+/// Limits on what a peer is allowed to send. Anything beyond them is a protocol violation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// The largest gossip payload, in bytes, that is accepted.
+    pub max_gossip_len: usize,
+}
+
+fn protocol_violation(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+/// Write a socket address.
+/// ```text
+/// %IS_IPV6_BOOL, u8%
+/// %IP ADDRESS% - 4 octets for ipv4, 8 u16 segments for ipv6
+/// %PORT, u16%
+/// ```
+pub fn write_address(writer: &mut impl Write, addr: &SocketAddr) -> std::io::Result<()> {
+    match addr.ip() {
+        IpAddr::V6(addr6) => {
+            // write 1 to indicate a ipv6 address
+            writer.write_u8(1)?;
+            for segment in addr6.segments() {
+                writer.write_u16::<BigEndian>(segment)?;
+            }
+        }
+        IpAddr::V4(addr4) => {
+            // write 0 to indicate a ipv4 address
+            writer.write_u8(0)?;
+            writer.write_all(&addr4.octets())?;
+        }
+    }
+    writer.write_u16::<BigEndian>(addr.port())
+}
+
+/// Read a socket address written by `write_address`.
+pub fn read_address(reader: &mut impl Read) -> std::io::Result<SocketAddr> {
+    let ip = match reader.read_u8()? {
+        1 => {
+            let mut segments = [0u16; 8];
+            for segment in segments.iter_mut() {
+                *segment = reader.read_u16::<BigEndian>()?;
+            }
+            IpAddr::V6(Ipv6Addr::from(segments))
+        }
+        0 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        is_ipv6 => return Err(invalid_address_family(is_ipv6)),
+    };
+    let port = reader.read_u16::<BigEndian>()?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn invalid_address_family(is_ipv6: u8) -> std::io::Error {
+    protocol_violation(format!("invalid address family {}", is_ipv6))
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_node_id(reader: &mut impl Read) -> std::io::Result<NodeId> {
+    Ok(NodeId::from_bytes(read_bytes(reader)?))
+}
+
+fn read_magic(reader: &mut impl Read) -> std::io::Result<()> {
+    let mut read_buf = [0; INITIAL_CONNECTION_MAGIC.len()];
+    reader.read_exact(&mut read_buf)?;
+    if read_buf == LEGACY_CONNECTION_MAGIC.as_bytes()[..read_buf.len()] {
+        return Err(protocol_violation(
+            "peer speaks the v0.1 protocol, which predates version negotiation".to_string(),
+        ));
+    }
+    if read_buf != INITIAL_CONNECTION_MAGIC.as_bytes() {
+        return Err(protocol_violation("wrong connection magic".to_string()));
+    }
+    Ok(())
+}
+
+/// Write a `Hello` in a single write call.
+pub fn write_hello(writer: &mut impl Write, hello: &Hello) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(INITIAL_CONNECTION_MAGIC.as_bytes());
+    buf.write_u16::<BigEndian>(hello.min_version)?;
+    buf.write_u16::<BigEndian>(hello.max_version)?;
+    buf.write_u32::<BigEndian>(hello.capabilities)?;
+    write_address(&mut buf, &hello.listen_addr)?;
+    buf.extend_from_slice(hello.node_id.as_bytes());
+    buf.extend_from_slice(&hello.nonce);
+    writer.write_all(&buf)
+}
+
+/// Read a `Hello`. Fails with `InvalidData` if the magic is wrong.
+pub fn read_hello(reader: &mut impl Read) -> std::io::Result<Hello> {
+    read_magic(reader)?;
+    let min_version = reader.read_u16::<BigEndian>()?;
+    let max_version = reader.read_u16::<BigEndian>()?;
+    let capabilities = reader.read_u32::<BigEndian>()?;
+    let listen_addr = read_address(reader)?;
+    let node_id = read_node_id(reader)?;
+    let nonce = read_bytes(reader)?;
+    Ok(Hello {
+        min_version,
+        max_version,
+        capabilities,
+        listen_addr,
+        node_id,
+        nonce,
+    })
+}
+
+/// Decode the `Hello` at the start of `buf`. Returns it and the number of bytes it took up. Fails
+/// with `UnexpectedEof` if `buf` does not hold a whole `Hello` yet.
+pub fn read_hello_from_slice(buf: &[u8]) -> std::io::Result<(Hello, usize)> {
+    let mut cursor = Cursor::new(buf);
+    let hello = read_hello(&mut cursor)?;
+    Ok((hello, cursor.position() as usize))
+}
+
+/// Encode `message` into `buf`.
+fn encode_into(buf: &mut Vec<u8>, message: &Message) -> std::io::Result<()> {
+    match message {
+        Message::Gossip(gossip) => {
+            buf.write_u8(GOSSIP_TYPE)?;
+            buf.write_u8(gossip.ttl)?;
+            buf.write_u8(gossip.hops)?;
+            buf.extend_from_slice(gossip.origin.as_bytes());
+            buf.write_u64::<BigEndian>(gossip.seqno)?;
+            buf.write_u32::<BigEndian>(gossip.payload.len() as u32)?;
+            buf.extend_from_slice(&gossip.payload);
+            buf.extend_from_slice(&gossip.signature);
+        }
+        Message::Confirm {
+            version,
+            capabilities,
+            node_id,
+            nonce,
+            signature,
+        } => {
+            buf.write_u8(CONFIRM_TYPE)?;
+            buf.extend_from_slice(INITIAL_CONNECTION_MAGIC.as_bytes());
+            buf.write_u16::<BigEndian>(*version)?;
+            buf.write_u32::<BigEndian>(*capabilities)?;
+            buf.extend_from_slice(node_id.as_bytes());
+            buf.extend_from_slice(nonce);
+            buf.extend_from_slice(signature);
+        }
+        Message::Reject { reason } => {
+            // cut the reason short on a character boundary if it is too long
+            let mut len = reason.len().min(REJECT_REASON_LEN_MAX as usize);
+            while !reason.is_char_boundary(len) {
+                len -= 1;
+            }
+            buf.write_u8(REJECT_TYPE)?;
+            buf.write_u16::<BigEndian>(len as u16)?;
+            buf.extend_from_slice(&reason.as_bytes()[..len]);
+        }
+        Message::Auth { signature } => {
+            buf.write_u8(AUTH_TYPE)?;
+            buf.extend_from_slice(signature);
+        }
+        Message::IHave { ids } => {
+            buf.write_u8(IHAVE_TYPE)?;
+            write_message_ids(buf, ids)?;
+        }
+        Message::Graft { ids } => {
+            buf.write_u8(GRAFT_TYPE)?;
+            write_message_ids(buf, ids)?;
+        }
+        Message::Prune => buf.write_u8(PRUNE_TYPE)?,
+        Message::Digest(digest) => {
+            if digest.filter.is_empty() || digest.filter.len() > DIGEST_FILTER_LEN_MAX as usize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "digest filter of an impossible length",
+                ));
+            }
+            buf.write_u8(DIGEST_TYPE)?;
+            buf.write_u32::<BigEndian>(digest.max_age_ms)?;
+            buf.write_u64::<BigEndian>(digest.seed)?;
+            buf.write_u8(digest.hash_count)?;
+            buf.write_u32::<BigEndian>(digest.filter.len() as u32)?;
+            buf.extend_from_slice(&digest.filter);
+        }
+        Message::Join => buf.write_u8(JOIN_TYPE)?,
+        Message::ForwardJoin { peer, ttl } => {
+            buf.write_u8(FORWARD_JOIN_TYPE)?;
+            buf.write_u8(*ttl)?;
+            write_peer_info(buf, peer)?;
+        }
+        Message::Neighbor { high_priority } => {
+            buf.write_u8(NEIGHBOR_TYPE)?;
+            buf.write_u8(*high_priority as u8)?;
+        }
+        Message::Disconnect => buf.write_u8(DISCONNECT_TYPE)?,
+        Message::Shuffle { peers } => {
+            buf.write_u8(SHUFFLE_TYPE)?;
+            write_peer_list(buf, peers)?;
+        }
+        Message::ShuffleReply { peers } => {
+            buf.write_u8(SHUFFLE_REPLY_TYPE)?;
+            write_peer_list(buf, peers)?;
+        }
+        Message::Busy { peers } => {
+            buf.write_u8(BUSY_TYPE)?;
+            write_peer_list(buf, peers)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_peer_info(buf: &mut Vec<u8>, peer: &PeerInfo) -> std::io::Result<()> {
+    buf.extend_from_slice(peer.node_id.as_bytes());
+    write_address(buf, &peer.addr)
+}
+
+fn read_peer_info(reader: &mut impl Read) -> std::io::Result<PeerInfo> {
+    let node_id = read_node_id(reader)?;
+    let addr = read_address(reader)?;
+    Ok(PeerInfo { node_id, addr })
+}
+
+fn write_peer_list(buf: &mut Vec<u8>, peers: &[PeerInfo]) -> std::io::Result<()> {
+    if peers.len() > PEER_LIST_LEN_MAX as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "too many peers for one message",
+        ));
+    }
+    buf.write_u16::<BigEndian>(peers.len() as u16)?;
+    for peer in peers {
+        write_peer_info(buf, peer)?;
+    }
+    Ok(())
+}
+
+fn read_peer_count(reader: &mut impl Read) -> std::io::Result<u16> {
+    let count = reader.read_u16::<BigEndian>()?;
+    if count > PEER_LIST_LEN_MAX {
+        return Err(protocol_violation(format!(
+            "{} peers in a peer list, the maximum is {}",
+            count, PEER_LIST_LEN_MAX
+        )));
+    }
+    Ok(count)
+}
+
+fn read_peer_list(reader: &mut impl Read) -> std::io::Result<Vec<PeerInfo>> {
+    let count = read_peer_count(reader)?;
+    let mut peers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        peers.push(read_peer_info(reader)?);
+    }
+    Ok(peers)
+}
+
+fn write_message_ids(buf: &mut Vec<u8>, ids: &[MessageId]) -> std::io::Result<()> {
+    if ids.len() > MESSAGE_ID_COUNT_MAX as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "too many message ids for one message",
+        ));
+    }
+    buf.write_u16::<BigEndian>(ids.len() as u16)?;
+    for id in ids {
+        buf.extend_from_slice(id.origin.as_bytes());
+        buf.write_u64::<BigEndian>(id.seqno)?;
+    }
+    Ok(())
+}
+
+fn read_message_id_count(reader: &mut impl Read) -> std::io::Result<u16> {
+    let count = reader.read_u16::<BigEndian>()?;
+    if count > MESSAGE_ID_COUNT_MAX {
+        return Err(protocol_violation(format!(
+            "{} message ids, the maximum is {}",
+            count, MESSAGE_ID_COUNT_MAX
+        )));
+    }
+    Ok(count)
+}
+
+fn read_message_ids(reader: &mut impl Read) -> std::io::Result<Vec<MessageId>> {
+    let count = read_message_id_count(reader)?;
+    let mut ids = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let origin = read_node_id(reader)?;
+        let seqno = reader.read_u64::<BigEndian>()?;
+        ids.push(MessageId { origin, seqno });
+    }
+    Ok(ids)
+}
+
+fn read_gossip_len(reader: &mut impl Read, limits: &DecodeLimits) -> std::io::Result<usize> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    if len > limits.max_gossip_len {
+        return Err(protocol_violation(format!(
+            "{} bytes of gossip, the maximum is {}",
+            len, limits.max_gossip_len
+        )));
+    }
+    Ok(len)
+}
+
+fn read_reject_reason_len(reader: &mut impl Read) -> std::io::Result<usize> {
+    let len = reader.read_u16::<BigEndian>()?;
+    if len > REJECT_REASON_LEN_MAX {
+        return Err(protocol_violation(format!(
+            "{} bytes of reject reason, the maximum is {}",
+            len, REJECT_REASON_LEN_MAX
+        )));
+    }
+    Ok(len as usize)
+}
+
+fn read_digest_filter_len(reader: &mut impl Read) -> std::io::Result<usize> {
+    let len = reader.read_u32::<BigEndian>()?;
+    // an empty filter has no bits for the ids to be hashed to
+    if len == 0 || len > DIGEST_FILTER_LEN_MAX {
+        return Err(protocol_violation(format!(
+            "{} bytes of digest filter, it has to be 1 to {}",
+            len, DIGEST_FILTER_LEN_MAX
+        )));
+    }
+    Ok(len as usize)
+}
+
+fn unknown_type(message_type: u8) -> std::io::Error {
+    protocol_violation(format!("unknown packet type {}", message_type))
+}
+
+/// Encode `message` into a new buffer.
+pub fn encode_to_vec(message: &Message) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_into(&mut buf, message).expect("message can not be encoded");
+    buf
+}
+
+/// Encode `message` and write it with a single write call, so that a message is never split
+/// over several writes.
+pub fn encode(writer: &mut impl Write, message: &Message) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    encode_into(&mut buf, message)?;
+    writer.write_all(&buf)
+}
+
+/// Read and decode one message. Fails with `InvalidData` if the peer is not following protocol or
+/// is going beyond `limits`, in which case nothing more than the offending header has been read.
+pub fn decode(reader: &mut impl Read, limits: &DecodeLimits) -> std::io::Result<Message> {
+    match reader.read_u8()? {
+        GOSSIP_TYPE => {
+            let ttl = reader.read_u8()?;
+            let hops = reader.read_u8()?;
+            let origin = read_node_id(reader)?;
+            let seqno = reader.read_u64::<BigEndian>()?;
+            let len = read_gossip_len(reader, limits)?;
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload)?;
+            let signature = read_bytes::<SIGNATURE_LEN>(reader)?;
+            Ok(Message::Gossip(Gossip {
+                ttl,
+                hops,
+                origin,
+                seqno,
+                payload,
+                signature,
+            }))
+        }
+        CONFIRM_TYPE => {
+            read_magic(reader)?;
+            let version = reader.read_u16::<BigEndian>()?;
+            let capabilities = reader.read_u32::<BigEndian>()?;
+            let node_id = read_node_id(reader)?;
+            let nonce = read_bytes(reader)?;
+            let signature = read_bytes::<SIGNATURE_LEN>(reader)?;
+            Ok(Message::Confirm {
+                version,
+                capabilities,
+                node_id,
+                nonce,
+                signature,
+            })
+        }
+        REJECT_TYPE => {
+            let len = read_reject_reason_len(reader)?;
+            let mut reason = vec![0; len];
+            reader.read_exact(&mut reason)?;
+            Ok(Message::Reject {
+                reason: String::from_utf8_lossy(&reason).into_owned(),
+            })
+        }
+        AUTH_TYPE => Ok(Message::Auth {
+            signature: read_bytes(reader)?,
+        }),
+        IHAVE_TYPE => Ok(Message::IHave {
+            ids: read_message_ids(reader)?,
+        }),
+        GRAFT_TYPE => Ok(Message::Graft {
+            ids: read_message_ids(reader)?,
+        }),
+        PRUNE_TYPE => Ok(Message::Prune),
+        DIGEST_TYPE => {
+            let max_age_ms = reader.read_u32::<BigEndian>()?;
+            let seed = reader.read_u64::<BigEndian>()?;
+            let hash_count = reader.read_u8()?;
+            let len = read_digest_filter_len(reader)?;
+            let mut filter = vec![0; len];
+            reader.read_exact(&mut filter)?;
+            Ok(Message::Digest(Digest {
+                max_age_ms,
+                seed,
+                hash_count,
+                filter,
+            }))
+        }
+        JOIN_TYPE => Ok(Message::Join),
+        FORWARD_JOIN_TYPE => {
+            let ttl = reader.read_u8()?;
+            let peer = read_peer_info(reader)?;
+            Ok(Message::ForwardJoin { peer, ttl })
+        }
+        NEIGHBOR_TYPE => match reader.read_u8()? {
+            0 => Ok(Message::Neighbor {
+                high_priority: false,
+            }),
+            1 => Ok(Message::Neighbor {
+                high_priority: true,
+            }),
+            priority => Err(protocol_violation(format!(
+                "invalid neighbor priority {}",
+                priority
+            ))),
+        },
+        DISCONNECT_TYPE => Ok(Message::Disconnect),
+        SHUFFLE_TYPE => Ok(Message::Shuffle {
+            peers: read_peer_list(reader)?,
+        }),
+        SHUFFLE_REPLY_TYPE => Ok(Message::ShuffleReply {
+            peers: read_peer_list(reader)?,
+        }),
+        BUSY_TYPE => Ok(Message::Busy {
+            peers: read_peer_list(reader)?,
+        }),
+        request_type => Err(unknown_type(request_type)),
+    }
+}
+
+/// Decode one message from the start of `buf`. Returns the message and the number of bytes it
+/// took up. Fails with `UnexpectedEof` if `buf` does not hold a whole message yet.
+pub fn decode_from_slice(buf: &[u8], limits: &DecodeLimits) -> std::io::Result<(Message, usize)> {
+    let mut cursor = Cursor::new(buf);
+    let message = decode(&mut cursor, limits)?;
+    Ok((message, cursor.position() as usize))
+}
+
+/// Move `header` past `len` bytes without looking at them. They need not have arrived yet.
+fn skip(header: &mut Cursor<&[u8]>, len: usize) {
+    header.set_position(header.position() + len as u64);
+}
+
+fn skip_address(header: &mut Cursor<&[u8]>) -> std::io::Result<()> {
+    match header.read_u8()? {
+        1 => skip(header, 8 * 2 + 2),
+        0 => skip(header, 4 + 2),
+        is_ipv6 => return Err(invalid_address_family(is_ipv6)),
+    }
+    Ok(())
+}
+
+fn skip_peer_info(header: &mut Cursor<&[u8]>) -> std::io::Result<()> {
+    skip(header, 32);
+    skip_address(header)
+}
+
+/// Move `header` to the end of the message that starts at it, reading only what tells how long
+/// the message is. This follows the layout `decode` reads, the round trip tests check that the
+/// two agree for every kind of message.
+fn skip_message(header: &mut Cursor<&[u8]>, limits: &DecodeLimits) -> std::io::Result<()> {
+    match header.read_u8()? {
+        GOSSIP_TYPE => {
+            skip(header, 1 + 1 + 32 + 8);
+            let len = read_gossip_len(header, limits)?;
+            skip(header, len + SIGNATURE_LEN);
+        }
+        CONFIRM_TYPE => skip(
+            header,
+            INITIAL_CONNECTION_MAGIC.len() + 2 + 4 + 32 + NONCE_LEN + SIGNATURE_LEN,
+        ),
+        REJECT_TYPE => {
+            let len = read_reject_reason_len(header)?;
+            skip(header, len);
+        }
+        AUTH_TYPE => skip(header, SIGNATURE_LEN),
+        IHAVE_TYPE | GRAFT_TYPE => {
+            let count = read_message_id_count(header)?;
+            skip(header, count as usize * (32 + 8));
+        }
+        PRUNE_TYPE | JOIN_TYPE | DISCONNECT_TYPE => {}
+        DIGEST_TYPE => {
+            skip(header, 4 + 8 + 1);
+            let len = read_digest_filter_len(header)?;
+            skip(header, len);
+        }
+        FORWARD_JOIN_TYPE => {
+            skip(header, 1);
+            skip_peer_info(header)?;
+        }
+        NEIGHBOR_TYPE => skip(header, 1),
+        SHUFFLE_TYPE | SHUFFLE_REPLY_TYPE | BUSY_TYPE => {
+            for _ in 0..read_peer_count(header)? {
+                skip_peer_info(header)?;
+            }
+        }
+        message_type => return Err(unknown_type(message_type)),
+    }
+    Ok(())
+}
+
+/// The length of what `skip` moves past, or `None` if `buf` ends before it could tell.
+fn measure(
+    buf: &[u8],
+    skip: impl FnOnce(&mut Cursor<&[u8]>) -> std::io::Result<()>,
+) -> std::io::Result<Option<usize>> {
+    let mut header = Cursor::new(buf);
+    match skip(&mut header) {
+        Ok(()) => Ok(Some(header.position() as usize)),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// The length of the message at the start of `buf`, told from its header alone, so that a message
+/// that is still arriving does not have to be decoded over and over. `None` if `buf` does not
+/// hold enough of the header yet. Fails with `InvalidData` like `decode` if the header already
+/// breaks protocol or goes beyond `limits`, before the rest of the message is waited for.
+pub fn message_len(buf: &[u8], limits: &DecodeLimits) -> std::io::Result<Option<usize>> {
+    measure(buf, |header| skip_message(header, limits))
+}
+
+/// The length of the `Hello` at the start of `buf`, like `message_len`. Fails with `InvalidData`
+/// if the magic is wrong.
+pub fn hello_len(buf: &[u8]) -> std::io::Result<Option<usize>> {
+    measure(buf, |header| {
+        read_magic(header)?;
+        skip(header, 2 + 2 + 4);
+        skip_address(header)?;
+        skip(header, 32 + NONCE_LEN);
+        Ok(())
+    })
+}
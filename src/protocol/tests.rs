@@ -0,0 +1,437 @@
+use super::*;
+use crate::identity::Identity;
+
+const LIMITS: DecodeLimits = DecodeLimits {
+    max_gossip_len: 1024,
+};
+
+fn v4_addr() -> SocketAddr {
+    "127.0.0.1:25532".parse().unwrap()
+}
+
+fn v6_addr() -> SocketAddr {
+    "[2001:db8::1:2]:25533".parse().unwrap()
+}
+
+fn node_id(seed: u8) -> NodeId {
+    NodeId::from_bytes([seed; 32])
+}
+
+fn peer_info(seed: u8, addr: SocketAddr) -> PeerInfo {
+    PeerInfo {
+        node_id: node_id(seed),
+        addr,
+    }
+}
+
+/// A gossip with a made up signature, which is fine as long as nobody verifies it.
+fn gossip(payload: Vec<u8>) -> Message {
+    Message::Gossip(Gossip {
+        ttl: 9,
+        hops: 4,
+        origin: node_id(0x77),
+        seqno: 0x0102_0304_0506_0708,
+        payload,
+        signature: [0x88; SIGNATURE_LEN],
+    })
+}
+
+fn confirm() -> Message {
+    Message::Confirm {
+        version: PROTOCOL_VERSION_MAX,
+        capabilities: CAPABILITIES,
+        node_id: node_id(0x11),
+        nonce: [0x22; NONCE_LEN],
+        signature: [0x33; SIGNATURE_LEN],
+    }
+}
+
+fn hello(listen_addr: SocketAddr) -> Hello {
+    Hello {
+        min_version: 3,
+        max_version: 7,
+        capabilities: 0x8000_0001,
+        listen_addr,
+        node_id: node_id(0x44),
+        nonce: [0x55; NONCE_LEN],
+    }
+}
+
+/// Encode `message`, decode it again through both the `Read` and the byte buffer interface and
+/// check that it survives the trip unchanged. Its length has to be told right from every part of
+/// it that holds the header, and decoding any part of it short of the whole runs out of bytes.
+fn assert_round_trip(message: Message) {
+    let buf = encode_to_vec(&message);
+
+    let mut written = Vec::new();
+    encode(&mut written, &message).unwrap();
+    assert_eq!(written, buf);
+
+    assert_eq!(decode(&mut &buf[..], &LIMITS).unwrap(), message);
+    assert_eq!(
+        decode_from_slice(&buf, &LIMITS).unwrap(),
+        (message, buf.len())
+    );
+
+    for len in 0..=buf.len() {
+        match message_len(&buf[..len], &LIMITS).unwrap() {
+            Some(message_len) => assert_eq!(message_len, buf.len()),
+            None => assert!(len < buf.len()),
+        }
+    }
+    for len in 0..buf.len() {
+        let error = decode_from_slice(&buf[..len], &LIMITS).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
+
+#[test]
+fn message_round_trip_test() {
+    assert_round_trip(gossip(b"some gossip".to_vec()));
+    assert_round_trip(gossip(Vec::new()));
+    assert_round_trip(gossip(vec![0x5A; LIMITS.max_gossip_len]));
+    assert_round_trip(Message::Join);
+    assert_round_trip(Message::ForwardJoin {
+        peer: peer_info(1, v6_addr()),
+        ttl: 6,
+    });
+    assert_round_trip(Message::Neighbor {
+        high_priority: true,
+    });
+    assert_round_trip(Message::Neighbor {
+        high_priority: false,
+    });
+    assert_round_trip(Message::Disconnect);
+    assert_round_trip(Message::Shuffle { peers: Vec::new() });
+    assert_round_trip(Message::Shuffle {
+        peers: vec![
+            peer_info(1, v4_addr()),
+            peer_info(2, v6_addr()),
+            peer_info(3, v4_addr()),
+        ],
+    });
+    assert_round_trip(Message::Busy {
+        peers: vec![peer_info(5, v4_addr())],
+    });
+    assert_round_trip(Message::ShuffleReply {
+        peers: vec![peer_info(4, v6_addr()); PEER_LIST_LEN_MAX as usize],
+    });
+    assert_round_trip(confirm());
+    assert_round_trip(Message::Reject {
+        reason: "no common protocol version".to_string(),
+    });
+    assert_round_trip(Message::Reject {
+        reason: String::new(),
+    });
+    assert_round_trip(Message::Auth {
+        signature: [0x66; SIGNATURE_LEN],
+    });
+    let ids: Vec<MessageId> = (0..MESSAGE_ID_COUNT_MAX as u64)
+        .map(|seqno| MessageId {
+            origin: node_id(seqno as u8),
+            seqno,
+        })
+        .collect();
+    assert_round_trip(Message::IHave { ids: ids.clone() });
+    assert_round_trip(Message::IHave { ids: Vec::new() });
+    assert_round_trip(Message::Graft { ids });
+    assert_round_trip(Message::Prune);
+    assert_round_trip(Message::Digest(Digest {
+        max_age_ms: 30_000,
+        seed: 0x0102_0304_0506_0708,
+        hash_count: 7,
+        filter: vec![0xA5; DIGEST_FILTER_LEN_MAX as usize],
+    }));
+}
+
+#[test]
+fn digest_test() {
+    let ids: Vec<MessageId> = (0..1000)
+        .map(|seqno| MessageId {
+            origin: node_id(seqno as u8),
+            seqno,
+        })
+        .collect();
+    let digest = Digest::new(&ids[..500], 0.01, 1000, rand::random());
+    assert!(ids[..500].iter().all(|id| digest.contains(id)));
+    let false_positives = ids[500..].iter().filter(|id| digest.contains(id)).count();
+    assert!(false_positives < 25, "{} false positives", false_positives);
+
+    // the bits only depend on the seed, so that every node reads a digest the same way
+    assert_eq!(
+        Digest::new(&ids[..10], 0.01, 1000, 42),
+        Digest::new(&ids[..10], 0.01, 1000, 42)
+    );
+    assert_ne!(
+        Digest::new(&ids[..10], 0.01, 1000, 42).filter,
+        Digest::new(&ids[..10], 0.01, 1000, 43).filter
+    );
+    let empty = Digest::new(&[], 0.01, 1000, 42);
+    assert!(!empty.contains(&ids[0]));
+}
+
+#[test]
+fn long_reject_reason_is_cut_short_test() {
+    // 'é' is two bytes long, so the cut has to fall between two of them
+    let reason = "é".repeat(REJECT_REASON_LEN_MAX as usize);
+    let buf = encode_to_vec(&Message::Reject { reason });
+    match decode(&mut &buf[..], &LIMITS).unwrap() {
+        Message::Reject { reason } => {
+            assert_eq!(reason, "é".repeat(REJECT_REASON_LEN_MAX as usize / 2))
+        }
+        message => panic!("decoded {:?}", message),
+    }
+}
+
+#[test]
+fn hello_round_trip_test() {
+    for listen_addr in [v4_addr(), v6_addr()] {
+        let hello = hello(listen_addr);
+        let mut buf = Vec::new();
+        write_hello(&mut buf, &hello).unwrap();
+        assert_eq!(read_hello(&mut &buf[..]).unwrap(), hello);
+
+        buf.push(GOSSIP_TYPE);
+        assert_eq!(read_hello_from_slice(&buf).unwrap(), (hello, buf.len() - 1));
+        for len in 0..buf.len() - 1 {
+            let error = read_hello_from_slice(&buf[..len]).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+            if let Some(hello_len) = hello_len(&buf[..len]).unwrap() {
+                assert_eq!(hello_len, buf.len() - 1);
+            }
+        }
+        assert_eq!(hello_len(&buf).unwrap(), Some(buf.len() - 1));
+    }
+}
+
+/// Pins down the exact bytes of a few messages so that the wire format can not change by accident.
+#[test]
+fn wire_format_test() {
+    assert_eq!(
+        encode_to_vec(&gossip(vec![0xAA, 0xBB])),
+        [
+            &[1, 9, 4][..],
+            &[0x77; 32],
+            &[1, 2, 3, 4, 5, 6, 7, 8],
+            &[0, 0, 0, 2, 0xAA, 0xBB],
+            &[0x88; SIGNATURE_LEN]
+        ]
+        .concat()
+    );
+    assert_eq!(encode_to_vec(&Message::Join), [11]);
+    assert_eq!(
+        encode_to_vec(&Message::ForwardJoin {
+            peer: peer_info(7, v4_addr()),
+            ttl: 3
+        }),
+        [&[12, 3][..], &[7; 32], &[0, 127, 0, 0, 1, 0x63, 0xBC]].concat()
+    );
+    assert_eq!(
+        encode_to_vec(&Message::Neighbor {
+            high_priority: true
+        }),
+        [13, 1]
+    );
+    assert_eq!(encode_to_vec(&Message::Disconnect), [14]);
+    assert_eq!(
+        encode_to_vec(&Message::Shuffle {
+            peers: vec![peer_info(7, v4_addr())]
+        }),
+        [&[15, 0, 1][..], &[7; 32], &[0, 127, 0, 0, 1, 0x63, 0xBC]].concat()
+    );
+    assert_eq!(
+        encode_to_vec(&Message::Auth {
+            signature: [9; SIGNATURE_LEN]
+        }),
+        [&[6][..], &[9; SIGNATURE_LEN]].concat()
+    );
+    assert_eq!(
+        encode_to_vec(&Message::Graft {
+            ids: vec![MessageId {
+                origin: node_id(5),
+                seqno: 0x0102
+            }]
+        }),
+        [&[8, 0, 1][..], &[5; 32], &[0, 0, 0, 0, 0, 0, 1, 2]].concat()
+    );
+    assert_eq!(encode_to_vec(&Message::Prune), [9]);
+
+    let mut buf = Vec::new();
+    write_address(&mut buf, &v6_addr()).unwrap();
+    assert_eq!(
+        buf,
+        [1, 0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 2, 0x63, 0xBD]
+    );
+}
+
+#[test]
+fn oversized_gossip_is_rejected_test() {
+    let buf = encode_to_vec(&gossip(vec![0; LIMITS.max_gossip_len + 1]));
+    let error = decode(&mut &buf[..], &LIMITS).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // the length is checked before the payload is read, a lying header is enough
+    let lying_header = [&[1, 0, 0][..], &[0; 32], &[0; 8], &[0xFF; 4]].concat();
+    let error = decode(&mut &lying_header[..], &LIMITS).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    let error = message_len(&lying_header, &LIMITS).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn protocol_violations_are_rejected_test() {
+    let too_many_addresses = [15, 0, PEER_LIST_LEN_MAX as u8 + 1];
+    let bad_address_family = [&[16, 0, 1][..], &[0; 32], &[7, 127, 0, 0, 1, 0, 80]].concat();
+    let bad_priority = [13, 2];
+    // the peer request and peer data of older protocol versions are gone
+    let unknown_types = [[2], [3], [42]];
+    for buf in [&too_many_addresses[..], &bad_address_family[..], &bad_priority]
+        .into_iter()
+        .chain(unknown_types.iter().map(|buf| &buf[..]))
+    {
+        let error = decode(&mut &buf[..], &LIMITS).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+    // the length of a message does not depend on the priority, it is only checked once the
+    // message is decoded
+    for buf in [&too_many_addresses[..], &bad_address_family[..]]
+        .into_iter()
+        .chain(unknown_types.iter().map(|buf| &buf[..]))
+    {
+        let error = message_len(buf, &LIMITS).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    let mut bad_confirm = encode_to_vec(&confirm());
+    bad_confirm[1] ^= 1;
+    let error = decode(&mut &bad_confirm[..], &LIMITS).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut bad_hello = Vec::new();
+    write_hello(&mut bad_hello, &hello(v4_addr())).unwrap();
+    bad_hello[0] ^= 1;
+    let error = read_hello(&mut &bad_hello[..]).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    let error = hello_len(&bad_hello).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let too_many_ids = [7, 0xFF, 0xFF];
+    let empty_digest = [&[10][..], &[0; 4], &[0; 8], &[1], &[0; 4]].concat();
+    let too_long_reason = [5, 0xFF, 0xFF];
+    for buf in [&too_many_ids[..], &empty_digest, &too_long_reason] {
+        let error = decode(&mut &buf[..], &LIMITS).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let error = message_len(buf, &LIMITS).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    let too_many = Message::Shuffle {
+        peers: vec![peer_info(1, v4_addr()); PEER_LIST_LEN_MAX as usize + 1],
+    };
+    assert!(encode(&mut Vec::new(), &too_many).is_err());
+}
+
+#[test]
+fn truncated_message_test() {
+    for message in [
+        Message::Shuffle {
+            peers: vec![peer_info(1, v6_addr()), peer_info(2, v4_addr())],
+        },
+        Message::ForwardJoin {
+            peer: peer_info(3, v6_addr()),
+            ttl: 1,
+        },
+        confirm(),
+    ] {
+        let buf = encode_to_vec(&message);
+        for len in 0..buf.len() {
+            let error = decode_from_slice(&buf[..len], &LIMITS).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
+
+/// The signed data of the two handshake directions must never be mixed up, otherwise a peer could
+/// answer a `Confirm` by echoing its signature back. Neither may that of two connections.
+#[test]
+fn handshake_signatures_test() {
+    let identity = Identity::generate();
+    let (hello_nonce, confirm_nonce) = ([1; NONCE_LEN], [2; NONCE_LEN]);
+    let binding = [3; 32];
+    let signature = identity.sign(&confirm_signed_data(&hello_nonce, &confirm_nonce, &binding));
+    assert!(identity
+        .node_id()
+        .verify(&confirm_signed_data(&hello_nonce, &confirm_nonce, &binding), &signature));
+    assert!(!identity
+        .node_id()
+        .verify(&auth_signed_data(&hello_nonce, &confirm_nonce, &binding), &signature));
+    assert!(!identity
+        .node_id()
+        .verify(&confirm_signed_data(&confirm_nonce, &hello_nonce, &binding), &signature));
+    // a signature made for one encrypted session is worthless in another
+    assert!(!identity
+        .node_id()
+        .verify(&confirm_signed_data(&hello_nonce, &confirm_nonce, &[4; 32]), &signature));
+}
+
+/// A peer from before version negotiation gets turned away with an error saying so.
+#[test]
+fn legacy_hello_is_rejected_test() {
+    let mut legacy_hello = LEGACY_CONNECTION_MAGIC.as_bytes().to_vec();
+    write_address(&mut legacy_hello, &v4_addr()).unwrap();
+    let error = read_hello(&mut &legacy_hello[..]).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("v0.1"));
+}
+
+#[test]
+fn negotiate_version_test() {
+    assert_eq!(
+        negotiate_version(PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX),
+        Some(PROTOCOL_VERSION_MAX)
+    );
+    // a newer peer falls back to our newest version and an older one gets its own
+    assert_eq!(
+        negotiate_version(PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX + 5),
+        Some(PROTOCOL_VERSION_MAX)
+    );
+    assert_eq!(
+        negotiate_version(0, PROTOCOL_VERSION_MIN),
+        Some(PROTOCOL_VERSION_MIN)
+    );
+    assert_eq!(
+        negotiate_version(PROTOCOL_VERSION_MAX + 1, PROTOCOL_VERSION_MAX + 5),
+        None
+    );
+    assert_eq!(negotiate_version(0, PROTOCOL_VERSION_MIN - 1), None);
+    assert_eq!(negotiate_version(7, 3), None);
+}
+
+#[test]
+fn gossip_signature_test() {
+    let identity = Identity::generate();
+    let gossip = Gossip::sign(&identity, 42, 5, b"signed news".to_vec());
+    assert_eq!(gossip.origin, identity.node_id());
+    assert_eq!((gossip.ttl, gossip.hops), (5, 0));
+    assert!(gossip.verify());
+
+    // relays change these on the way
+    let mut relayed = gossip.clone();
+    relayed.ttl -= 1;
+    relayed.hops += 1;
+    assert!(relayed.verify());
+    assert_eq!(relayed.id(), gossip.id());
+
+    let mut altered = gossip.clone();
+    altered.payload[0] ^= 1;
+    assert!(!altered.verify());
+
+    let mut altered = gossip.clone();
+    altered.seqno += 1;
+    assert!(!altered.verify());
+
+    // somebody else claiming to be the origin
+    let mut forged = Gossip::sign(&Identity::generate(), 42, 5, b"signed news".to_vec());
+    forged.origin = identity.node_id();
+    assert!(!forged.verify());
+}
@@ -0,0 +1,307 @@
+//! Waiting for something to do. The node sleeps in `Reactor::wait` until one of its sockets is
+//! ready, a `GossipNode` handle wakes it up or the earliest of its timers runs out, so an idle
+//! node takes next to no CPU and a packet that arrives is handled right away.
+//!
+//! The sockets are registered with a `mio::Poll`, which uses epoll on linux and whatever the
+//! platform offers elsewhere. Readiness is edge triggered, a socket that is reported ready has to
+//! be read or written until it would block, otherwise it is not reported again. Connections that
+//! are not sockets, like those of the in-memory transport, report their readiness by hand through
+//! a `Notifier` instead, by the same rules.
+//!
+//! Connecting to a peer does not hold the node up either. The socket is registered as soon as
+//! connecting starts, it is reported ready once the connection has opened or failed, and a
+//! connection that is still not open after `CONNECT_TIMEOUT` is given up on.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mio::event::Source;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::driver::{Driver, OpenedStream, CONNECT_TIMEOUT};
+use crate::transport::{Listener, Transport};
+
+#[cfg(test)]
+mod tests;
+
+/// The token of the waker. The sockets get the tokens after it.
+const WAKER_TOKEN: Token = Token(0);
+
+/// How many readiness events are picked up per wait. More are simply left for the next wait.
+const EVENTS_LEN: usize = 256;
+
+pub(crate) struct Reactor {
+    poll: Poll,
+    events: Events,
+    waker: Arc<Waker>,
+    /// The tokens reported ready through a `Notifier` since the last wait.
+    notified: Arc<Mutex<HashSet<Token>>>,
+    next_token: usize,
+}
+
+impl Reactor {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        Ok(Reactor {
+            poll,
+            events: Events::with_capacity(EVENTS_LEN),
+            waker,
+            notified: Arc::new(Mutex::new(HashSet::new())),
+            next_token: WAKER_TOKEN.0 + 1,
+        })
+    }
+
+    /// What reports the readiness of sources the reactor does not watch itself.
+    pub(crate) fn notifier(&self) -> Notifier {
+        Notifier {
+            waker: self.waker.clone(),
+            notified: self.notified.clone(),
+        }
+    }
+
+    /// A token no source has been given yet.
+    pub(crate) fn next_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    /// The waker that ends a `wait` from another thread, for the handle to call when it has
+    /// sent the node a command.
+    pub(crate) fn waker(&self) -> Arc<Waker> {
+        self.waker.clone()
+    }
+
+    /// Watch `source` for `interest` and return the token its readiness is reported with. A
+    /// source is forgotten by the reactor once it is dropped.
+    pub(crate) fn register(
+        &mut self,
+        source: &mut impl Source,
+        interest: Interest,
+    ) -> std::io::Result<Token> {
+        let token = self.next_token();
+        self.poll.registry().register(source, token, interest)?;
+        Ok(token)
+    }
+
+    /// Sleep until a registered source is ready, a source is reported ready through a
+    /// `Notifier`, the waker is woken or `wakeup` has come. Returns the tokens of the sources that
+    /// are ready, which may be none.
+    pub(crate) fn wait(&mut self, wakeup: &Wakeup) -> std::io::Result<HashSet<Token>> {
+        let timeout = if self.notified.lock().unwrap().is_empty() {
+            wakeup
+                .0
+                .map(|instant| instant.saturating_duration_since(Instant::now()))
+        } else {
+            // a notifier wakes the waker too, but that may have been before the last wait
+            Some(Duration::ZERO)
+        };
+        match self.poll.poll(&mut self.events, timeout) {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+        let mut ready: HashSet<Token> = self
+            .events
+            .iter()
+            .map(|event| event.token())
+            .filter(|token| *token != WAKER_TOKEN)
+            .collect();
+        ready.extend(self.notified.lock().unwrap().drain());
+        Ok(ready)
+    }
+}
+
+impl std::fmt::Debug for Reactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reactor")
+            .field("next_token", &self.next_token)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Reports sources ready to the `Reactor` it came from, from any thread.
+#[derive(Debug, Clone)]
+pub(crate) struct Notifier {
+    waker: Arc<Waker>,
+    notified: Arc<Mutex<HashSet<Token>>>,
+}
+
+impl Notifier {
+    /// Have the reactor report `token` ready on its current or next wait.
+    pub(crate) fn notify(&self, token: Token) {
+        self.notified.lock().unwrap().insert(token);
+        // the reactor only fails to be woken if it is gone, and then nobody is waiting
+        let _ = self.waker.wake();
+    }
+}
+
+/// A connection a `MioDriver` is opening.
+#[derive(Debug)]
+struct Dialing<S> {
+    stream: S,
+    addr: SocketAddr,
+    deadline: Instant,
+    /// Whether the stream was reported ready since connecting was last found not to be over.
+    ready: bool,
+}
+
+impl<S> Dialing<S> {
+    /// Whether connecting is over, and how it went. `None` while it is still going on.
+    fn finish(&mut self, transport: &impl Transport<Stream = S>) -> Option<std::io::Result<()>> {
+        if std::mem::take(&mut self.ready) {
+            match transport.finish_dial(&self.stream) {
+                Ok(true) => return Some(Ok(())),
+                Ok(false) => {}
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        if Instant::now() >= self.deadline {
+            return Some(Err(std::io::ErrorKind::TimedOut.into()));
+        }
+        None
+    }
+}
+
+/// Runs a node on its own thread, with its connections carried by the transport `T` and watched
+/// by a `Reactor`.
+#[derive(Debug)]
+pub(crate) struct MioDriver<T: Transport> {
+    reactor: Reactor,
+    transport: T,
+    listener: T::Listener,
+    listener_token: Token,
+    /// Whether connections may be waiting on the listener. It is only reported ready again once
+    /// all of them have been accepted.
+    listener_ready: bool,
+    dialing: HashMap<Token, Dialing<T::Stream>>,
+}
+
+impl<T: Transport> MioDriver<T> {
+    /// Listen on `bind_addr` with `transport`.
+    pub(crate) fn bind(transport: T, bind_addr: SocketAddr) -> std::io::Result<Self> {
+        let mut reactor = Reactor::new()?;
+        let (listener, listener_token) = transport.listen(bind_addr, &mut reactor)?;
+        Ok(MioDriver {
+            reactor,
+            transport,
+            listener,
+            listener_token,
+            listener_ready: false,
+            dialing: HashMap::new(),
+        })
+    }
+
+    /// The waker that ends a `wait` from another thread, see `Reactor::waker`.
+    pub(crate) fn waker(&self) -> Arc<Waker> {
+        self.reactor.waker()
+    }
+
+    fn open(&mut self, stream: T::Stream, remote_addr: SocketAddr) -> std::io::Result<OpenedStream> {
+        let mut stream = stream;
+        let token = self.transport.register(&mut stream, &mut self.reactor)?;
+        Ok(OpenedStream {
+            stream: Box::new(stream),
+            token,
+            remote_addr,
+        })
+    }
+}
+
+impl<T: Transport> Driver for MioDriver<T> {
+    fn local_addr(&self) -> SocketAddr {
+        self.listener
+            .local_addr()
+            .expect("failed to get listener local address")
+    }
+
+    fn accept(&mut self) -> Vec<OpenedStream> {
+        let mut accepted = Vec::new();
+        while self.listener_ready {
+            match self.listener.accept() {
+                Ok((stream, remote_addr)) => match self.open(stream, remote_addr) {
+                    Ok(opened) => accepted.push(opened),
+                    Err(error) => eprintln!(
+                        "{}: Failed to take in the connection from {}: {}",
+                        self.local_addr(),
+                        remote_addr,
+                        error
+                    ),
+                },
+                Err(error) => {
+                    if error.kind() != std::io::ErrorKind::WouldBlock {
+                        eprintln!("There was a accept error : {}", error);
+                    }
+                    self.listener_ready = false;
+                }
+            }
+        }
+        accepted
+    }
+
+    /// The stream is watched from the start, it is reported ready once connecting is over and
+    /// then keeps its token.
+    fn dial(&mut self, addr: &SocketAddr) -> std::io::Result<Token> {
+        let mut stream = self.transport.dial(addr)?;
+        let token = self.transport.register(&mut stream, &mut self.reactor)?;
+        let dialing = Dialing {
+            stream,
+            addr: *addr,
+            deadline: Instant::now() + CONNECT_TIMEOUT,
+            ready: false,
+        };
+        self.dialing.insert(token, dialing);
+        Ok(token)
+    }
+
+    fn dialed(&mut self) -> Vec<(Token, std::io::Result<OpenedStream>)> {
+        let finished: Vec<(Token, std::io::Result<()>)> = self
+            .dialing
+            .iter_mut()
+            .filter_map(|(token, dialing)| Some((*token, dialing.finish(&self.transport)?)))
+            .collect();
+        finished
+            .into_iter()
+            .map(|(token, result)| {
+                let dialing = self.dialing.remove(&token).expect("the dial was just found");
+                let opened = result.map(|()| OpenedStream {
+                    stream: Box::new(dialing.stream),
+                    token,
+                    remote_addr: dialing.addr,
+                });
+                (token, opened)
+            })
+            .collect()
+    }
+
+    async fn wait(&mut self, wakeup: &Wakeup) -> std::io::Result<HashSet<Token>> {
+        let mut ready = self.reactor.wait(wakeup)?;
+        if ready.remove(&self.listener_token) {
+            self.listener_ready = true;
+        }
+        for (token, dialing) in self.dialing.iter_mut() {
+            dialing.ready |= ready.contains(token);
+        }
+        Ok(ready)
+    }
+}
+
+/// The earliest instant a node has something to do at, collected from all of its timers. `None`
+/// means the node only has to wake up when something happens.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Wakeup(pub(crate) Option<Instant>);
+
+impl Wakeup {
+    /// Make sure the node wakes up at `instant`, or earlier.
+    pub(crate) fn at(&mut self, instant: Instant) {
+        self.0 = Some(self.0.map_or(instant, |earliest| earliest.min(instant)));
+    }
+
+    /// Make sure the node does not sleep at all.
+    pub(crate) fn now(&mut self) {
+        self.at(Instant::now());
+    }
+}
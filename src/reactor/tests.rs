@@ -0,0 +1,46 @@
+use super::*;
+
+use std::io::Write;
+use std::time::Duration;
+
+#[test]
+fn wakeup_test() {
+    let start = Instant::now();
+    let mut wakeup = Wakeup::default();
+    assert_eq!(wakeup.0, None);
+    wakeup.at(start + Duration::from_secs(2));
+    wakeup.at(start + Duration::from_secs(1));
+    wakeup.at(start + Duration::from_secs(3));
+    assert_eq!(wakeup.0, Some(start + Duration::from_secs(1)));
+}
+
+/// The reactor sleeps until its timer runs out, unless a socket gets ready or it is woken first.
+#[test]
+fn wait_test() {
+    let mut reactor = Reactor::new().unwrap();
+    let mut listener = mio::net::TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let token = reactor.register(&mut listener, Interest::READABLE).unwrap();
+
+    let mut wakeup = Wakeup::default();
+    let start = Instant::now();
+    wakeup.at(start + Duration::from_millis(100));
+    assert!(reactor.wait(&wakeup).unwrap().is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    let mut stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    stream.write_all(b"ready").unwrap();
+    let start = Instant::now();
+    let ready = reactor.wait(&Wakeup::default()).unwrap();
+    assert_eq!(ready, HashSet::from([token]));
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let waker = reactor.waker();
+    let thread = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        waker.wake().unwrap();
+    });
+    let start = Instant::now();
+    assert!(reactor.wait(&Wakeup::default()).unwrap().is_empty());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    thread.join().unwrap();
+}
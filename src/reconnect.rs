@@ -0,0 +1,165 @@
+//! Reconnecting to peers whose connection failed.
+//!
+//! A peer whose connection breaks is most likely to be back soon, after a restart or once the
+//! network between the two recovers, and it was in the active view for a reason. So instead of
+//! forgetting it the node remembers it and connects to it again, waiting longer after every
+//! attempt that does not bring it back, see `Backoff`. After a configured number of attempts the
+//! node gives up on it and leaves filling its active view to the passive view.
+//!
+//! Peers that were let go on purpose, because they broke protocol or were moved to the passive
+//! view, are never reconnected to.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::backoff::Backoff;
+use crate::identity::NodeId;
+use crate::protocol::PeerInfo;
+use crate::ReconnectingPeer;
+
+#[cfg(test)]
+mod tests;
+
+/// How long after losing a peer the node first tries to reconnect to it. The wait doubles with
+/// every attempt.
+const RECONNECT_RETRY_INITIAL: Duration = Duration::from_millis(500);
+
+/// The longest the node waits between two attempts to reconnect to a peer.
+const RECONNECT_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// The most lost peers remembered at once. Past it the peer that was lost first is given up on.
+const LOST_PEERS_LEN_MAX: usize = 64;
+
+#[derive(Debug)]
+struct LostPeer {
+    addr: SocketAddr,
+    lost_instant: Instant,
+    attempts: u32,
+    backoff: Backoff,
+    next_attempt: Instant,
+}
+
+/// What became of a lost peer whose next attempt was due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reconnect {
+    /// Connect to the peer, this is the `attempt`th time.
+    Attempt { peer: PeerInfo, attempt: u32 },
+    /// None of the `attempts` brought the peer back, it is forgotten.
+    GiveUp { peer: PeerInfo, attempts: u32 },
+}
+
+#[derive(Debug)]
+pub(crate) struct Reconnector {
+    max_attempts: u32,
+    lost: HashMap<NodeId, LostPeer>,
+}
+
+impl Reconnector {
+    /// A reconnector that tries every lost peer up to `max_attempts` times, none at all if it is 0.
+    pub(crate) fn new(max_attempts: u32) -> Self {
+        Reconnector {
+            max_attempts,
+            lost: HashMap::new(),
+        }
+    }
+
+    /// Note that the connection to `peer` failed, so that it is reconnected to.
+    pub(crate) fn lost(&mut self, peer: PeerInfo, now: Instant) {
+        if self.max_attempts == 0 {
+            return;
+        }
+        if self.lost.len() >= LOST_PEERS_LEN_MAX && !self.lost.contains_key(&peer.node_id) {
+            let first_lost = self
+                .lost
+                .iter()
+                .min_by_key(|(_, lost)| lost.lost_instant)
+                .map(|(node_id, _)| *node_id);
+            if let Some(first_lost) = first_lost {
+                self.lost.remove(&first_lost);
+            }
+        }
+        let mut backoff = Backoff::new(RECONNECT_RETRY_INITIAL, RECONNECT_RETRY_MAX);
+        let next_attempt = now + backoff.next_delay();
+        self.lost.insert(
+            peer.node_id,
+            LostPeer {
+                addr: peer.addr,
+                lost_instant: now,
+                attempts: 0,
+                backoff,
+                next_attempt,
+            },
+        );
+    }
+
+    /// Forget about `node_id`, the node does not want it back.
+    pub(crate) fn forget(&mut self, node_id: &NodeId) {
+        self.lost.remove(node_id);
+    }
+
+    /// The lost peers whose next attempt is due. Peers that are `connected` again, whether
+    /// through an earlier attempt or because they connected themselves, are forgotten, and
+    /// peers that are `connecting` wait for that to work out first. Every attempt counts, the
+    /// next one is only due after a longer wait, or the peer is given up on then.
+    pub(crate) fn due(
+        &mut self,
+        now: Instant,
+        connected: impl Fn(&NodeId) -> bool,
+        connecting: impl Fn(&NodeId) -> bool,
+    ) -> Vec<Reconnect> {
+        let mut due = Vec::new();
+        let max_attempts = self.max_attempts;
+        self.lost.retain(|node_id, lost| {
+            if connected(node_id) {
+                return false;
+            }
+            if now < lost.next_attempt || connecting(node_id) {
+                return true;
+            }
+            let peer = PeerInfo {
+                node_id: *node_id,
+                addr: lost.addr,
+            };
+            if lost.attempts >= max_attempts {
+                due.push(Reconnect::GiveUp {
+                    peer,
+                    attempts: lost.attempts,
+                });
+                return false;
+            }
+            lost.attempts += 1;
+            lost.next_attempt = now + lost.backoff.next_delay();
+            due.push(Reconnect::Attempt {
+                peer,
+                attempt: lost.attempts,
+            });
+            true
+        });
+        due
+    }
+
+    /// When the next attempt, or giving up on a peer, is due. Peers that are `connecting` are
+    /// left out, like in `due`. `None` if no other peer is lost.
+    pub(crate) fn next_attempt(&self, connecting: impl Fn(&NodeId) -> bool) -> Option<Instant> {
+        self.lost
+            .iter()
+            .filter(|(node_id, _)| !connecting(node_id))
+            .map(|(_, lost)| lost.next_attempt)
+            .min()
+    }
+
+    /// The lost peers that are still being reconnected to, the ones lost first first.
+    pub(crate) fn reconnecting_peers(&self, now: Instant) -> Vec<ReconnectingPeer> {
+        let mut lost: Vec<(&NodeId, &LostPeer)> = self.lost.iter().collect();
+        lost.sort_by_key(|(_, lost)| lost.lost_instant);
+        lost.into_iter()
+            .map(|(node_id, lost)| ReconnectingPeer {
+                node_id: *node_id,
+                addr: lost.addr,
+                attempts: lost.attempts,
+                next_attempt_in: lost.next_attempt.saturating_duration_since(now),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,66 @@
+use super::*;
+use crate::identity::Identity;
+
+fn peer(port: u16) -> PeerInfo {
+    PeerInfo {
+        node_id: Identity::generate().node_id(),
+        addr: SocketAddr::from(([127, 0, 0, 1], port)),
+    }
+}
+
+#[test]
+fn give_up_test() {
+    let start = Instant::now();
+    let later = |secs| start + Duration::from_secs(secs);
+    let mut reconnector = Reconnector::new(2);
+    let lost = peer(1);
+    reconnector.lost(lost, start);
+    assert_eq!(reconnector.due(start, |_| false, |_| false), []);
+    assert_eq!(reconnector.reconnecting_peers(start)[0].attempts, 0);
+    assert!(reconnector.next_attempt(|_| false).is_some_and(|instant| instant > start));
+    assert_eq!(reconnector.next_attempt(|_| true), None);
+
+    assert_eq!(
+        reconnector.due(later(60), |_| false, |_| false),
+        [Reconnect::Attempt { peer: lost, attempt: 1 }]
+    );
+    // an attempt is not due again right away
+    assert_eq!(reconnector.due(later(60), |_| false, |_| false), []);
+    // nor while the connection it started is still going
+    assert_eq!(reconnector.due(later(120), |_| false, |_| true), []);
+    assert_eq!(
+        reconnector.due(later(120), |_| false, |_| false),
+        [Reconnect::Attempt { peer: lost, attempt: 2 }]
+    );
+    let reconnecting = reconnector.reconnecting_peers(later(120));
+    assert_eq!(reconnecting.len(), 1);
+    assert_eq!(reconnecting[0].attempts, 2);
+    assert!(reconnecting[0].next_attempt_in > Duration::ZERO);
+
+    assert_eq!(
+        reconnector.due(later(180), |_| false, |_| false),
+        [Reconnect::GiveUp { peer: lost, attempts: 2 }]
+    );
+    assert_eq!(reconnector.reconnecting_peers(later(180)), []);
+    assert_eq!(reconnector.next_attempt(|_| false), None);
+}
+
+#[test]
+fn reconnected_test() {
+    let start = Instant::now();
+    let mut reconnector = Reconnector::new(5);
+    let (back, forgotten) = (peer(1), peer(2));
+    reconnector.lost(back, start);
+    reconnector.lost(forgotten, start);
+    reconnector.forget(&forgotten.node_id);
+    // a peer that is connected again is done with
+    assert_eq!(
+        reconnector.due(start + Duration::from_secs(60), |node_id| *node_id == back.node_id, |_| false),
+        []
+    );
+    assert_eq!(reconnector.reconnecting_peers(start), []);
+
+    let mut disabled = Reconnector::new(0);
+    disabled.lost(back, start);
+    assert_eq!(disabled.reconnecting_peers(start), []);
+}
@@ -0,0 +1,217 @@
+//! Remembering which gossip a node has already heard, so that it is delivered and relayed only
+//! once. Gossip is remembered for a retention time and forgotten after it, a copy that arrives
+//! later than that is taken for fresh gossip again.
+//!
+//! `ExactSeenCache` remembers every id it is given and never mistakes one gossip for another, but
+//! its memory grows with the traffic. `BloomSeenCache` uses a fixed amount of memory whatever the
+//! traffic, at the price of now and then taking fresh gossip for gossip it has already heard.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+use crate::protocol::MessageId;
+
+#[cfg(test)]
+mod tests;
+
+/// The gossip ids a node has heard within the retention time.
+pub(crate) trait SeenCache {
+    /// Whether `id` has been inserted and not forgotten since.
+    fn contains(&self, id: &MessageId) -> bool;
+
+    /// Remember `id`, which was heard at `now`.
+    fn insert(&mut self, id: MessageId, now: Instant);
+
+    /// Forget what has been remembered for longer than the retention time. Called once per loop
+    /// iteration, so it has to be cheap when there is nothing to forget.
+    fn expire(&mut self, now: Instant);
+}
+
+/// Remembers every id exactly, for exactly the retention time.
+#[derive(Debug)]
+pub(crate) struct ExactSeenCache {
+    retention: Duration,
+    heard: HashMap<MessageId, Instant>,
+    /// The ids in the order they were inserted in, which is also the order they expire in.
+    expiry_queue: VecDeque<(Instant, MessageId)>,
+}
+
+impl ExactSeenCache {
+    pub(crate) fn new(retention: Duration) -> Self {
+        ExactSeenCache {
+            retention,
+            heard: HashMap::new(),
+            expiry_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl SeenCache for ExactSeenCache {
+    fn contains(&self, id: &MessageId) -> bool {
+        self.heard.contains_key(id)
+    }
+
+    fn insert(&mut self, id: MessageId, now: Instant) {
+        if self.heard.insert(id, now).is_none() {
+            self.expiry_queue.push_back((now, id));
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(heard_instant, id)) = self.expiry_queue.front() {
+            if now.duration_since(heard_instant) <= self.retention {
+                break;
+            }
+            self.expiry_queue.pop_front();
+            self.heard.remove(&id);
+        }
+    }
+}
+
+/// How many bloom filters a `BloomSeenCache` rotates through. The oldest one is dropped every
+/// retention time divided by one less than this, so that gossip is forgotten between one and
+/// `BLOOM_GENERATIONS / (BLOOM_GENERATIONS - 1)` retention times after it was heard.
+const BLOOM_GENERATIONS: usize = 5;
+
+/// A plain bloom filter over the two hashes `BloomSeenCache` computes for an id.
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+    /// How many ids were inserted, and how many it can take before its false positive rate rises
+    /// above the one it was sized for.
+    len: usize,
+    capacity: usize,
+}
+
+impl BloomFilter {
+    /// A filter that holds `capacity` ids with a false positive rate of `false_positive_rate`.
+    fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let ln_2 = std::f64::consts::LN_2;
+        let bit_count =
+            (-(capacity as f64) * false_positive_rate.ln() / (ln_2 * ln_2)).ceil().max(64.0) as u64;
+        let hash_count = ((bit_count as f64 / capacity as f64) * ln_2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; bit_count.div_ceil(64) as usize],
+            bit_count,
+            hash_count,
+            len: 0,
+            capacity,
+        }
+    }
+
+    /// The bits an id with the hashes `(h1, h2)` sets, by double hashing.
+    fn bit_indices(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = u64> + '_ {
+        (0..self.hash_count as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.bit_count)
+    }
+
+    fn contains(&self, hashes: (u64, u64)) -> bool {
+        self.bit_indices(hashes)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, hashes: (u64, u64)) {
+        let bits: Vec<u64> = self.bit_indices(hashes).collect();
+        for bit in bits {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    fn clear(&mut self) {
+        self.bits.fill(0);
+        self.len = 0;
+    }
+}
+
+/// Remembers ids in a ring of bloom filters. New ids go into the newest filter and every
+/// `retention / (BLOOM_GENERATIONS - 1)` the oldest filter is cleared and becomes the newest, so
+/// expiring costs nothing most of the time and the memory used never changes.
+///
+/// The cache is sized for `capacity` ids per retention time. If more arrive the newest filter
+/// fills up early and the filters rotate sooner, so the false positive rate holds but gossip is
+/// forgotten before the retention time is up.
+#[derive(Debug)]
+pub(crate) struct BloomSeenCache {
+    rotation_period: Duration,
+    /// The filters, newest first.
+    filters: VecDeque<BloomFilter>,
+    last_rotation: Instant,
+    /// Keyed randomly for every cache, so that nobody can make up ids that collide on purpose.
+    hash_builder: RandomState,
+}
+
+impl BloomSeenCache {
+    /// A cache for `capacity` ids per `retention` that takes fresh gossip for heard gossip with a
+    /// probability of at most `false_positive_rate`.
+    pub(crate) fn new(
+        retention: Duration,
+        capacity: usize,
+        false_positive_rate: f64,
+        now: Instant,
+    ) -> Self {
+        let active_generations = BLOOM_GENERATIONS - 1;
+        // an id is looked up in every filter, any of which may give a false positive
+        let filter_false_positive_rate = false_positive_rate / BLOOM_GENERATIONS as f64;
+        let filter_capacity = capacity.div_ceil(active_generations).max(1);
+        BloomSeenCache {
+            rotation_period: retention / active_generations as u32,
+            filters: (0..BLOOM_GENERATIONS)
+                .map(|_| BloomFilter::new(filter_capacity, filter_false_positive_rate))
+                .collect(),
+            last_rotation: now,
+            hash_builder: RandomState::new(),
+        }
+    }
+
+    fn hashes(&self, id: &MessageId) -> (u64, u64) {
+        let h1 = self.hash_builder.hash_one((id, 0u8));
+        // a step of zero would have every hash function pick the same bit
+        let h2 = self.hash_builder.hash_one((id, 1u8)) | 1;
+        (h1, h2)
+    }
+
+    /// Forget the oldest filter and start filling a new one.
+    fn rotate(&mut self) {
+        let mut oldest = self.filters.pop_back().expect("there is always more than one filter");
+        oldest.clear();
+        self.filters.push_front(oldest);
+    }
+}
+
+impl SeenCache for BloomSeenCache {
+    fn contains(&self, id: &MessageId) -> bool {
+        let hashes = self.hashes(id);
+        self.filters.iter().any(|filter| filter.contains(hashes))
+    }
+
+    fn insert(&mut self, id: MessageId, now: Instant) {
+        if self.filters[0].is_full() {
+            self.rotate();
+            self.last_rotation = now;
+        }
+        let hashes = self.hashes(&id);
+        self.filters[0].insert(hashes);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        // after a long pause there can be several rotations to catch up on, but never more than
+        // it takes to forget everything
+        for _ in 0..BLOOM_GENERATIONS {
+            if now.duration_since(self.last_rotation) < self.rotation_period {
+                return;
+            }
+            self.rotate();
+            self.last_rotation += self.rotation_period;
+        }
+        self.last_rotation = now;
+    }
+}
@@ -0,0 +1,91 @@
+use super::*;
+use crate::identity::NodeId;
+
+fn id(seqno: u64) -> MessageId {
+    MessageId {
+        origin: NodeId::from_bytes([0x42; 32]),
+        seqno,
+    }
+}
+
+#[test]
+fn exact_seen_cache_test() {
+    let retention = Duration::from_secs(10);
+    let start = Instant::now();
+    let mut cache = ExactSeenCache::new(retention);
+    cache.insert(id(1), start);
+    cache.insert(id(2), start + Duration::from_secs(5));
+    assert!(cache.contains(&id(1)) && cache.contains(&id(2)));
+    assert!(!cache.contains(&id(3)));
+
+    cache.expire(start + retention);
+    assert!(cache.contains(&id(1)));
+    cache.expire(start + retention + Duration::from_secs(1));
+    assert!(!cache.contains(&id(1)) && cache.contains(&id(2)));
+    cache.expire(start + retention * 2);
+    assert!(!cache.contains(&id(2)));
+    assert!(cache.heard.is_empty() && cache.expiry_queue.is_empty());
+}
+
+#[test]
+fn bloom_seen_cache_expiry_test() {
+    let retention = Duration::from_secs(40);
+    let start = Instant::now();
+    let mut cache = BloomSeenCache::new(retention, 1000, 0.001, start);
+    cache.insert(id(1), start);
+
+    // remembered for the whole retention time, checked every loop iteration
+    let mut now = start;
+    while now < start + retention {
+        cache.expire(now);
+        assert!(cache.contains(&id(1)));
+        now += Duration::from_millis(500);
+    }
+    cache.expire(start + retention * BLOOM_GENERATIONS as u32 / (BLOOM_GENERATIONS as u32 - 1));
+    assert!(!cache.contains(&id(1)));
+
+    // a long pause forgets everything at once
+    let now = start + retention * 2;
+    cache.insert(id(2), now);
+    cache.expire(now + retention * 100);
+    assert!(!cache.contains(&id(2)));
+    cache.insert(id(3), now + retention * 100);
+    assert!(cache.contains(&id(3)));
+}
+
+/// Fills the cache up to its capacity and counts how many unknown ids it claims to have heard.
+#[test]
+fn bloom_seen_cache_false_positive_test() {
+    let capacity = 10_000;
+    let false_positive_rate = 0.01;
+    let start = Instant::now();
+    let mut cache = BloomSeenCache::new(Duration::from_secs(40), capacity, false_positive_rate, start);
+    let memory_len = |cache: &BloomSeenCache| -> usize {
+        cache.filters.iter().map(|filter| filter.bits.len()).sum()
+    };
+    let initial_memory_len = memory_len(&cache);
+
+    for seqno in 0..capacity as u64 {
+        cache.insert(id(seqno), start);
+    }
+    assert!((0..capacity as u64).all(|seqno| cache.contains(&id(seqno))));
+    let false_positives = (capacity as u64..capacity as u64 + 100_000)
+        .filter(|&seqno| cache.contains(&id(seqno)))
+        .count();
+    assert!(
+        false_positives < 100_000 / 100 * 2,
+        "{} false positives",
+        false_positives
+    );
+
+    // more than it is sized for only makes it forget early
+    for seqno in 0..capacity as u64 * 10 {
+        cache.insert(id(seqno), start);
+    }
+    assert_eq!(memory_len(&cache), initial_memory_len);
+    assert!(cache.contains(&id(capacity as u64 * 10 - 1)));
+    let still_known = (0..capacity as u64)
+        .filter(|&seqno| cache.contains(&id(seqno)))
+        .count();
+    assert!(still_known < capacity / 50, "{} ids still known", still_known);
+}
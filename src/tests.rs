@@ -1,109 +1,137 @@
+use crate::node::gossip_to_hex;
 use crate::*;
 
 use std::time::Duration;
 
-use std::net::IpAddr;
-use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::mpsc;
+
+use rand::seq::SliceRandom;
+
+/// Start a node listening on `port` of the loopback address. Panics if the node fails to bind
+/// its listener.
+fn start_node(
+    use_ipv6: bool,
+    port: u16,
+    bootstrap_peer: Option<SocketAddr>,
+    lifetime: Duration,
+) -> GossipNode {
+    let ip: IpAddr = if use_ipv6 {
+        "::1".parse().unwrap()
+    } else {
+        "127.0.0.1".parse().unwrap()
+    };
+    let mut builder = GossipNode::builder()
+        .bind_addr(SocketAddr::new(ip, port))
+        .lifetime(lifetime);
+    if let Some(bootstrap_peer) = bootstrap_peer {
+        builder = builder.bootstrap_peer(bootstrap_peer);
+    }
+    builder.spawn().unwrap()
+}
+
+/// Wait until `events` tells of a peer connecting, so that what the node publishes next has
+/// somewhere to go. Nodes join the network in the background once they are spawned.
+fn wait_for_peer(events: &mpsc::Receiver<NodeEvent>) {
+    let connected = events
+        .iter()
+        .any(|event| matches!(event, NodeEvent::PeerConnected { .. }));
+    assert!(connected);
+}
+
+/// Publish a random gossip on `node` once every `period` until `duration` has passed. Returns the
+/// gossips that were published.
+fn publish_random_gossip(node: &GossipNode, period: Duration, duration: Duration) -> Vec<Vec<u8>> {
+    let mut sent_gossips = Vec::new();
+    let start = std::time::Instant::now();
+    while start.elapsed() < duration {
+        std::thread::sleep(period);
+        let gossip: Vec<u8> = (0..10).map(|_| rand::random()).collect();
+        node.publish(gossip.clone()).unwrap();
+        sent_gossips.push(gossip);
+    }
+    sent_gossips
+}
+
+/// Wait for `node` to stop and collect all the gossip payloads it received from `events`.
+fn received_gossip(node: GossipNode, events: mpsc::Receiver<NodeEvent>) -> Vec<Vec<u8>> {
+    node.join().unwrap();
+    events
+        .into_iter()
+        .filter_map(|event| match event {
+            NodeEvent::GossipReceived { payload, .. } => Some(payload),
+            _ => None,
+        })
+        .collect()
+}
 
 /// This test is used to make sure the networks peer discovery and disconnect handling works.
 /// It does this by building a network shaped like a chain, every node is initially connected
 /// to only 2 others. The two edge nodes are used to test the network, one will send gossip,
 /// the other will record the gossip it "hears". During the test non edge nodes are taken offline
 /// without notice to the other peers until the network only has two peers left, the edge peers.
-/// This is done using the `self_destruct_time` optional argument to `do_peer`.
+/// This is done using the `lifetime` of the nodes.
 ///
-/// Since one node is sending and one node is receiving we can simplify the problem of testing
-/// to gossip *awareness*. Passing `should_use_gossip_awareness` as true on the edge nodes causes
-/// them to accumulate the gossips they send and receive in the associated `gossip_awareness` array.
-/// The two awareness arrays of the edge nodes are compared at the end and if the receiving node has
-/// not received all the sent gossips the test fails.
+/// The sending edge node publishes a gossip every second and keeps track of what it has sent. The
+/// receiving edge node is subscribed to and the gossip it receives is compared to the sent gossip
+/// at the end. If the receiving node has not received all the sent gossips the test fails.
 ///
-/// If any of the nodes either fails to start listening on a tcp port or fails to connect
-/// to their initial peer there will be a panic and the test will fail. As is the nature with these
-/// things, the tests could fail because the ports are *in use* by another process on the machine.
-///
-/// It may be wise to move away panics and instead simply assert a Result type returned from do_peer.
+/// If any of the nodes fails to start listening on a tcp port there will be a panic and the test
+/// will fail, so the test can fail because its ports are *in use* by another process on the
+/// machine.
 #[test]
 fn dying_chain_ipv4_test() {
     let base_port = 11400;
-    std::thread::spawn(move || {
-        do_peer(
-            false,
-            Duration::from_secs(2000),
-            base_port + 1,
-            None,
-            Some(Duration::from_secs(30)),
-            &mut Vec::new(),
-            false,
-        ).unwrap();
-    });
+    let localhost = IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap());
+    let mut middle_nodes = Vec::new();
+    middle_nodes.push(start_node(
+        false,
+        base_port + 1,
+        None,
+        Duration::from_secs(30),
+    ));
 
     std::thread::sleep(Duration::from_millis(20));
 
     let middle_count = 13u16;
     for i in 1..middle_count {
-        std::thread::spawn(move || {
-            do_peer(
-                false,
-                Duration::from_secs(2000),
-                base_port + 1 + i,
-                Some(&SocketAddr::new(
-                    IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
-                    base_port + i,
-                )),
-                Some(Duration::from_secs(30)),
-                &mut Vec::new(),
-                false,
-            ).unwrap();
-        });
+        middle_nodes.push(start_node(
+            false,
+            base_port + 1 + i,
+            Some(SocketAddr::new(localhost, base_port + i)),
+            Duration::from_secs(30),
+        ));
         std::thread::sleep(Duration::from_millis(1500));
     }
 
-    let received_gossips = Arc::new(Mutex::new(Vec::new()));
-    let received_gossips2 = received_gossips.clone();
-
-    std::thread::spawn(move || {
-        let mut array = received_gossips.lock().unwrap();
-        do_peer(
-            false,
-            Duration::from_secs(2000),
-            base_port + 1 + middle_count,
-            Some(&SocketAddr::new(
-                IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
-                base_port + middle_count,
-            )),
-            Some(Duration::from_secs(35)),
-            &mut *array,
-            true,
-        ).unwrap();
-    });
+    let receiving_node = start_node(
+        false,
+        base_port + 1 + middle_count,
+        Some(SocketAddr::new(localhost, base_port + middle_count)),
+        Duration::from_secs(35),
+    );
+    let events = receiving_node.subscribe();
 
     std::thread::sleep(Duration::from_millis(800));
 
-    let mut sent_gossips = Vec::new();
-
-    do_peer(
+    let sending_node = start_node(
         false,
-        Duration::from_secs(1),
         base_port,
-        Some(&SocketAddr::new(
-            IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
-            base_port + 1,
-        )),
-        Some(Duration::from_secs(30)),
-        &mut sent_gossips,
-        true,
-    ).unwrap();
+        Some(SocketAddr::new(localhost, base_port + 1)),
+        Duration::from_secs(30),
+    );
+    let sent_gossips = publish_random_gossip(
+        &sending_node,
+        Duration::from_secs(1),
+        Duration::from_secs(29),
+    );
+    sending_node.join().unwrap();
 
-    let array = received_gossips2.lock().unwrap();
+    let received_gossips = received_gossip(receiving_node, events);
     for gossip in sent_gossips
     {
-        assert!(array.contains(&gossip));
-        let mut s = String::with_capacity(2 * GOSSIP_LEN);
-        for byte in gossip.iter() {
-            write!(s, "{:02X}", byte).unwrap();
-        }
-        println!("0x{} was sent and received", s);
+        assert!(received_gossips.contains(&gossip));
+        println!("{} was sent and received", gossip_to_hex(&gossip));
     }
 }
 
@@ -113,64 +141,1296 @@ fn dying_chain_ipv4_test() {
 #[test]
 fn three_way_ipv6_test() {
     let base_port = 11500;
-    std::thread::spawn(move || {
-        do_peer(
-            true,
-            Duration::from_secs(2000),
-            base_port + 1,
-            None,
-            Some(Duration::from_secs(20)),
-            &mut Vec::new(),
-            false,
-        ).unwrap();
-    });
+    let localhost = IpAddr::from("::1".parse::<Ipv6Addr>().unwrap());
+    let _middle_node = start_node(true, base_port + 1, None, Duration::from_secs(20));
 
     std::thread::sleep(Duration::from_millis(20));
 
-    let received_gossips = Arc::new(Mutex::new(Vec::new()));
-    let received_gossips2 = received_gossips.clone();
-
-    std::thread::spawn(move || {
-        let mut array = received_gossips.lock().unwrap();
-        do_peer(
-            true,
-            Duration::from_secs(2000),
-            base_port + 1 + 1,
-            Some(&SocketAddr::new(
-                IpAddr::from("::1".parse::<Ipv6Addr>().unwrap()),
-                base_port + 1,
-            )),
-            Some(Duration::from_secs(20)),
-            &mut *array,
-            true,
-        ).unwrap();
-    });
+    let receiving_node = start_node(
+        true,
+        base_port + 1 + 1,
+        Some(SocketAddr::new(localhost, base_port + 1)),
+        Duration::from_secs(20),
+    );
+    let events = receiving_node.subscribe();
 
     std::thread::sleep(Duration::from_millis(300));
 
-    let mut sent_gossips = Vec::new();
-
-    do_peer(
+    let sending_node = start_node(
         true,
-        Duration::from_secs(1),
         base_port,
-        Some(&SocketAddr::new(
-            IpAddr::from("::1".parse::<Ipv6Addr>().unwrap()),
-            base_port + 1,
-        )),
-        Some(Duration::from_secs(15)),
-        &mut sent_gossips,
-        true,
-    ).unwrap();
+        Some(SocketAddr::new(localhost, base_port + 1)),
+        Duration::from_secs(15),
+    );
+    let sent_gossips = publish_random_gossip(
+        &sending_node,
+        Duration::from_secs(1),
+        Duration::from_secs(14),
+    );
+    sending_node.join().unwrap();
 
-    let array = received_gossips2.lock().unwrap();
+    let received_gossips = received_gossip(receiving_node, events);
     for gossip in sent_gossips
     {
-        assert!(array.contains(&gossip));
-        let mut s = String::with_capacity(2 * GOSSIP_LEN);
-        for byte in gossip.iter() {
-            write!(s, "{:02X}", byte).unwrap();
+        assert!(received_gossips.contains(&gossip));
+        println!("{} was sent and received", gossip_to_hex(&gossip));
+    }
+}
+
+/// Checks that gossip handed to `GossipNode::publish` reaches the other peers, in order and with
+/// the right sender, and that the connection shows up in the event stream before the gossip.
+#[test]
+fn publish_test() {
+    let base_port = 11600;
+    let localhost = IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap());
+    let receiving_node = start_node(false, base_port, None, Duration::from_secs(4));
+    let events = receiving_node.subscribe();
+
+    let sending_node = start_node(
+        false,
+        base_port + 1,
+        Some(SocketAddr::new(localhost, base_port)),
+        Duration::from_secs(10),
+    );
+    wait_for_peer(&sending_node.subscribe());
+
+    // the same payload twice is two separate gossips
+    let gossips = vec![
+        b"first news".to_vec(),
+        vec![0xAB; 3000],
+        Vec::new(),
+        b"first news".to_vec(),
+    ];
+    let ids: Vec<MessageId> = gossips
+        .iter()
+        .map(|gossip| sending_node.publish(gossip.clone()).unwrap())
+        .collect();
+    assert!(ids.iter().all(|id| id.origin == sending_node.node_id()));
+    assert!(sending_node.publish(vec![0; DEFAULT_MAX_GOSSIP_LEN + 1]).is_err());
+
+    receiving_node.join().unwrap();
+    let events: Vec<NodeEvent> = events.into_iter().collect();
+    let sender_addr = sending_node.local_addr();
+    assert_eq!(
+        events[0],
+        NodeEvent::PeerConnected {
+            node_id: sending_node.node_id(),
+            addr: sender_addr
+        }
+    );
+    let expected_events: Vec<NodeEvent> = gossips
+        .into_iter()
+        .zip(ids)
+        .map(|(payload, id)| NodeEvent::GossipReceived {
+            payload,
+            id,
+            from: sending_node.node_id(),
+            hops: 0,
+        })
+        .collect();
+    assert_eq!(events[1..], expected_events);
+}
+
+/// Connects to a node over a plain socket and checks the handshake answers, both for a hello the
+/// node can work with and for one from a peer with no protocol version in common with it.
+#[test]
+fn version_negotiation_test() {
+    use crate::protocol::*;
+
+    let node = start_node(false, 11700, None, Duration::from_secs(5));
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    let handshake = |min_version, max_version| {
+        let mut stream = std::net::TcpStream::connect(node.local_addr()).unwrap();
+        let hello = Hello {
+            min_version,
+            max_version,
+            capabilities: u32::MAX,
+            listen_addr: "127.0.0.1:11701".parse().unwrap(),
+            node_id: Identity::generate().node_id(),
+            nonce: [0; NONCE_LEN],
+        };
+        write_hello(&mut stream, &hello).unwrap();
+        decode(&mut stream, &limits).unwrap()
+    };
+
+    match handshake(PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX + 10) {
+        Message::Confirm {
+            version,
+            capabilities,
+            ..
+        // the node floods gossip, so it does not claim the plumtree capability
+        } => assert_eq!((version, capabilities), (PROTOCOL_VERSION_MAX, 0)),
+        message => panic!("expected a confirmation, got {:?}", message),
+    }
+    match handshake(PROTOCOL_VERSION_MAX + 1, PROTOCOL_VERSION_MAX + 10) {
+        Message::Reject { reason } => assert!(reason.contains("no common protocol version")),
+        message => panic!("expected a rejection, got {:?}", message),
+    }
+}
+
+/// Connect to `node` over a plain socket and go through the plaintext handshake by hand, claiming
+/// to be `identity` but signing the `Auth` with `auth_identity`. Checks that the node proves it
+/// owns its node id on the way.
+fn connect_raw_peer(
+    node: &GossipNode,
+    identity: &Identity,
+    auth_identity: &Identity,
+) -> std::net::TcpStream {
+    use crate::protocol::*;
+
+    let mut stream = std::net::TcpStream::connect(node.local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    let hello = Hello {
+        min_version: PROTOCOL_VERSION_MIN,
+        max_version: PROTOCOL_VERSION_MAX,
+        capabilities: CAPABILITIES,
+        listen_addr: "127.0.0.1:1".parse().unwrap(),
+        node_id: identity.node_id(),
+        nonce: rand::random(),
+    };
+    write_hello(&mut stream, &hello).unwrap();
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    let (node_id, nonce, signature) = match decode(&mut stream, &limits).unwrap() {
+        Message::Confirm {
+            node_id,
+            nonce,
+            signature,
+            ..
+        } => (node_id, nonce, signature),
+        message => panic!("expected a confirmation, got {:?}", message),
+    };
+    assert_eq!(node_id, node.node_id());
+    assert!(node_id.verify(&confirm_signed_data(&hello.nonce, &nonce, &[]), &signature));
+
+    let auth = Message::Auth {
+        signature: auth_identity.sign(&auth_signed_data(&hello.nonce, &nonce, &[])),
+    };
+    encode(&mut stream, &auth).unwrap();
+    stream
+}
+
+/// Checks that a peer that fails to prove it owns its node id is disconnected while one that
+/// succeeds gets answers to its requests.
+#[test]
+fn authentication_test() {
+    use crate::protocol::*;
+    use std::io::Read;
+
+    let node = start_node(false, 11800, None, Duration::from_secs(5));
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    let identity = Identity::generate();
+
+    let mut stream = connect_raw_peer(&node, &identity, &identity);
+    encode(&mut stream, &Message::Shuffle { peers: Vec::new() }).unwrap();
+    assert_eq!(
+        decode(&mut stream, &limits).unwrap(),
+        Message::ShuffleReply { peers: Vec::new() }
+    );
+
+    let mut stream = connect_raw_peer(&node, &identity, &Identity::generate());
+    encode(&mut stream, &Message::Shuffle { peers: Vec::new() }).unwrap();
+    let mut buf = [0; 1];
+    assert!(!matches!(stream.read(&mut buf), Ok(1)));
+}
+
+/// Sends a node gossip that is not signed by its claimed origin. The node must not pass it on and
+/// must drop the peer that relayed it.
+#[test]
+fn forged_gossip_test() {
+    use crate::protocol::*;
+
+    let node = start_node(false, 12000, None, Duration::from_secs(3));
+    let events = node.subscribe();
+    let identity = Identity::generate();
+    let mut stream = connect_raw_peer(&node, &identity, &identity);
+
+    let honest = Gossip::sign(&identity, 1, 0, b"honest news".to_vec());
+    encode(&mut stream, &Message::Gossip(honest.clone())).unwrap();
+    let mut forged = Gossip::sign(&identity, 2, 0, b"forged news".to_vec());
+    forged.origin = node.node_id();
+    encode(&mut stream, &Message::Gossip(forged)).unwrap();
+    let late = Gossip::sign(&identity, 3, 0, b"late news".to_vec());
+    let _ = encode(&mut stream, &Message::Gossip(late));
+
+    node.join().unwrap();
+    let events: Vec<NodeEvent> = events.into_iter().collect();
+    let peer_addr = "127.0.0.1:1".parse().unwrap();
+    assert_eq!(
+        events,
+        [
+            NodeEvent::PeerConnected {
+                node_id: identity.node_id(),
+                addr: peer_addr
+            },
+            NodeEvent::GossipReceived {
+                payload: b"honest news".to_vec(),
+                id: honest.id(),
+                from: identity.node_id(),
+                hops: 0
+            },
+            NodeEvent::PeerDisconnected {
+                node_id: identity.node_id(),
+                addr: peer_addr
+            }
+        ]
+    );
+}
+
+/// Checks that two nodes requiring encryption talk to each other, and that a node requiring
+/// encryption turns away peers that connect in plaintext.
+#[test]
+fn encryption_test() {
+    use crate::protocol::*;
+
+    let base_port = 11900;
+    let localhost = IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap());
+    let start_node = |port, bootstrap_peer: Option<u16>, encryption| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::new(localhost, port))
+            .encryption(encryption)
+            .lifetime(Duration::from_secs(4));
+        if let Some(bootstrap_port) = bootstrap_peer {
+            builder = builder.bootstrap_peer(SocketAddr::new(localhost, bootstrap_port));
+        }
+        builder.spawn().unwrap()
+    };
+
+    let receiving_node = start_node(base_port, None, Encryption::Required);
+    let events = receiving_node.subscribe();
+    let sending_node = start_node(base_port + 1, Some(base_port), Encryption::Required);
+    wait_for_peer(&sending_node.subscribe());
+    let plaintext_node = start_node(base_port + 2, Some(base_port), Encryption::Off);
+    let secret_id = sending_node.publish("secret news").unwrap();
+    plaintext_node.publish("plain news").unwrap();
+
+    let mut stream = std::net::TcpStream::connect(receiving_node.local_addr()).unwrap();
+    let hello = Hello {
+        min_version: PROTOCOL_VERSION_MIN,
+        max_version: PROTOCOL_VERSION_MAX,
+        capabilities: CAPABILITIES,
+        listen_addr: SocketAddr::new(localhost, base_port + 3),
+        node_id: Identity::generate().node_id(),
+        nonce: [0; NONCE_LEN],
+    };
+    write_hello(&mut stream, &hello).unwrap();
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    match decode(&mut stream, &limits).unwrap() {
+        Message::Reject { reason } => assert!(reason.contains("encryption is required")),
+        message => panic!("expected a rejection, got {:?}", message),
+    }
+
+    receiving_node.join().unwrap();
+    let events: Vec<NodeEvent> = events.into_iter().collect();
+    assert_eq!(
+        events,
+        [
+            NodeEvent::PeerConnected {
+                node_id: sending_node.node_id(),
+                addr: sending_node.local_addr()
+            },
+            NodeEvent::GossipReceived {
+                payload: b"secret news".to_vec(),
+                id: secret_id,
+                from: sending_node.node_id(),
+                hops: 0
+            }
+        ]
+    );
+}
+
+/// Injects gossip into one end of a two node network and checks that it only gets relayed while
+/// its time to live lasts, and that the hops are counted on the way.
+#[test]
+fn gossip_ttl_test() {
+    use crate::protocol::*;
+
+    let base_port = 12100;
+    let localhost = IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap());
+    let relaying_node = start_node(false, base_port, None, Duration::from_secs(4));
+    let relaying_events = relaying_node.subscribe();
+    let receiving_node = start_node(
+        false,
+        base_port + 1,
+        Some(SocketAddr::new(localhost, base_port)),
+        Duration::from_secs(4),
+    );
+    let receiving_events = receiving_node.subscribe();
+    wait_for_peer(&receiving_events);
+
+    let identity = Identity::generate();
+    let mut stream = connect_raw_peer(&relaying_node, &identity, &identity);
+    let gossips = [
+        Gossip::sign(&identity, 1, 0, b"stops here".to_vec()),
+        Gossip::sign(&identity, 2, 1, b"goes one further".to_vec()),
+    ];
+    for gossip in &gossips {
+        encode(&mut stream, &Message::Gossip(gossip.clone())).unwrap();
+    }
+
+    let received_gossip = |node: GossipNode, events: mpsc::Receiver<NodeEvent>| {
+        node.join().unwrap();
+        events
+            .into_iter()
+            .filter(|event| matches!(event, NodeEvent::GossipReceived { .. }))
+            .collect::<Vec<NodeEvent>>()
+    };
+    let relaying_node_id = relaying_node.node_id();
+    assert_eq!(
+        received_gossip(relaying_node, relaying_events),
+        gossips
+            .iter()
+            .map(|gossip| NodeEvent::GossipReceived {
+                payload: gossip.payload.clone(),
+                id: gossip.id(),
+                from: identity.node_id(),
+                hops: 0,
+            })
+            .collect::<Vec<NodeEvent>>()
+    );
+    assert_eq!(
+        received_gossip(receiving_node, receiving_events),
+        [NodeEvent::GossipReceived {
+            payload: b"goes one further".to_vec(),
+            id: gossips[1].id(),
+            from: relaying_node_id,
+            hops: 1,
+        }]
+    );
+}
+
+/// Builds a triangle of nodes using bloom filter duplicate suppression, so that every gossip
+/// reaches each node over two paths, and checks that every gossip is still delivered exactly once.
+#[test]
+fn bloom_duplicate_suppression_test() {
+    let base_port = 12200;
+    let localhost = IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap());
+    let bloom = DuplicateSuppression::Bloom {
+        capacity: 1000,
+        false_positive_rate: 0.0001,
+    };
+    let start_node = |port: u16, bootstrap_port: Option<u16>| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::new(localhost, port))
+            .duplicate_suppression(bloom)
+            .lifetime(Duration::from_secs(8));
+        if let Some(bootstrap_port) = bootstrap_port {
+            builder = builder.bootstrap_peer(SocketAddr::new(localhost, bootstrap_port));
         }
-        println!("0x{} was sent and received", s);
+        builder.spawn().unwrap()
+    };
+
+    let receiving_node = start_node(base_port, None);
+    let events = receiving_node.subscribe();
+    let _relaying_node = start_node(base_port + 1, Some(base_port));
+    let sending_node = start_node(base_port + 2, Some(base_port + 1));
+    // the sending node finds the receiving node through the relaying node
+    std::thread::sleep(Duration::from_secs(3));
+    let sent_gossips =
+        publish_random_gossip(&sending_node, Duration::from_millis(50), Duration::from_secs(2));
+    assert_eq!(received_gossip(receiving_node, events), sent_gossips);
+
+    let error = GossipNode::builder()
+        .duplicate_suppression(DuplicateSuppression::Bloom {
+            capacity: 1000,
+            false_positive_rate: 1.5,
+        })
+        .spawn()
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// Has one raw peer send gossip to a node that excludes the sender when forwarding, and checks
+/// that the gossip goes to the other raw peer but does not come back to the one that sent it.
+#[test]
+fn exclude_sender_test() {
+    use crate::protocol::*;
+
+    let node = GossipNode::builder()
+        .bind_addr("127.0.0.1:12300".parse().unwrap())
+        .forwarding_strategy(ForwardingStrategy::ExcludeSender)
+        .lifetime(Duration::from_secs(3))
+        .spawn()
+        .unwrap();
+    let sending_identity = Identity::generate();
+    let mut sending_stream = connect_raw_peer(&node, &sending_identity, &sending_identity);
+    let receiving_identity = Identity::generate();
+    let mut receiving_stream = connect_raw_peer(&node, &receiving_identity, &receiving_identity);
+    // give the node time to finish both handshakes
+    std::thread::sleep(Duration::from_millis(500));
+
+    let gossip = Gossip::sign(&sending_identity, 1, 5, b"no echo".to_vec());
+    encode(&mut sending_stream, &Message::Gossip(gossip.clone())).unwrap();
+
+    // read until the node stops and closes the connection
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    let received_gossip = |stream: &mut std::net::TcpStream| {
+        let mut gossips = Vec::new();
+        while let Ok(message) = decode(stream, &limits) {
+            if let Message::Gossip(gossip) = message {
+                gossips.push(gossip.id());
+            }
+        }
+        gossips
+    };
+    assert_eq!(received_gossip(&mut receiving_stream), [gossip.id()]);
+    assert_eq!(received_gossip(&mut sending_stream), []);
+    node.join().unwrap();
+}
+
+/// Runs a fully meshed network of plumtree nodes and takes one of them offline halfway. Every
+/// gossip must reach the receiving node exactly once, before and after.
+#[test]
+fn plumtree_test() {
+    let base_port = 12400;
+    let localhost = IpAddr::from("127.0.0.1".parse::<Ipv4Addr>().unwrap());
+    let start_node = |port: u16, bootstrap_port: Option<u16>| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::new(localhost, port))
+            .forwarding_strategy(ForwardingStrategy::Plumtree)
+            .lifetime(Duration::from_secs(12));
+        if let Some(bootstrap_port) = bootstrap_port {
+            builder = builder.bootstrap_peer(SocketAddr::new(localhost, bootstrap_port));
+        }
+        builder.spawn().unwrap()
+    };
+
+    let receiving_node = start_node(base_port, None);
+    let events = receiving_node.subscribe();
+    let relaying_nodes = [
+        start_node(base_port + 1, Some(base_port)),
+        start_node(base_port + 2, Some(base_port + 1)),
+    ];
+    let sending_node = start_node(base_port + 3, Some(base_port + 2));
+    // let everyone find everyone else
+    std::thread::sleep(Duration::from_secs(3));
+
+    let period = Duration::from_millis(50);
+    let mut sent_gossips = publish_random_gossip(&sending_node, period, Duration::from_secs(2));
+    let [first_relaying_node, _second_relaying_node] = relaying_nodes;
+    drop(first_relaying_node);
+    sent_gossips.extend(publish_random_gossip(&sending_node, period, Duration::from_secs(3)));
+
+    let mut received_gossips = received_gossip(receiving_node, events);
+    sent_gossips.sort();
+    received_gossips.sort();
+    assert_eq!(received_gossips, sent_gossips);
+}
+
+/// Plays a plumtree peer over a plain socket and checks that the node prunes a peer that sends it
+/// a copy, only announces gossip to it afterwards, grafts gossip it announces and answers its
+/// grafts.
+#[test]
+fn plumtree_protocol_test() {
+    use crate::protocol::*;
+
+    let node = GossipNode::builder()
+        .bind_addr("127.0.0.1:12500".parse().unwrap())
+        .forwarding_strategy(ForwardingStrategy::Plumtree)
+        .lifetime(Duration::from_secs(5))
+        .spawn()
+        .unwrap();
+    let events = node.subscribe();
+    let identity = Identity::generate();
+    let mut stream = connect_raw_peer(&node, &identity, &identity);
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    let next_message = |stream: &mut std::net::TcpStream| loop {
+        match decode(stream, &limits).unwrap() {
+            Message::Shuffle { .. } => continue,
+            message => return message,
+        }
+    };
+
+    let pushed = Gossip::sign(&identity, 1, 5, b"pushed".to_vec());
+    encode(&mut stream, &Message::Gossip(pushed.clone())).unwrap();
+    encode(&mut stream, &Message::Gossip(pushed.clone())).unwrap();
+    assert_eq!(next_message(&mut stream), Message::Prune);
+
+    let published = node.publish("published").unwrap();
+    assert_eq!(
+        next_message(&mut stream),
+        Message::IHave {
+            ids: vec![published]
+        }
+    );
+
+    let announced = Gossip::sign(&identity, 2, 5, b"announced".to_vec());
+    encode(
+        &mut stream,
+        &Message::IHave {
+            ids: vec![announced.id()],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        next_message(&mut stream),
+        Message::Graft {
+            ids: vec![announced.id()]
+        }
+    );
+    encode(&mut stream, &Message::Gossip(announced.clone())).unwrap();
+
+    encode(
+        &mut stream,
+        &Message::Graft {
+            ids: vec![published],
+        },
+    )
+    .unwrap();
+    match next_message(&mut stream) {
+        Message::Gossip(gossip) => assert_eq!(gossip.id(), published),
+        message => panic!("expected the grafted gossip, got {:?}", message),
+    }
+
+    node.join().unwrap();
+    let received_ids: Vec<MessageId> = events
+        .into_iter()
+        .filter_map(|event| match event {
+            NodeEvent::GossipReceived { id, .. } => Some(id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received_ids, [pushed.id(), announced.id()]);
+}
+
+/// Publishes gossip on a node before anybody is connected to it and checks that a node joining
+/// later still receives it through anti-entropy, exactly once.
+#[test]
+fn anti_entropy_test() {
+    let base_port = 12600;
+    let start_node = |port: u16, bootstrap_port: Option<u16>| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::from(([127, 0, 0, 1], port)))
+            .anti_entropy_period(Duration::from_millis(200))
+            .lifetime(Duration::from_secs(4));
+        if let Some(bootstrap_port) = bootstrap_port {
+            builder = builder.bootstrap_peer(SocketAddr::from(([127, 0, 0, 1], bootstrap_port)));
+        }
+        builder.spawn().unwrap()
+    };
+
+    let sending_node = start_node(base_port, None);
+    let missed_ids: Vec<MessageId> = (0..3)
+        .map(|i| sending_node.publish(format!("missed news {}", i)).unwrap())
+        .collect();
+    // give the node time to take in what it published
+    std::thread::sleep(Duration::from_millis(200));
+
+    let receiving_node = start_node(base_port + 1, Some(base_port));
+    let events = receiving_node.subscribe();
+    receiving_node.join().unwrap();
+    let mut received_ids: Vec<MessageId> = events
+        .into_iter()
+        .filter_map(|event| match event {
+            NodeEvent::GossipReceived { id, .. } => Some(id),
+            _ => None,
+        })
+        .collect();
+    received_ids.sort();
+    assert_eq!(received_ids, missed_ids);
+}
+
+/// Has every node of a network join through the same node, with active views too small to hold
+/// all the others, and checks that no node ends up with more connections than its active view
+/// allows while gossip still reaches every node.
+#[test]
+fn bounded_active_view_test() {
+    let base_port = 12700;
+    let node_count = 8;
+    let active_view_size = 3;
+    let start_node = |port: u16, bootstrap_port: Option<u16>| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::from(([127, 0, 0, 1], port)))
+            .active_view_size(active_view_size)
+            .lifetime(Duration::from_secs(8));
+        if let Some(bootstrap_port) = bootstrap_port {
+            builder = builder.bootstrap_peer(SocketAddr::from(([127, 0, 0, 1], bootstrap_port)));
+        }
+        builder.spawn().unwrap()
+    };
+
+    let mut nodes = vec![start_node(base_port, None)];
+    let mut events = vec![nodes[0].subscribe()];
+    for i in 1..node_count {
+        nodes.push(start_node(base_port + i, Some(base_port)));
+        events.push(nodes.last().unwrap().subscribe());
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // let the views settle
+    std::thread::sleep(Duration::from_secs(3));
+    let publishing_node = nodes.pop().unwrap();
+    let published = publishing_node.publish("bounded news").unwrap();
+
+    for (node, events) in nodes.into_iter().zip(events) {
+        node.join().unwrap();
+        let mut peer_count = 0i32;
+        let mut received = false;
+        for event in events {
+            match event {
+                NodeEvent::PeerConnected { .. } => peer_count += 1,
+                NodeEvent::PeerDisconnected { .. } => peer_count -= 1,
+                NodeEvent::GossipReceived { id, .. } => received |= id == published,
+                _ => {}
+            }
+            assert!(peer_count <= active_view_size as i32);
+        }
+        assert!(received);
+    }
+}
+
+/// Fills the only inbound connection a node allows and checks that the next peer to connect is
+/// told the node is busy and pointed to the peer holding the connection instead, and that a node
+/// bootstrapping off the busy node joins the network through the peers it suggests. A limit of
+/// zero connections is refused when the node is started.
+#[test]
+fn connection_limit_test() {
+    use crate::protocol::*;
+
+    let base_port = 12800;
+    let start_node = |port: u16, bootstrap_peer: Option<SocketAddr>, max_inbound| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::from(([127, 0, 0, 1], port)))
+            .max_inbound(max_inbound)
+            .lifetime(Duration::from_secs(4));
+        if let Some(bootstrap_peer) = bootstrap_peer {
+            builder = builder.bootstrap_peer(bootstrap_peer);
+        }
+        builder.spawn().unwrap()
+    };
+    let busy_node = start_node(base_port, None, 1);
+    let connected_node = start_node(base_port + 1, Some(busy_node.local_addr()), 10);
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut stream = std::net::TcpStream::connect(busy_node.local_addr()).unwrap();
+    let hello = Hello {
+        min_version: PROTOCOL_VERSION_MIN,
+        max_version: PROTOCOL_VERSION_MAX,
+        capabilities: CAPABILITIES,
+        listen_addr: "127.0.0.1:1".parse().unwrap(),
+        node_id: Identity::generate().node_id(),
+        nonce: rand::random(),
+    };
+    write_hello(&mut stream, &hello).unwrap();
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    let connected_peer = PeerInfo {
+        node_id: connected_node.node_id(),
+        addr: connected_node.local_addr(),
+    };
+    assert_eq!(
+        decode(&mut stream, &limits).unwrap(),
+        Message::Busy {
+            peers: vec![connected_peer]
+        }
+    );
+    let mut buf = [0; 1];
+    assert!(!matches!(std::io::Read::read(&mut stream, &mut buf), Ok(1)));
+
+    let late_node = start_node(base_port + 2, Some(busy_node.local_addr()), 10);
+    let events = late_node.subscribe();
+    late_node.join().unwrap();
+    let connected_addrs: Vec<SocketAddr> = events
+        .into_iter()
+        .filter_map(|event| match event {
+            NodeEvent::PeerConnected { addr, .. } => Some(addr),
+            _ => None,
+        })
+        .collect();
+    // the busy node may connect out to the late node later on, once it learns about it
+    assert_eq!(connected_addrs[0], connected_node.local_addr());
+
+    for builder in [
+        GossipNode::builder().max_inbound(0),
+        GossipNode::builder().max_outbound(0),
+        GossipNode::builder().max_total(0),
+    ] {
+        assert_eq!(builder.spawn().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+/// Restarts a node with the address book it kept the first time around and a bootstrap peer that
+/// is down, and checks that it rejoins the network through the peer it remembers.
+#[test]
+fn address_book_test() {
+    let base_port = 12900;
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let path = std::env::temp_dir().join(format!(
+        "p2p_gossip_address_book_integration_test_{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let identity = Identity::generate();
+    let start_restarting_node = |port: u16, bootstrap_port: u16| {
+        GossipNode::builder()
+            .bind_addr(SocketAddr::new(localhost, port))
+            .bootstrap_peer(SocketAddr::new(localhost, bootstrap_port))
+            .identity(identity.clone())
+            .address_book(&path)
+            .lifetime(Duration::from_secs(2))
+            .spawn()
+    };
+
+    let seed_node = start_node(false, base_port, None, Duration::from_secs(6));
+    start_restarting_node(base_port + 1, base_port).unwrap().join().unwrap();
+    let book = std::fs::read_to_string(&path).unwrap();
+    assert!(book.contains(&seed_node.node_id().to_string()));
+
+    // nothing listens on the bootstrap port this time
+    let restarted_node = start_restarting_node(base_port + 2, base_port + 3).unwrap();
+    let events = restarted_node.subscribe();
+    restarted_node.join().unwrap();
+    assert!(events.into_iter().any(|event| matches!(
+        event,
+        NodeEvent::PeerConnected { node_id, .. } if node_id == seed_node.node_id()
+    )));
+
+    seed_node.shutdown();
+    seed_node.join().unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Starts a node whose bootstrap peers are all down and checks that it keeps running and gets into
+/// the network once one of them comes up.
+#[test]
+fn bootstrap_retry_test() {
+    let base_port = 13000;
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let joining_node = GossipNode::builder()
+        .bind_addr(SocketAddr::new(localhost, base_port))
+        .bootstrap_peer(SocketAddr::new(localhost, base_port + 1))
+        .bootstrap_peer(SocketAddr::new(localhost, base_port + 2))
+        .lifetime(Duration::from_secs(5))
+        .spawn()
+        .unwrap();
+    let events = joining_node.subscribe();
+
+    std::thread::sleep(Duration::from_secs(1));
+    let seed_node = start_node(false, base_port + 2, None, Duration::from_secs(5));
+    joining_node.join().unwrap();
+    assert!(events.into_iter().any(|event| matches!(
+        event,
+        NodeEvent::PeerConnected { node_id, .. } if node_id == seed_node.node_id()
+    )));
+}
+
+/// Stops two peers of a node, brings one of them back on the same address and checks that the
+/// node reconnects to it, while it gives up on the other after its configured attempts.
+#[test]
+fn reconnect_test() {
+    let base_port = 13100;
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let watching_node = GossipNode::builder()
+        .bind_addr(SocketAddr::new(localhost, base_port))
+        .reconnect_attempts(2)
+        // without a passive view the lost peers are not replaced with others
+        .passive_view_size(0)
+        .lifetime(Duration::from_secs(7))
+        .spawn()
+        .unwrap();
+    let events = watching_node.subscribe();
+
+    let identity = Identity::generate();
+    let start_returning_node = |bootstrap_peer: Option<SocketAddr>, lifetime| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::new(localhost, base_port + 1))
+            .identity(identity.clone())
+            .lifetime(lifetime);
+        if let Some(bootstrap_peer) = bootstrap_peer {
+            builder = builder.bootstrap_peer(bootstrap_peer);
+        }
+        builder.spawn().unwrap()
+    };
+    let returning_node = start_returning_node(Some(watching_node.local_addr()), Duration::from_secs(1));
+    let gone_node = start_node(
+        false,
+        base_port + 2,
+        Some(watching_node.local_addr()),
+        Duration::from_secs(1),
+    );
+    let gone_node_id = gone_node.node_id();
+    returning_node.join().unwrap();
+    gone_node.join().unwrap();
+
+    std::thread::sleep(Duration::from_millis(100));
+    let stats = watching_node.stats().unwrap();
+    let mut reconnecting: Vec<NodeId> = stats
+        .reconnecting_peers
+        .iter()
+        .map(|peer| peer.node_id)
+        .collect();
+    reconnecting.sort();
+    let mut expected = vec![identity.node_id(), gone_node_id];
+    expected.sort();
+    assert_eq!(reconnecting, expected);
+
+    let _returned_node = start_returning_node(None, Duration::from_secs(6));
+    watching_node.join().unwrap();
+    let events: Vec<NodeEvent> = events.into_iter().collect();
+    let returned_connections = events
+        .iter()
+        .filter(|event| matches!(
+            event,
+            NodeEvent::PeerConnected { node_id, .. } if *node_id == identity.node_id()
+        ))
+        .count();
+    assert_eq!(returned_connections, 2);
+    let gone_addr = SocketAddr::new(localhost, base_port + 2);
+    let gone_node_events: Vec<&NodeEvent> = events
+        .iter()
+        .filter(|event| match event {
+            NodeEvent::ReconnectAttempt { node_id, .. } | NodeEvent::ReconnectGaveUp { node_id, .. } => {
+                *node_id == gone_node_id
+            }
+            _ => false,
+        })
+        .collect();
+    assert_eq!(
+        gone_node_events,
+        [
+            &NodeEvent::ReconnectAttempt {
+                node_id: gone_node_id,
+                addr: gone_addr,
+                attempt: 1
+            },
+            &NodeEvent::ReconnectAttempt {
+                node_id: gone_node_id,
+                addr: gone_addr,
+                attempt: 2
+            },
+            &NodeEvent::ReconnectGaveUp {
+                node_id: gone_node_id,
+                addr: gone_addr,
+                attempts: 2
+            },
+        ]
+    );
+    assert!(!events.iter().any(|event| matches!(
+        event,
+        NodeEvent::ReconnectGaveUp { node_id, .. } if *node_id == identity.node_id()
+    )));
+}
+
+/// Checks that a node answers its handle and its peers right away instead of when its loop comes
+/// around next. What is measured does not involve signatures, which take milliseconds to verify
+/// in debug builds.
+#[test]
+fn wakeup_latency_test() {
+    use crate::protocol::*;
+
+    let node = start_node(false, 13200, None, Duration::from_secs(5));
+    let median = |mut latencies: Vec<Duration>| {
+        latencies.sort();
+        latencies[latencies.len() / 2]
+    };
+
+    let mut latencies = Vec::new();
+    for _ in 0..20 {
+        std::thread::sleep(Duration::from_millis(20));
+        let start = std::time::Instant::now();
+        node.stats().unwrap();
+        latencies.push(start.elapsed());
+    }
+    let stats_latency = median(latencies);
+    assert!(stats_latency < Duration::from_millis(2), "stats took {:?}", stats_latency);
+
+    let identity = Identity::generate();
+    let mut stream = connect_raw_peer(&node, &identity, &identity);
+    let limits = DecodeLimits {
+        max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+    };
+    let mut latencies = Vec::new();
+    for _ in 0..20 {
+        std::thread::sleep(Duration::from_millis(20));
+        let start = std::time::Instant::now();
+        encode(&mut stream, &Message::Shuffle { peers: Vec::new() }).unwrap();
+        loop {
+            match decode(&mut stream, &limits).unwrap() {
+                Message::ShuffleReply { .. } => break,
+                _ => continue,
+            }
+        }
+        latencies.push(start.elapsed());
+    }
+    let shuffle_latency = median(latencies);
+    assert!(shuffle_latency < Duration::from_millis(2), "shuffles took {:?}", shuffle_latency);
+}
+
+/// A listener that never accepts and whose backlog is full, so that connecting to it hangs until
+/// the connection times out, like connecting to an address that drops everything. Returns the
+/// listener with the connections filling its backlog.
+fn blackhole() -> (std::net::TcpListener, Vec<std::net::TcpStream>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut backlog = Vec::new();
+    while let Ok(stream) = std::net::TcpStream::connect_timeout(&addr, Duration::from_millis(200)) {
+        backlog.push(stream);
+    }
+    (listener, backlog)
+}
+
+/// A node that is told of a peer nobody answers for keeps gossiping with the peer it already has
+/// while it connects, instead of waiting for the connection to time out.
+#[test]
+fn unreachable_peer_test() {
+    use crate::protocol::*;
+
+    let base_port = 13500;
+    let (blackhole, _backlog) = blackhole();
+    let node = start_node(false, base_port, None, Duration::from_secs(8));
+    let receiving_node = start_node(
+        false,
+        base_port + 1,
+        Some(node.local_addr()),
+        Duration::from_secs(8),
+    );
+    let events = receiving_node.subscribe();
+    wait_for_peer(&events);
+
+    // the walk ends at the node, which connects to the unreachable peer itself
+    let identity = Identity::generate();
+    let mut stream = connect_raw_peer(&node, &identity, &identity);
+    let unreachable_peer = PeerInfo {
+        node_id: Identity::generate().node_id(),
+        addr: blackhole.local_addr().unwrap(),
+    };
+    encode(
+        &mut stream,
+        &Message::ForwardJoin {
+            peer: unreachable_peer,
+            ttl: 0,
+        },
+    )
+    .unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+
+    let start = std::time::Instant::now();
+    node.publish("still flowing").unwrap();
+    assert!(gossip_received_within(&events, b"still flowing", Duration::from_secs(2)));
+    node.stats().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+/// A gossip of almost the maximum length. A couple of hundred of them are more than a peer that
+/// does not read takes in its socket buffers.
+fn big_gossip(index: usize) -> Vec<u8> {
+    let mut gossip = vec![index as u8; DEFAULT_MAX_GOSSIP_LEN - 100];
+    gossip[..8].copy_from_slice(&rand::random::<[u8; 8]>());
+    gossip
+}
+
+/// A peer that stops reading is dropped once its send queue is full, while the node's other peers
+/// keep getting everything.
+#[test]
+fn drop_slow_peer_test() {
+    let base_port = 13400;
+    let node = GossipNode::builder()
+        .bind_addr(SocketAddr::from(([127, 0, 0, 1], base_port)))
+        .send_queue_high_water_mark(256 * 1024)
+        .send_queue_policy(SendQueuePolicy::DropPeer)
+        .lifetime(Duration::from_secs(60))
+        .spawn()
+        .unwrap();
+    let events = node.subscribe();
+    let reader = start_node(false, base_port + 1, Some(node.local_addr()), Duration::from_secs(60));
+    let reader_events = reader.subscribe();
+    let identity = Identity::generate();
+    let _slow_stream = connect_raw_peer(&node, &identity, &identity);
+    std::thread::sleep(Duration::from_secs(1));
+
+    // every gossip is published once the reader has the one before, so only the slow peer falls
+    // behind
+    let mut slow_peer_dropped = false;
+    for index in 0..200 {
+        let gossip = big_gossip(index);
+        node.publish(gossip.clone()).unwrap();
+        let arrived = reader_events
+            .iter()
+            .any(|event| matches!(event, NodeEvent::GossipReceived { payload, .. } if payload == gossip));
+        assert!(arrived);
+        slow_peer_dropped |= events.try_iter().any(|event| {
+            matches!(event, NodeEvent::PeerDisconnected { node_id, .. } if node_id == identity.node_id())
+        });
+        if slow_peer_dropped {
+            break;
+        }
+    }
+    assert!(slow_peer_dropped);
+}
+
+/// With backpressure a node stops answering its other peers while a peer does not read what it is
+/// sent, until it drops that peer because its queue stays full for too long.
+#[test]
+fn backpressure_test() {
+    use crate::protocol::*;
+
+    let node = GossipNode::builder()
+        .bind_addr(SocketAddr::from(([127, 0, 0, 1], 13410)))
+        .send_queue_high_water_mark(256 * 1024)
+        .send_queue_policy(SendQueuePolicy::Backpressure)
+        .lifetime(Duration::from_secs(30))
+        .spawn()
+        .unwrap();
+    let events = node.subscribe();
+    let identity = Identity::generate();
+    let stream = connect_raw_peer(&node, &identity, &identity);
+    stream.set_read_timeout(Some(Duration::from_secs(20))).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    // the peer reads everything it is sent and notes when the answer to its shuffle comes
+    let reading = std::thread::spawn(move || {
+        let mut stream = stream;
+        let limits = DecodeLimits {
+            max_gossip_len: DEFAULT_MAX_GOSSIP_LEN,
+        };
+        loop {
+            if let Message::ShuffleReply { .. } = decode(&mut stream, &limits).unwrap() {
+                return std::time::Instant::now();
+            }
+        }
+    });
+    let slow_identity = Identity::generate();
+    let _slow_stream = connect_raw_peer(&node, &slow_identity, &slow_identity);
+    std::thread::sleep(Duration::from_secs(1));
+
+    for index in 0..200 {
+        node.publish(big_gossip(index)).unwrap();
+    }
+    let start = std::time::Instant::now();
+    encode(&mut writer, &Message::Shuffle { peers: Vec::new() }).unwrap();
+    let answered = reading.join().unwrap();
+    assert!(answered.duration_since(start) > Duration::from_secs(5));
+    let slow_peer_dropped = events.iter().any(|event| {
+        matches!(event, NodeEvent::PeerDisconnected { node_id, .. } if node_id == slow_identity.node_id())
+    });
+    assert!(slow_peer_dropped);
+}
+
+/// Wait until `events` delivers gossip with `payload`, without blocking the runtime. Returns
+/// whether it did within `timeout`.
+#[cfg(feature = "async")]
+async fn gossip_arrives(
+    events: &mpsc::Receiver<NodeEvent>,
+    payload: &[u8],
+    timeout: Duration,
+) -> bool {
+    let start = std::time::Instant::now();
+    while start.elapsed() < timeout {
+        match events.try_recv() {
+            Ok(NodeEvent::GossipReceived { payload: received, .. }) if received == payload => {
+                return true
+            }
+            Ok(_) => {}
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+    false
+}
+
+/// Wait until `events` delivers gossip with `payload`. Returns whether it did within `timeout`.
+#[cfg(feature = "async")]
+async fn async_gossip_arrives(
+    events: &mut tokio::sync::mpsc::UnboundedReceiver<NodeEvent>,
+    payload: &[u8],
+    timeout: Duration,
+) -> bool {
+    let arrives = async {
+        while let Some(event) = events.recv().await {
+            if matches!(event, NodeEvent::GossipReceived { payload: ref received, .. } if received == payload)
+            {
+                return true;
+            }
+        }
+        false
+    };
+    tokio::time::timeout(timeout, arrives).await.unwrap_or(false)
+}
+
+/// Async and sync nodes are peers of each other. A chain of an async node, a sync node and another
+/// async node, joining one after the other, passes gossip both ways over encrypted connections.
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_node_test() {
+    let base_port = 13300;
+    let localhost = IpAddr::from(Ipv4Addr::LOCALHOST);
+    let first = GossipNode::builder()
+        .bind_addr(SocketAddr::new(localhost, base_port))
+        .spawn_async()
+        .await
+        .unwrap();
+    let mut first_events = first.subscribe();
+    let middle = start_node(false, base_port + 1, Some(first.local_addr()), Duration::from_secs(30));
+    let middle_events = middle.subscribe();
+    let last = GossipNode::builder()
+        .bind_addr(SocketAddr::new(localhost, base_port + 2))
+        .bootstrap_peer(middle.local_addr())
+        .spawn_async()
+        .await
+        .unwrap();
+    let mut last_events = last.subscribe();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let stats = first.stats().await.unwrap();
+    assert!(stats.connected_peers.contains(&PeerInfo {
+        node_id: middle.node_id(),
+        addr: middle.local_addr()
+    }));
+
+    first.publish("from the first").unwrap();
+    assert!(gossip_arrives(&middle_events, b"from the first", Duration::from_secs(5)).await);
+    assert!(async_gossip_arrives(&mut last_events, b"from the first", Duration::from_secs(5)).await);
+
+    last.publish("from the last").unwrap();
+    assert!(gossip_arrives(&middle_events, b"from the last", Duration::from_secs(5)).await);
+    assert!(async_gossip_arrives(&mut first_events, b"from the last", Duration::from_secs(5)).await);
+
+    first.shutdown();
+    first.join().await.unwrap();
+    assert!(first_events.recv().await.is_none());
+}
+
+/// `run` returns once the node's lifetime is over, or right away if the node can not start.
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn run_test() {
+    let start = std::time::Instant::now();
+    let builder = GossipNode::builder()
+        .bind_addr(SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 13310))
+        .lifetime(Duration::from_millis(300));
+    run(builder).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let error = run(GossipNode::builder().active_view_size(0)).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    let builder = GossipNode::builder().transport(TransportKind::Memory(MemoryNetwork::new()));
+    let error = run(builder).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+}
+
+/// Wait until `events` delivers gossip with `payload`. Returns whether it did within `timeout`.
+fn gossip_received_within(
+    events: &mpsc::Receiver<NodeEvent>,
+    payload: &[u8],
+    timeout: Duration,
+) -> bool {
+    let deadline = std::time::Instant::now() + timeout;
+    while let Ok(event) =
+        events.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+    {
+        if matches!(event, NodeEvent::GossipReceived { payload: ref received, .. } if received == payload)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// A hundred nodes on a `MemoryNetwork` form a network without using a single port, each of them
+/// joining through a random one of those before it. The nodes join in the background, so the test
+/// waits until every node has a peer and the peers link all of them up. Gossip published on the
+/// last of them then reaches every node.
+#[test]
+fn memory_network_test() {
+    let network = MemoryNetwork::new();
+    let mut nodes: Vec<GossipNode> = Vec::new();
+    for _ in 0..100 {
+        let mut builder = GossipNode::builder().transport(TransportKind::Memory(network.clone()));
+        if let Some(bootstrap_node) = nodes.choose(&mut rand::thread_rng()) {
+            builder = builder.bootstrap_peer(bootstrap_node.local_addr());
+        }
+        nodes.push(builder.spawn().unwrap());
+    }
+    assert_eq!(nodes[0].local_addr(), SocketAddr::from(([127, 0, 0, 1], 1)));
+    let events: Vec<_> = nodes.iter().map(GossipNode::subscribe).collect();
+
+    // the nodes the last one reaches through the peers of every node on the way
+    let reached = || {
+        let connected_peers: Vec<Vec<SocketAddr>> = nodes
+            .iter()
+            .map(|node| {
+                let stats = node.stats().unwrap();
+                stats.connected_peers.iter().map(|peer| peer.addr).collect()
+            })
+            .collect();
+        let mut reached = vec![99];
+        let mut index = 0;
+        while let Some(&node) = reached.get(index) {
+            for addr in &connected_peers[node] {
+                let peer = nodes.iter().position(|node| node.local_addr() == *addr).unwrap();
+                if !reached.contains(&peer) {
+                    reached.push(peer);
+                }
+            }
+            index += 1;
+        }
+        reached.len()
+    };
+    let deadline = std::time::Instant::now() + Duration::from_secs(30);
+    while reached() < nodes.len() {
+        assert!(std::time::Instant::now() < deadline, "the nodes did not all join up in time");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    nodes[99].publish("over the memory network").unwrap();
+    for events in &events[..99] {
+        assert!(gossip_received_within(
+            events,
+            b"over the memory network",
+            Duration::from_secs(10)
+        ));
+    }
+}
+
+/// Nodes on Unix domain sockets in the same directory reach each other like over TCP.
+#[cfg(unix)]
+#[test]
+fn unix_socket_test() {
+    let dir = std::env::temp_dir().join(format!(
+        "p2p_gossip_unix_socket_test_{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let start_unix_node = |port: u16, bootstrap_peer: Option<SocketAddr>| {
+        let mut builder = GossipNode::builder()
+            .bind_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+            .transport(TransportKind::Unix(dir.clone()))
+            .lifetime(Duration::from_secs(20));
+        if let Some(bootstrap_peer) = bootstrap_peer {
+            builder = builder.bootstrap_peer(bootstrap_peer);
+        }
+        builder.spawn().unwrap()
+    };
+    let first = start_unix_node(0, None);
+    let middle = start_unix_node(13500, Some(first.local_addr()));
+    let last = start_unix_node(13501, Some(middle.local_addr()));
+    let first_events = first.subscribe();
+    let last_events = last.subscribe();
+    std::thread::sleep(Duration::from_millis(500));
+
+    first.publish("from the first").unwrap();
+    assert!(gossip_received_within(&last_events, b"from the first", Duration::from_secs(5)));
+    last.publish("from the last").unwrap();
+    assert!(gossip_received_within(&first_events, b"from the last", Duration::from_secs(5)));
+
+    // the directory is left as it was
+    for node in [first, middle, last] {
+        node.shutdown();
+        node.join().unwrap();
     }
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,483 @@
+//! What a node's connections to its peers are carried over. A `MioDriver` listens, dials and
+//! watches its connections through a `Transport`, the node itself only ever sees `Stream`s.
+//!
+//! There are three of them, picked with `TransportKind`:
+//! - `TcpTransport`, the default.
+//! - `UnixTransport`, Unix domain sockets in a directory, for nodes that run as processes on the
+//!   same host.
+//! - `MemoryNetwork`, connections between the nodes of one process that never leave it, so that
+//!   tests can run hundreds of nodes without using up ports.
+//!
+//! Peers are known by socket addresses on every transport, since that is what the protocol
+//! passes around. The Unix and memory transports only use them as names. A listener bound to port
+//! 0 is given a free port by the transport, like by the OS for TCP. The address a connection came
+//! in from is only known on TCP, the other transports report `0.0.0.0:0`. It is only logged
+//! anyway, a peer is reached on the listening address from its `Hello`.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Interest, Token};
+
+use crate::driver::Stream;
+use crate::reactor::{Notifier, Reactor};
+
+#[cfg(test)]
+mod tests;
+
+/// How much a memory connection holds in each direction before writes to it would block, like
+/// the socket buffers of a TCP connection.
+const MEMORY_PIPE_CAPACITY: usize = 256 * 1024;
+
+/// Where a listener is bound to port 0 is picked from, the range of ports that OSes hand out.
+#[cfg(unix)]
+const UNIX_EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// How many free ports a Unix listener bound to port 0 tries before it gives up.
+#[cfg(unix)]
+const UNIX_PORT_ATTEMPTS: usize = 64;
+
+/// Where connections come in.
+pub(crate) trait Listener: Send + std::fmt::Debug {
+    type Stream;
+
+    /// The address the listener is bound to, with the port it was given if it was bound to 0.
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// A connection that has come in, and the address it came from. Fails with `WouldBlock` if
+    /// there is none.
+    fn accept(&mut self) -> std::io::Result<(Self::Stream, SocketAddr)>;
+}
+
+pub(crate) trait Transport: Send + std::fmt::Debug + 'static {
+    type Stream: Stream;
+    type Listener: Listener<Stream = Self::Stream>;
+
+    /// Listen on `addr`. Returns the listener and the token `reactor` reports it ready with when
+    /// connections come in.
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        reactor: &mut Reactor,
+    ) -> std::io::Result<(Self::Listener, Token)>;
+
+    /// Start connecting to the node listening on `addr`, without waiting for the connection to
+    /// open. Once the stream is registered it is reported ready when connecting is over, see
+    /// `finish_dial`.
+    fn dial(&self, addr: &SocketAddr) -> std::io::Result<Self::Stream>;
+
+    /// Whether connecting `stream`, which came from `dial`, is over. Fails if it did not work.
+    /// Transports whose connections are open as soon as `dial` returns keep this default.
+    fn finish_dial(&self, _stream: &Self::Stream) -> std::io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Have `reactor` watch `stream`, which has just been accepted or dialed. Returns the token
+    /// `reactor` reports it ready with.
+    fn register(&self, stream: &mut Self::Stream, reactor: &mut Reactor)
+        -> std::io::Result<Token>;
+}
+
+/// The address reported for connections whose address is not known.
+fn unknown_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TcpTransport;
+
+impl Stream for TcpStream {}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+
+    fn accept(&mut self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self)
+    }
+}
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        reactor: &mut Reactor,
+    ) -> std::io::Result<(TcpListener, Token)> {
+        let mut listener = TcpListener::bind(addr)?;
+        let token = reactor.register(&mut listener, Interest::READABLE)?;
+        Ok((listener, token))
+    }
+
+    fn dial(&self, addr: &SocketAddr) -> std::io::Result<TcpStream> {
+        TcpStream::connect(*addr)
+    }
+
+    /// The socket is reported writable once the handshake is over, or failed, but it may be
+    /// reported before that too.
+    fn finish_dial(&self, stream: &TcpStream) -> std::io::Result<bool> {
+        if let Some(error) = stream.take_error()? {
+            return Err(error);
+        }
+        match stream.peer_addr() {
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == std::io::ErrorKind::NotConnected => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn register(&self, stream: &mut TcpStream, reactor: &mut Reactor) -> std::io::Result<Token> {
+        stream.set_nodelay(true)?;
+        reactor.register(stream, Interest::READABLE | Interest::WRITABLE)
+    }
+}
+
+/// Unix domain sockets in `dir`. The node listening on `addr` has its socket at `dir/addr`.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub(crate) struct UnixTransport {
+    dir: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        UnixTransport { dir }
+    }
+
+    fn path(&self, addr: &SocketAddr) -> PathBuf {
+        self.dir.join(addr.to_string())
+    }
+
+    /// Bind the socket of `addr`. A socket file nobody listens on any more is left behind by a
+    /// node that did not stop cleanly, it is replaced.
+    fn bind(&self, addr: SocketAddr) -> std::io::Result<UnixListener> {
+        let path = self.path(&addr);
+        let listener = match mio::net::UnixListener::bind(&path) {
+            Err(error) if error.kind() == std::io::ErrorKind::AddrInUse => {
+                match std::os::unix::net::UnixStream::connect(&path) {
+                    Err(stale) if stale.kind() == std::io::ErrorKind::ConnectionRefused => {
+                        std::fs::remove_file(&path)?;
+                        mio::net::UnixListener::bind(&path)?
+                    }
+                    _ => return Err(error),
+                }
+            }
+            result => result?,
+        };
+        Ok(UnixListener {
+            listener,
+            addr,
+            path,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Stream for mio::net::UnixStream {}
+
+/// Removes its socket file when it is dropped.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct UnixListener {
+    listener: mio::net::UnixListener,
+    addr: SocketAddr,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = mio::net::UnixStream;
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn accept(&mut self) -> std::io::Result<(mio::net::UnixStream, SocketAddr)> {
+        let (stream, _) = self.listener.accept()?;
+        Ok((stream, unknown_addr()))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    type Stream = mio::net::UnixStream;
+    type Listener = UnixListener;
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        reactor: &mut Reactor,
+    ) -> std::io::Result<(UnixListener, Token)> {
+        let mut listener = if addr.port() != 0 {
+            self.bind(addr)?
+        } else {
+            let mut attempts = 0;
+            loop {
+                let port = rand::Rng::gen_range(&mut rand::thread_rng(), UNIX_EPHEMERAL_PORTS);
+                match self.bind(SocketAddr::new(addr.ip(), port)) {
+                    Err(error)
+                        if error.kind() == std::io::ErrorKind::AddrInUse
+                            && attempts < UNIX_PORT_ATTEMPTS =>
+                    {
+                        attempts += 1
+                    }
+                    result => break result?,
+                }
+            }
+        };
+        let token = reactor.register(&mut listener.listener, Interest::READABLE)?;
+        Ok((listener, token))
+    }
+
+    /// Connecting to a socket on the same host does not wait for the other side, it works or
+    /// fails right away. It fails with `WouldBlock` if the listener has too many connections
+    /// waiting to be accepted.
+    fn dial(&self, addr: &SocketAddr) -> std::io::Result<mio::net::UnixStream> {
+        mio::net::UnixStream::connect(self.path(addr))
+    }
+
+    fn register(
+        &self,
+        stream: &mut mio::net::UnixStream,
+        reactor: &mut Reactor,
+    ) -> std::io::Result<Token> {
+        reactor.register(stream, Interest::READABLE | Interest::WRITABLE)
+    }
+}
+
+/// An in-process network the nodes of one process can reach each other on, without any sockets.
+/// A node started with `TransportKind::Memory` listens on the network instead of a port, and only
+/// reaches the nodes listening on the same network. Clones of a `MemoryNetwork` are the same
+/// network.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryNetwork {
+    listeners: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<Backlog>>>>>,
+}
+
+impl MemoryNetwork {
+    /// A network nobody listens on yet.
+    pub fn new() -> Self {
+        MemoryNetwork::default()
+    }
+}
+
+/// The connections that came in to a memory listener and were not accepted yet.
+#[derive(Debug)]
+struct Backlog {
+    pending: VecDeque<MemoryStream>,
+    notifier: Notifier,
+    token: Token,
+}
+
+/// Removes itself from its network when it is dropped. The connections it did not accept are
+/// closed.
+#[derive(Debug)]
+pub(crate) struct MemoryListener {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+    backlog: Arc<Mutex<Backlog>>,
+}
+
+impl Listener for MemoryListener {
+    type Stream = MemoryStream;
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn accept(&mut self) -> std::io::Result<(MemoryStream, SocketAddr)> {
+        match self.backlog.lock().unwrap().pending.pop_front() {
+            Some(stream) => Ok((stream, unknown_addr())),
+            None => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.network.listeners.lock().unwrap().remove(&self.addr);
+    }
+}
+
+impl Transport for MemoryNetwork {
+    type Stream = MemoryStream;
+    type Listener = MemoryListener;
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+        reactor: &mut Reactor,
+    ) -> std::io::Result<(MemoryListener, Token)> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let addr = if addr.port() != 0 {
+            addr
+        } else {
+            (1..=u16::MAX)
+                .map(|port| SocketAddr::new(addr.ip(), port))
+                .find(|addr| !listeners.contains_key(addr))
+                .ok_or(std::io::ErrorKind::AddrInUse)?
+        };
+        if listeners.contains_key(&addr) {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        let token = reactor.next_token();
+        let backlog = Arc::new(Mutex::new(Backlog {
+            pending: VecDeque::new(),
+            notifier: reactor.notifier(),
+            token,
+        }));
+        listeners.insert(addr, backlog.clone());
+        let listener = MemoryListener {
+            network: self.clone(),
+            addr,
+            backlog,
+        };
+        Ok((listener, token))
+    }
+
+    /// Connecting is immediate, the connection waits in the listener's backlog until it is
+    /// accepted.
+    fn dial(&self, addr: &SocketAddr) -> std::io::Result<MemoryStream> {
+        let backlog = match self.listeners.lock().unwrap().get(addr) {
+            Some(backlog) => backlog.clone(),
+            None => return Err(std::io::ErrorKind::ConnectionRefused.into()),
+        };
+        let (stream, accepted) = MemoryStream::pair();
+        let mut backlog = backlog.lock().unwrap();
+        backlog.pending.push_back(accepted);
+        backlog.notifier.notify(backlog.token);
+        Ok(stream)
+    }
+
+    fn register(&self, stream: &mut MemoryStream, reactor: &mut Reactor) -> std::io::Result<Token> {
+        let token = reactor.next_token();
+        let notifier = reactor.notifier();
+        stream.incoming.lock().unwrap().reader = Some((notifier.clone(), token));
+        stream.outgoing.lock().unwrap().writer = Some((notifier.clone(), token));
+        // like a socket, a fresh stream is writable, and may have been written to already
+        notifier.notify(token);
+        Ok(token)
+    }
+}
+
+/// One direction of a memory connection.
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    reader_closed: bool,
+    writer_closed: bool,
+    /// Who is told when there is something to read, once the reading end is registered.
+    reader: Option<(Notifier, Token)>,
+    /// Who is told when there is room to write, once the writing end is registered.
+    writer: Option<(Notifier, Token)>,
+}
+
+impl Pipe {
+    fn notify_reader(&self) {
+        if let Some((notifier, token)) = &self.reader {
+            notifier.notify(*token);
+        }
+    }
+
+    fn notify_writer(&self) {
+        if let Some((notifier, token)) = &self.writer {
+            notifier.notify(*token);
+        }
+    }
+}
+
+/// One end of a memory connection. Reads and writes never block, and readiness is reported to
+/// the reactor the end is registered with, as it would be for a socket.
+#[derive(Debug)]
+pub(crate) struct MemoryStream {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+}
+
+impl MemoryStream {
+    /// The two ends of a new connection.
+    fn pair() -> (Self, Self) {
+        let there = Arc::new(Mutex::new(Pipe::default()));
+        let back = Arc::new(Mutex::new(Pipe::default()));
+        (
+            MemoryStream {
+                incoming: back.clone(),
+                outgoing: there.clone(),
+            },
+            MemoryStream {
+                incoming: there,
+                outgoing: back,
+            },
+        )
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut pipe = self.incoming.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.writer_closed {
+                return Ok(0);
+            }
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        let was_full = pipe.buf.len() >= MEMORY_PIPE_CAPACITY;
+        let len = pipe.buf.read(buf)?;
+        if was_full {
+            pipe.notify_writer();
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut pipe = self.outgoing.lock().unwrap();
+        if pipe.reader_closed {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        let len = buf.len().min(MEMORY_PIPE_CAPACITY - pipe.buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        pipe.buf.extend(&buf[..len]);
+        pipe.notify_reader();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MemoryStream {}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        outgoing.writer_closed = true;
+        outgoing.notify_reader();
+        drop(outgoing);
+        let mut incoming = self.incoming.lock().unwrap();
+        incoming.reader_closed = true;
+        incoming.notify_writer();
+    }
+}
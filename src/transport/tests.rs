@@ -0,0 +1,128 @@
+use super::*;
+
+use std::collections::HashSet;
+
+use crate::reactor::Wakeup;
+
+/// What `reactor` reports ready right now.
+fn ready_now(reactor: &mut Reactor) -> HashSet<Token> {
+    let mut wakeup = Wakeup::default();
+    wakeup.now();
+    reactor.wait(&wakeup).unwrap()
+}
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// Listeners on a memory network are given the ports that are free, connections only reach the
+/// addresses that are listened on and a listener that is dropped frees its address.
+#[test]
+fn memory_listen_test() {
+    let network = MemoryNetwork::new();
+    let mut reactor = Reactor::new().unwrap();
+    let (mut listener, listener_token) = network.listen(addr(0), &mut reactor).unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr(1));
+    let (second, _) = network.listen(addr(0), &mut reactor).unwrap();
+    assert_eq!(second.local_addr().unwrap(), addr(2));
+    let error = network.listen(addr(2), &mut reactor).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+    drop(second);
+    let error = network.dial(&addr(2)).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+
+    assert_eq!(ready_now(&mut reactor), HashSet::new());
+    let _dialed = network.dial(&addr(1)).unwrap();
+    assert_eq!(ready_now(&mut reactor), HashSet::from([listener_token]));
+    let (_accepted, remote_addr) = listener.accept().unwrap();
+    assert_eq!(remote_addr, unknown_addr());
+    let error = listener.accept().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+}
+
+/// Both ends of a memory connection are reported ready like sockets, what is written to one end
+/// is read from the other, a full connection blocks the writer and a closed one ends the reader.
+#[test]
+fn memory_stream_test() {
+    let network = MemoryNetwork::new();
+    let mut reactor = Reactor::new().unwrap();
+    let (mut listener, listener_token) = network.listen(addr(0), &mut reactor).unwrap();
+
+    // what is written before the other end is accepted waits for it
+    let mut dialed = network.dial(&addr(1)).unwrap();
+    let dialed_token = network.register(&mut dialed, &mut reactor).unwrap();
+    dialed.write_all(b"hello").unwrap();
+    assert_eq!(
+        ready_now(&mut reactor),
+        HashSet::from([listener_token, dialed_token])
+    );
+    let (mut accepted, _) = listener.accept().unwrap();
+    let accepted_token = network.register(&mut accepted, &mut reactor).unwrap();
+    assert_eq!(ready_now(&mut reactor), HashSet::from([accepted_token]));
+    let mut buf = [0; 16];
+    assert_eq!(accepted.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    let error = accepted.read(&mut buf).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+
+    let big = vec![7; MEMORY_PIPE_CAPACITY + 100];
+    assert_eq!(accepted.write(&big).unwrap(), MEMORY_PIPE_CAPACITY);
+    let error = accepted.write(&big).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+    assert_eq!(ready_now(&mut reactor), HashSet::from([dialed_token]));
+    // reading makes room, which the writer is told about
+    assert_eq!(dialed.read(&mut buf).unwrap(), buf.len());
+    assert_eq!(ready_now(&mut reactor), HashSet::from([accepted_token]));
+    assert_eq!(accepted.write(&big).unwrap(), buf.len());
+
+    drop(accepted);
+    assert_eq!(ready_now(&mut reactor), HashSet::from([dialed_token]));
+    let mut rest = Vec::new();
+    assert_eq!(dialed.read_to_end(&mut rest).unwrap(), MEMORY_PIPE_CAPACITY);
+    let error = dialed.write(b"anyone there?").unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+}
+
+/// Unix sockets are files in the transport's directory named after the addresses, which are
+/// cleaned up behind a listener and replaced if a node left them behind.
+#[cfg(unix)]
+#[test]
+fn unix_test() {
+    let dir = std::env::temp_dir().join(format!("p2p_gossip_unix_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let transport = UnixTransport::new(dir.clone());
+    let mut reactor = Reactor::new().unwrap();
+
+    let (mut listener, listener_token) = transport.listen(addr(0), &mut reactor).unwrap();
+    let local_addr = listener.local_addr().unwrap();
+    assert!(UNIX_EPHEMERAL_PORTS.contains(&local_addr.port()));
+    assert!(dir.join(local_addr.to_string()).exists());
+
+    let mut dialed = transport.dial(&local_addr).unwrap();
+    transport.register(&mut dialed, &mut reactor).unwrap();
+    dialed.write_all(b"hello").unwrap();
+    assert!(reactor
+        .wait(&Wakeup::default())
+        .unwrap()
+        .contains(&listener_token));
+    let (mut accepted, remote_addr) = listener.accept().unwrap();
+    assert_eq!(remote_addr, unknown_addr());
+    // it was written before the connection was accepted, so it is there already
+    let mut buf = [0; 16];
+    assert_eq!(accepted.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+
+    drop(listener);
+    assert!(!dir.join(local_addr.to_string()).exists());
+    let error = transport.dial(&local_addr).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+    // a socket file nobody listens on is taken over
+    drop(std::os::unix::net::UnixListener::bind(dir.join(addr(4000).to_string())).unwrap());
+    let (listener, _) = transport.listen(addr(4000), &mut reactor).unwrap();
+    let error = transport.listen(addr(4000), &mut reactor).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+    drop(listener);
+    std::fs::remove_dir_all(&dir).unwrap();
+}